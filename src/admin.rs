@@ -0,0 +1,219 @@
+use crate::{discord, Config};
+use chrono::prelude::*;
+use sha2::Digest;
+
+/// What's been served of a guild's calendar since startup
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GuildStats {
+    pub last_fetch: Option<DateTime<Utc>>,
+    pub requests: u64,
+    pub events: usize,
+    pub last_error: Option<String>,
+}
+
+/// Calendar stats for each guild, and the guilds whose calendars aren't served, which are saved to disk
+pub struct Registry {
+    path: std::path::PathBuf,
+    stats: std::sync::Mutex<std::collections::HashMap<discord::Snowflake, GuildStats>>,
+    blocked: tokio::sync::Mutex<std::collections::BTreeSet<discord::Snowflake>>,
+}
+
+impl Registry {
+    pub fn load(path: std::path::PathBuf) -> std::io::Result<Self> {
+        let blocked = match std::fs::read(&path) {
+            Ok(d) => serde_json::from_slice(&d)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::collections::BTreeSet::new(),
+            Err(e) => return Err(e)
+        };
+        Ok(Registry {
+            path,
+            stats: Default::default(),
+            blocked: tokio::sync::Mutex::new(blocked),
+        })
+    }
+
+    async fn save(&self, blocked: &std::collections::BTreeSet<discord::Snowflake>) -> Result<(), rocket::http::Status> {
+        let data = serde_json::to_vec(blocked).map_err(|_| rocket::http::Status::InternalServerError)?;
+        let tmp_path = self.path.with_extension("tmp");
+        if let Err(e) = async {
+            tokio::fs::write(&tmp_path, data).await?;
+            tokio::fs::rename(&tmp_path, &self.path).await
+        }.await {
+            println!("Unable to save blocked guilds: {}", e);
+            return Err(rocket::http::Status::InternalServerError);
+        }
+        Ok(())
+    }
+
+    pub async fn is_blocked(&self, guild_id: &discord::Snowflake) -> bool {
+        self.blocked.lock().await.contains(guild_id)
+    }
+
+    pub fn record_request(&self, guild_id: discord::Snowflake) {
+        self.stats.lock().unwrap().entry(guild_id).or_default().requests += 1;
+    }
+
+    /// Records the outcome of fetching a guild's events from Discord, given as how many there were
+    pub fn record_fetch(&self, guild_id: discord::Snowflake, result: Result<usize, rocket::http::Status>) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(guild_id).or_default();
+        stats.last_fetch = Some(Utc::now());
+        match result {
+            Ok(events) => {
+                stats.events = events;
+                stats.last_error = None;
+            },
+            Err(status) => stats.last_error = Some(status.to_string())
+        }
+    }
+}
+
+/// A request carrying the configured admin token
+pub struct Admin;
+
+/// Compares hashes of the tokens, so how long it takes doesn't give away how much of the token was right
+fn token_matches(expected: &str, given: &str) -> bool {
+    let (expected, given) = (sha2::Sha256::digest(expected.as_bytes()), sha2::Sha256::digest(given.as_bytes()));
+    expected.iter().zip(given.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> rocket::request::Outcome<Self, ()> {
+        // The API doesn't exist at all without a token configured
+        let expected = match request.rocket().state::<Config>().and_then(|c| c.admin_token.as_deref()) {
+            Some(t) => t,
+            None => return rocket::request::Outcome::Failure((rocket::http::Status::NotFound, ()))
+        };
+        match request.headers().get_one("Authorization").and_then(|a| a.strip_prefix("Bearer ")) {
+            Some(token) if token_matches(expected, token) => rocket::request::Outcome::Success(Admin),
+            _ => rocket::request::Outcome::Failure((rocket::http::Status::Unauthorized, ()))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct GuildInfo {
+    id: discord::Snowflake,
+    name: String,
+    blocked: bool,
+    #[serde(flatten)]
+    stats: GuildStats,
+    /// Where to PUT whether the guild is blocked
+    blocked_url: String,
+    /// Where to POST to refresh the guild's calendar
+    refresh_url: String,
+}
+
+/// Lists every guild the bot is in, with how its calendar has been used
+#[get("/admin/guilds")]
+pub async fn guilds(
+    _admin: Admin, config: &rocket::State<Config>, client: &rocket::State<reqwest::Client>,
+    registry: &rocket::State<std::sync::Arc<Registry>>
+) -> Result<(rocket::http::ContentType, String), rocket::http::Status> {
+    let guilds = crate::current_user_guilds(client, None).await?;
+    let blocked = registry.blocked.lock().await.clone();
+    let stats = registry.stats.lock().unwrap().clone();
+    let guilds = guilds.into_iter().map(|g| GuildInfo {
+        id: g.id,
+        name: g.name,
+        blocked: blocked.contains(&g.id),
+        stats: stats.get(&g.id).cloned().unwrap_or_default(),
+        blocked_url: format!("{}{}", config.root_url, uri!(set_blocked(g.id))),
+        refresh_url: format!("{}{}", config.root_url, uri!(refresh(g.id))),
+    }).collect::<Vec<_>>();
+    serde_json::to_string(&guilds)
+        .map(|g| (rocket::http::ContentType::JSON, g))
+        .map_err(|_| rocket::http::Status::InternalServerError)
+}
+
+/// Stops or starts serving a guild's calendar, given a body of `true` or `false`
+#[put("/admin/guilds/<guild_id>/blocked", data = "<body>")]
+pub async fn set_blocked(
+    _admin: Admin, registry: &rocket::State<std::sync::Arc<Registry>>,
+    guild_id: Result<discord::Snowflake, std::num::ParseIntError>, body: String
+) -> Result<rocket::http::Status, rocket::http::Status> {
+    let guild_id = guild_id.map_err(|_| rocket::http::Status::BadRequest)?;
+    let block = body.trim().parse::<bool>().map_err(|_| rocket::http::Status::BadRequest)?;
+    let mut blocked = registry.blocked.lock().await;
+    if block {
+        blocked.insert(guild_id);
+    } else {
+        blocked.remove(&guild_id);
+    }
+    registry.save(&blocked).await?;
+    Ok(rocket::http::Status::NoContent)
+}
+
+/// Drops a guild's cached calendars, so the next request fetches its events from Discord again
+#[post("/admin/guilds/<guild_id>/refresh")]
+pub fn refresh(
+    _admin: Admin, cache: &rocket::State<std::sync::Arc<crate::CalendarCache>>, guild_id: Result<discord::Snowflake, std::num::ParseIntError>
+) -> rocket::http::Status {
+    match guild_id {
+        Ok(guild_id) => {
+            cache.clear(&guild_id);
+            rocket::http::Status::NoContent
+        },
+        Err(_) => rocket::http::Status::BadRequest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clears_last_error() {
+        let registry = Registry::load(std::env::temp_dir().join("unused-blocked.json")).unwrap();
+        let guild_id = discord::Snowflake(10);
+        registry.record_fetch(guild_id, Err(rocket::http::Status::BadGateway));
+        assert_eq!(registry.stats.lock().unwrap()[&guild_id].last_error.as_deref(), Some("502 Bad Gateway"));
+        registry.record_fetch(guild_id, Ok(3));
+        let stats = registry.stats.lock().unwrap()[&guild_id].clone();
+        assert_eq!((stats.events, stats.last_error), (3, None));
+    }
+
+    #[rocket::async_test]
+    async fn blocks_guilds() {
+        let data_dir = std::env::temp_dir().join(format!("admin-{}", rand::random::<u64>()));
+        std::fs::create_dir(&data_dir).unwrap();
+        let figment = rocket::Config::figment()
+            .merge(("discord_token", "token"))
+            .merge(("root_url", "https://events.example.com"))
+            .merge(("admin_token", "secret"))
+            .merge(("data_dir", &data_dir));
+        let client = rocket::local::asynchronous::Client::tracked(crate::rocket().configure(figment)).await.unwrap();
+        let guild_id = discord::Snowflake(10);
+        let auth = rocket::http::Header::new("Authorization", "Bearer secret");
+
+        let res = client.put(uri!(set_blocked(&guild_id))).body("true").dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::Unauthorized);
+        let res = client.put(uri!(set_blocked(&guild_id))).header(rocket::http::Header::new("Authorization", "Bearer nope"))
+            .body("true").dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::Unauthorized);
+
+        let res = client.put(uri!(set_blocked(&guild_id))).header(auth.clone()).body("true").dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::NoContent);
+        let res = client.get(uri!(crate::calendar(&guild_id, _, _, _, _))).dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::Forbidden);
+        let res = client.get(uri!(crate::channel_calendar(&guild_id, discord::Snowflake(20), _, _, _))).dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::Forbidden);
+        assert!(Registry::load(data_dir.join("blocked.json")).unwrap().is_blocked(&guild_id).await);
+
+        let res = client.post(uri!(refresh(&guild_id))).header(auth.clone()).dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::NoContent);
+        let res = client.put(uri!(set_blocked(&guild_id))).header(auth.clone()).body("false").dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::NoContent);
+        assert!(!Registry::load(data_dir.join("blocked.json")).unwrap().is_blocked(&guild_id).await);
+
+        let res = client.put("/admin/guilds/nope/blocked").header(auth.clone()).body("true").dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        let res = client.post("/admin/guilds/nope/refresh").header(auth).dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::BadRequest);
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
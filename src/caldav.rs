@@ -0,0 +1,718 @@
+use crate::{discord, ical};
+use chrono::prelude::*;
+use sha2::Digest;
+
+pub mod push;
+
+const DAV: &str = "DAV:";
+const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+const CALENDAR_SERVER: &str = "http://calendarserver.org/ns/";
+const ROOT: &str = "/dav/";
+const HOME: &str = "/dav/guilds/";
+
+#[derive(Debug, Deserialize)]
+pub struct CalDavConfig {
+    #[serde(default = "default_address")]
+    pub address: std::net::IpAddr,
+    pub port: u16,
+    /// Guilds listed in the calendar home, though any guild's calendar can be reached by its URL
+    #[serde(default)]
+    pub guilds: Vec<discord::Snowflake>,
+}
+
+fn default_address() -> std::net::IpAddr {
+    std::net::Ipv4Addr::LOCALHOST.into()
+}
+
+/// An XML element name, as its namespace and local name
+type Name<'a> = (&'a str, &'a str);
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn element((ns, name): Name, content: &str) -> String {
+    let prefix = match ns {
+        DAV => "d",
+        CALDAV => "c",
+        CALENDAR_SERVER => "cs",
+        _ => return match content {
+            "" => format!("<x:{} xmlns:x=\"{}\"/>", name, escape(ns)),
+            c => format!("<x:{name} xmlns:x=\"{}\">{}</x:{name}>", escape(ns), c, name = name)
+        }
+    };
+    match content {
+        "" => format!("<{}:{}/>", prefix, name),
+        c => format!("<{prefix}:{name}>{}</{prefix}:{name}>", c, prefix = prefix, name = name)
+    }
+}
+
+fn href(path: &str) -> String {
+    element((DAV, "href"), &escape(path))
+}
+
+/// One event, as a calendar object resource
+struct Resource {
+    id: discord::Snowflake,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    etag: String,
+    data: String,
+}
+
+/// A guild's calendar name and events, as kept in the calendar cache
+pub struct Events {
+    name: String,
+    resources: Vec<Resource>,
+}
+
+/// A guild's events, as a calendar collection
+pub struct Collection {
+    guild_id: discord::Snowflake,
+    token: String,
+    events: std::sync::Arc<Events>,
+}
+
+impl Collection {
+    fn path(&self) -> String {
+        format!("{}{}/", HOME, self.guild_id)
+    }
+
+    fn resource_path(&self, resource: &Resource) -> String {
+        format!("{}{}/{}.ics", HOME, self.guild_id, resource.id)
+    }
+}
+
+/// Something a request can be made to, to fetch its properties
+enum Target<'a> {
+    Principal,
+    Home,
+    Collection(&'a Collection),
+    Resource(&'a Collection, &'a Resource),
+}
+
+impl Target<'_> {
+    const PRINCIPAL_PROPERTIES: &'static [Name<'static>] = &[
+        (DAV, "resourcetype"), (DAV, "displayname"), (DAV, "current-user-principal"), (DAV, "principal-URL"),
+        (CALDAV, "calendar-home-set"),
+    ];
+    const HOME_PROPERTIES: &'static [Name<'static>] = &[
+        (DAV, "resourcetype"), (DAV, "displayname"), (DAV, "current-user-principal"),
+    ];
+    const COLLECTION_PROPERTIES: &'static [Name<'static>] = &[
+        (DAV, "resourcetype"), (DAV, "displayname"), (DAV, "current-user-principal"), (DAV, "current-user-privilege-set"),
+        (DAV, "supported-report-set"), (DAV, "sync-token"), (CALDAV, "supported-calendar-component-set"),
+        (CALENDAR_SERVER, "getctag"),
+    ];
+    const RESOURCE_PROPERTIES: &'static [Name<'static>] = &[
+        (DAV, "resourcetype"), (DAV, "getetag"), (DAV, "getcontenttype"), (DAV, "getcontentlength"),
+    ];
+
+    fn path(&self) -> String {
+        match self {
+            Target::Principal => ROOT.to_string(),
+            Target::Home => HOME.to_string(),
+            Target::Collection(c) => c.path(),
+            Target::Resource(c, r) => c.resource_path(r)
+        }
+    }
+
+    /// Properties given when every property is asked for, which leaves out calendar data as it's expensive
+    fn all_properties(&self) -> &'static [Name<'static>] {
+        match self {
+            Target::Principal => Self::PRINCIPAL_PROPERTIES,
+            Target::Home => Self::HOME_PROPERTIES,
+            Target::Collection(_) => Self::COLLECTION_PROPERTIES,
+            Target::Resource(..) => Self::RESOURCE_PROPERTIES
+        }
+    }
+
+    /// The contents of a property, if the target has it
+    fn property(&self, name: Name) -> Option<String> {
+        Some(match (self, name) {
+            (_, (DAV, "current-user-principal")) | (Target::Principal, (DAV, "principal-URL")) => href(ROOT),
+            (Target::Principal, (CALDAV, "calendar-home-set")) => href(HOME),
+            (Target::Principal, (DAV, "resourcetype")) => element((DAV, "collection"), "") + &element((DAV, "principal"), ""),
+            (Target::Principal, (DAV, "displayname")) => "Discord Events".to_string(),
+            (Target::Home, (DAV, "resourcetype")) => element((DAV, "collection"), ""),
+            (Target::Home, (DAV, "displayname")) => "Servers".to_string(),
+            (Target::Collection(_), (DAV, "resourcetype")) => element((DAV, "collection"), "") + &element((CALDAV, "calendar"), ""),
+            (Target::Collection(c), (DAV, "displayname")) => escape(&c.events.name),
+            (Target::Collection(_), (DAV, "current-user-privilege-set")) =>
+                element((DAV, "privilege"), &element((DAV, "read"), "")),
+            (Target::Collection(_), (DAV, "supported-report-set")) => [(CALDAV, "calendar-query"), (CALDAV, "calendar-multiget"), (DAV, "sync-collection")]
+                .iter()
+                .map(|r| element((DAV, "supported-report"), &element((DAV, "report"), &element(*r, ""))))
+                .collect(),
+            (Target::Collection(c), (DAV, "sync-token") | (CALENDAR_SERVER, "getctag")) => escape(&c.token),
+            (Target::Collection(_), (CALDAV, "supported-calendar-component-set")) => "<c:comp name=\"VEVENT\"/>".to_string(),
+            (Target::Resource(..), (DAV, "resourcetype")) => String::new(),
+            (Target::Resource(_, r), (DAV, "getetag")) => escape(&format!("\"{}\"", r.etag)),
+            (Target::Resource(..), (DAV, "getcontenttype")) => "text/calendar; charset=utf-8; component=VEVENT".to_string(),
+            (Target::Resource(_, r), (DAV, "getcontentlength")) => r.data.len().to_string(),
+            // Carriage returns are written as references, or XML parsers would drop them from the CRLF line endings
+            (Target::Resource(_, r), (CALDAV, "calendar-data")) => escape(&r.data).replace('\r', "&#13;"),
+            _ => return None
+        })
+    }
+}
+
+/// Which properties a PROPFIND or REPORT asks for
+enum Properties {
+    All,
+    Named(Vec<(String, String)>),
+}
+
+impl Properties {
+    fn parse(parent: Option<roxmltree::Node>) -> Self {
+        let prop = match parent.and_then(|p| p.children().find(|c| c.has_tag_name((DAV, "prop")))) {
+            Some(p) => p,
+            None => return Properties::All
+        };
+        Properties::Named(prop.children().filter(|c| c.is_element())
+            .map(|c| (c.tag_name().namespace().unwrap_or("").to_string(), c.tag_name().name().to_string()))
+            .collect())
+    }
+}
+
+/// A 207 Multi-Status response body
+struct Multistatus(String);
+
+impl Multistatus {
+    fn new() -> Self {
+        Multistatus(format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<d:multistatus xmlns:d=\"{}\" xmlns:c=\"{}\" xmlns:cs=\"{}\">",
+            DAV, CALDAV, CALENDAR_SERVER
+        ))
+    }
+
+    fn propstat(props: &[String], status: &str) -> String {
+        element((DAV, "propstat"), &(element((DAV, "prop"), &props.concat()) + &element((DAV, "status"), status)))
+    }
+
+    /// Adds a target's properties, with any it doesn't have listed as not found
+    fn response(&mut self, target: &Target, properties: &Properties) {
+        let (mut found, mut missing) = (vec![], vec![]);
+        match properties {
+            Properties::All => for name in target.all_properties() {
+                found.extend(target.property(*name).map(|p| element(*name, &p)));
+            },
+            Properties::Named(names) => for (ns, name) in names {
+                match target.property((ns, name)) {
+                    Some(p) => found.push(element((ns, name), &p)),
+                    None => missing.push(element((ns, name), ""))
+                }
+            }
+        }
+        let mut content = href(&target.path());
+        if !found.is_empty() {
+            content += &Self::propstat(&found, "HTTP/1.1 200 OK");
+        }
+        if !missing.is_empty() {
+            content += &Self::propstat(&missing, "HTTP/1.1 404 Not Found");
+        }
+        self.0 += &element((DAV, "response"), &content);
+    }
+
+    fn not_found(&mut self, path: &str) {
+        self.0 += &element((DAV, "response"), &(href(path) + &element((DAV, "status"), "HTTP/1.1 404 Not Found")));
+    }
+
+    fn finish(mut self, sync_token: Option<&str>) -> String {
+        if let Some(token) = sync_token {
+            self.0 += &element((DAV, "sync-token"), &escape(token));
+        }
+        self.0 + "</d:multistatus>"
+    }
+}
+
+/// Sync tokens handed out for each guild, as counters of how many times its events have been seen to change. They're
+/// kept in memory, so clients holding a token from before a restart are told to sync from scratch.
+pub struct SyncHistory {
+    boot: u64,
+    guilds: std::sync::Mutex<std::collections::HashMap<discord::Snowflake, History>>,
+}
+
+#[derive(Default)]
+struct History {
+    seq: u64,
+    etags: std::collections::HashMap<discord::Snowflake, String>,
+    /// When each event, including deleted ones, last changed
+    changed: std::collections::HashMap<discord::Snowflake, u64>,
+}
+
+impl Default for SyncHistory {
+    fn default() -> Self {
+        SyncHistory { boot: rand::random(), guilds: Default::default() }
+    }
+}
+
+impl SyncHistory {
+    /// Records the latest state of a guild's events, giving the sync token for it
+    fn update(&self, guild_id: discord::Snowflake, resources: &[Resource]) -> String {
+        let mut guilds = self.guilds.lock().unwrap();
+        let history = guilds.entry(guild_id).or_default();
+        let etags = resources.iter().map(|r| (r.id, r.etag.clone())).collect::<std::collections::HashMap<_, _>>();
+        let mut changed = etags.iter().filter(|(id, etag)| history.etags.get(id) != Some(etag)).map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        changed.extend(history.etags.keys().filter(|id| !etags.contains_key(id)));
+        if !changed.is_empty() || history.seq == 0 {
+            history.seq += 1;
+            for id in changed {
+                history.changed.insert(id, history.seq);
+            }
+        }
+        history.etags = etags;
+        format!("urn:x-discord-events:sync:{}:{}", self.boot, history.seq)
+    }
+
+    /// Events changed since a sync token was handed out, or `None` if the token isn't one we know
+    fn changed_since(&self, guild_id: discord::Snowflake, token: &str) -> Option<Vec<discord::Snowflake>> {
+        let seq: u64 = token.strip_prefix(&format!("urn:x-discord-events:sync:{}:", self.boot))?.parse().ok()?;
+        let guilds = self.guilds.lock().unwrap();
+        let history = guilds.get(&guild_id).filter(|h| seq <= h.seq)?;
+        Some(history.changed.iter().filter(|(_, s)| **s > seq).map(|(id, _)| *id).collect())
+    }
+}
+
+/// Fetches a guild's name and events, each serialized as its own calendar
+async fn resources(
+    client: &reqwest::Client, guild_id: discord::Snowflake, time_zone: Option<chrono_tz::Tz>,
+    settings: &crate::settings::GuildSettings
+) -> Result<Events, rocket::http::Status> {
+    // In the default language, so every client sees the same resources and sync tokens
+    let calendar = crate::guild_calendar(client, None, &guild_id, None, None, time_zone, settings, Default::default()).await?;
+    let resources = calendar.events.into_iter().filter_map(|event| {
+        let id = event.uid.split('@').next()?.parse().ok()?;
+        let (start, end) = (event.start, event.end.unwrap_or(event.start));
+        let data = ical::Calendar::new(format!("Discord Events Export {}", env!("CARGO_PKG_VERSION")))
+            .time_zone(time_zone)
+            .event(event)
+            .to_string();
+        let etag = hex::encode(&sha2::Sha256::digest(data.as_bytes())[..16]);
+        Some(Resource { id, start, end, etag, data })
+    }).collect();
+    Ok(Events { name: calendar.name.unwrap_or_else(|| "Discord Events".to_string()), resources })
+}
+
+fn parse_time(value: Option<&str>) -> Result<Option<DateTime<Utc>>, u16> {
+    value.map(|v| NaiveDateTime::parse_from_str(v, "%Y%m%dT%H%M%SZ").map(|t| Utc.from_utc_datetime(&t)).map_err(|_| 400))
+        .transpose()
+}
+
+/// Whether an event overlaps a time range, following RFC 4791 section 9.9
+fn overlaps(resource: &Resource, start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> bool {
+    let starts_before_end = end.is_none_or(|end| resource.start < end);
+    let ends_after_start = start.is_none_or(|start| resource.end > start || (resource.end == resource.start && resource.start >= start));
+    starts_before_end && ends_after_start
+}
+
+/// Answers a PROPFIND, given the target and, if the depth asks for them, its children
+fn propfind(targets: &[Target], body: &str) -> Result<String, u16> {
+    let doc;
+    let properties = if body.trim().is_empty() {
+        Properties::All
+    } else {
+        doc = roxmltree::Document::parse(body).map_err(|_| 400u16)?;
+        Properties::parse(Some(doc.root_element()))
+    };
+    let mut out = Multistatus::new();
+    for target in targets {
+        out.response(target, &properties);
+    }
+    Ok(out.finish(None))
+}
+
+/// Why a request couldn't be answered
+#[derive(Debug, PartialEq)]
+enum Failure {
+    Status(u16),
+    /// A precondition from RFC 3253 or RFC 6578 that the request broke, sent as a 403 with a DAV:error body
+    Precondition(&'static str),
+}
+
+impl From<u16> for Failure {
+    fn from(status: u16) -> Self {
+        Failure::Status(status)
+    }
+}
+
+/// Answers a calendar-query, calendar-multiget or sync-collection REPORT on a collection
+fn report(collection: &Collection, sync: &SyncHistory, body: &str) -> Result<String, Failure> {
+    let doc = roxmltree::Document::parse(body).map_err(|_| 400u16)?;
+    let root = doc.root_element();
+    let properties = Properties::parse(Some(root));
+    let mut out = Multistatus::new();
+
+    match (root.tag_name().namespace(), root.tag_name().name()) {
+        (Some(CALDAV), "calendar-query") => {
+            let range = root.descendants().find(|n| n.has_tag_name((CALDAV, "time-range")));
+            let start = parse_time(range.and_then(|r| r.attribute("start")))?;
+            let end = parse_time(range.and_then(|r| r.attribute("end")))?;
+            for resource in collection.events.resources.iter().filter(|r| overlaps(r, start, end)) {
+                out.response(&Target::Resource(collection, resource), &properties);
+            }
+            Ok(out.finish(None))
+        },
+        (Some(CALDAV), "calendar-multiget") => {
+            for href in root.children().filter(|c| c.has_tag_name((DAV, "href"))) {
+                let path = href.text().unwrap_or("").trim();
+                // Clients may send full URLs rather than paths
+                let path = match path.split_once("://") {
+                    Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+                    None => path
+                };
+                match collection.events.resources.iter().find(|r| collection.resource_path(r) == path) {
+                    Some(r) => out.response(&Target::Resource(collection, r), &properties),
+                    None => out.not_found(path)
+                }
+            }
+            Ok(out.finish(None))
+        },
+        (Some(DAV), "sync-collection") => {
+            let token = root.children().find(|c| c.has_tag_name((DAV, "sync-token"))).and_then(|t| t.text()).unwrap_or("").trim();
+            if token.is_empty() {
+                for resource in &collection.events.resources {
+                    out.response(&Target::Resource(collection, resource), &properties);
+                }
+            } else {
+                let mut changed = sync.changed_since(collection.guild_id, token)
+                    .ok_or(Failure::Precondition("valid-sync-token"))?;
+                changed.sort();
+                for id in changed {
+                    match collection.events.resources.iter().find(|r| r.id == id) {
+                        Some(r) => out.response(&Target::Resource(collection, r), &properties),
+                        None => out.not_found(&format!("{}{}.ics", collection.path(), id))
+                    }
+                }
+            }
+            Ok(out.finish(Some(&collection.token)))
+        },
+        _ => Err(Failure::Precondition("supported-report"))
+    }
+}
+
+pub struct Server {
+    pub client: reqwest::Client,
+    pub guilds: Vec<discord::Snowflake>,
+    pub time_zones: std::collections::HashMap<discord::Snowflake, chrono_tz::Tz>,
+    pub settings: std::sync::Arc<crate::settings::Settings>,
+    pub registry: std::sync::Arc<crate::admin::Registry>,
+    pub cache: std::sync::Arc<crate::CalendarCache>,
+    pub cache_ttl: std::time::Duration,
+    pub sync: SyncHistory,
+}
+
+fn response(status: u16, content_type: Option<&str>, body: String) -> hyper::Response<hyper::Body> {
+    let mut res = hyper::Response::builder()
+        .status(status)
+        .header("DAV", "1, 3, calendar-access")
+        .header("Allow", "OPTIONS, GET, HEAD, PROPFIND, REPORT");
+    if let Some(content_type) = content_type {
+        res = res.header("Content-Type", content_type);
+    }
+    res.body(body.into()).expect("responses are built from valid headers")
+}
+
+fn multistatus<E: Into<Failure>>(result: Result<String, E>) -> hyper::Response<hyper::Body> {
+    match result.map_err(Into::into) {
+        Ok(body) => response(207, Some("application/xml; charset=utf-8"), body),
+        Err(Failure::Precondition(name)) => response(403, Some("application/xml; charset=utf-8"), format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<d:error xmlns:d=\"{}\"><d:{}/></d:error>", DAV, name
+        )),
+        Err(Failure::Status(status)) => response(status, None, String::new())
+    }
+}
+
+impl Server {
+    async fn collection(&self, guild_id: discord::Snowflake) -> Result<Collection, u16> {
+        if self.registry.is_blocked(&guild_id).await {
+            return Err(403);
+        }
+        self.registry.record_request(guild_id);
+        // Clients poll often, so events are only fetched from Discord again once the cache TTL has passed
+        let events = match self.cache.events(&guild_id, self.cache_ttl) {
+            Some(e) => e,
+            None => {
+                let settings = self.settings.guild(&guild_id);
+                let time_zone = settings.tz.or_else(|| self.time_zones.get(&guild_id).copied());
+                let result = resources(&self.client, guild_id, time_zone, &settings).await;
+                self.registry.record_fetch(guild_id, result.as_ref().map(|e| e.resources.len()).map_err(|s| *s));
+                let events = std::sync::Arc::new(result.map_err(|s| s.code)?);
+                self.cache.insert_events(guild_id, events.clone());
+                events
+            }
+        };
+        Ok(Collection { guild_id, token: self.sync.update(guild_id, &events.resources), events })
+    }
+
+    async fn handle(&self, method: &hyper::Method, path: &str, depth: &str, body: &str) -> hyper::Response<hyper::Body> {
+        if path.trim_end_matches('/') == "/.well-known/caldav" {
+            let mut res = response(301, None, String::new());
+            res.headers_mut().insert("Location", hyper::header::HeaderValue::from_static(ROOT));
+            return res;
+        }
+        if method == hyper::Method::OPTIONS {
+            return response(200, None, String::new());
+        }
+        let children = depth != "0";
+        let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+        let guild_id = segments.get(2).map(|g| g.parse::<discord::Snowflake>());
+
+        match (method.as_str(), segments.as_slice(), guild_id) {
+            ("PROPFIND", ["dav"], _) => multistatus(propfind(&[Target::Principal], body)),
+            ("PROPFIND", ["dav", "guilds"], _) => {
+                let mut collections = vec![];
+                if children {
+                    for guild_id in &self.guilds {
+                        match self.collection(*guild_id).await {
+                            Ok(c) => collections.push(c),
+                            Err(403) => {},
+                            Err(e) => println!("Unable to list guild {} over CalDAV: {}", guild_id, e)
+                        }
+                    }
+                }
+                let targets = std::iter::once(Target::Home).chain(collections.iter().map(Target::Collection)).collect::<Vec<_>>();
+                multistatus(propfind(&targets, body))
+            },
+            (_, ["dav", "guilds", _, ..], Some(Err(_))) => response(404, None, String::new()),
+            (m, ["dav", "guilds", _], Some(Ok(guild_id))) if m == "PROPFIND" || m == "REPORT" => {
+                let collection = match self.collection(guild_id).await {
+                    Ok(c) => c,
+                    Err(status) => return response(status, None, String::new())
+                };
+                if m == "REPORT" {
+                    return multistatus(report(&collection, &self.sync, body));
+                }
+                let mut targets = vec![Target::Collection(&collection)];
+                if children {
+                    targets.extend(collection.events.resources.iter().map(|r| Target::Resource(&collection, r)));
+                }
+                multistatus(propfind(&targets, body))
+            },
+            (m, ["dav", "guilds", _, file], Some(Ok(guild_id))) if m == "PROPFIND" || m == "GET" || m == "HEAD" => {
+                let collection = match self.collection(guild_id).await {
+                    Ok(c) => c,
+                    Err(status) => return response(status, None, String::new())
+                };
+                let resource = match collection.events.resources.iter().find(|r| file.strip_suffix(".ics") == Some(&r.id.to_string())) {
+                    Some(r) => r,
+                    None => return response(404, None, String::new())
+                };
+                if m == "PROPFIND" {
+                    return multistatus(propfind(&[Target::Resource(&collection, resource)], body));
+                }
+                let mut res = response(200, Some("text/calendar; charset=utf-8"), match m {
+                    "GET" => resource.data.clone(),
+                    _ => String::new()
+                });
+                if let Ok(etag) = hyper::header::HeaderValue::from_str(&format!("\"{}\"", resource.etag)) {
+                    res.headers_mut().insert("ETag", etag);
+                }
+                res
+            },
+            (_, ["dav", ..], _) => response(405, None, String::new()),
+            _ => response(404, None, String::new())
+        }
+    }
+}
+
+/// Serves calendars over CalDAV, on its own port as Rocket can't route WebDAV's methods
+pub async fn serve(server: std::sync::Arc<Server>, address: std::net::SocketAddr) {
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let server = server.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+                let server = server.clone();
+                async move {
+                    let depth = req.headers().get("Depth").and_then(|d| d.to_str().ok()).unwrap_or("1").to_string();
+                    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+                    let body = match hyper::body::to_bytes(req.into_body()).await {
+                        Ok(b) => String::from_utf8_lossy(&b).into_owned(),
+                        Err(_) => return Ok::<_, std::convert::Infallible>(response(400, None, String::new()))
+                    };
+                    Ok(server.handle(&method, &path, &depth, &body).await)
+                }
+            }))
+        }
+    });
+    match hyper::Server::try_bind(&address) {
+        Ok(builder) => if let Err(e) = builder.serve(make_service).await {
+            println!("CalDAV server failed: {}", e);
+        },
+        Err(e) => println!("Unable to start CalDAV server on {}: {}", address, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resource(id: u64, start: &str, end: &str, summary: &str) -> Resource {
+        let data = ical::Calendar::new("test")
+            .event(ical::Event::new(format!("{}@e", id), Utc::now(), start.parse().unwrap())
+                .end(end.parse::<DateTime<Utc>>().unwrap())
+                .summary(summary.to_string()))
+            .to_string();
+        Resource {
+            id: discord::Snowflake(id), start: start.parse().unwrap(), end: end.parse().unwrap(),
+            etag: hex::encode(&sha2::Sha256::digest(data.as_bytes())[..16]), data
+        }
+    }
+
+    fn collection(sync: &SyncHistory, resources: Vec<Resource>) -> Collection {
+        Collection {
+            guild_id: discord::Snowflake(10), token: sync.update(discord::Snowflake(10), &resources),
+            events: std::sync::Arc::new(Events { name: "Test & Co Events".to_string(), resources })
+        }
+    }
+
+    fn responses(body: &str) -> Vec<(String, Vec<String>)> {
+        let doc = roxmltree::Document::parse(body).unwrap();
+        doc.root_element().children().filter(|c| c.has_tag_name((DAV, "response"))).map(|r| (
+            r.children().find(|c| c.has_tag_name((DAV, "href"))).unwrap().text().unwrap().to_string(),
+            r.descendants().filter(|c| c.has_tag_name((DAV, "status"))).map(|s| s.text().unwrap().to_string()).collect()
+        )).collect()
+    }
+
+    #[test]
+    fn propfind_collection() {
+        let sync = SyncHistory::default();
+        let collection = collection(&sync, vec![resource(1, "2030-01-01T10:00:00Z", "2030-01-01T11:00:00Z", "One")]);
+        let body = propfind(
+            &[Target::Collection(&collection), Target::Resource(&collection, &collection.events.resources[0])],
+            r#"<d:propfind xmlns:d="DAV:" xmlns:x="urn:example"><d:prop><d:displayname/><d:getetag/><x:color/></d:prop></d:propfind>"#
+        ).unwrap();
+        let doc = roxmltree::Document::parse(&body).unwrap();
+        assert_eq!(responses(&body), vec![
+            ("/dav/guilds/10/".to_string(), vec!["HTTP/1.1 200 OK".to_string(), "HTTP/1.1 404 Not Found".to_string()]),
+            ("/dav/guilds/10/1.ics".to_string(), vec!["HTTP/1.1 200 OK".to_string(), "HTTP/1.1 404 Not Found".to_string()]),
+        ]);
+        assert_eq!(doc.descendants().find(|n| n.has_tag_name((DAV, "displayname"))).unwrap().text(), Some("Test & Co Events"));
+        assert!(doc.descendants().any(|n| n.has_tag_name(("urn:example", "color"))));
+
+        let body = propfind(&[Target::Principal], "").unwrap();
+        assert!(body.contains("<c:calendar-home-set><d:href>/dav/guilds/</d:href></c:calendar-home-set>"));
+    }
+
+    #[test]
+    fn queries() {
+        let sync = SyncHistory::default();
+        let collection = collection(&sync, vec![
+            resource(1, "2030-01-01T10:00:00Z", "2030-01-01T11:00:00Z", "One"),
+            resource(2, "2030-02-01T10:00:00Z", "2030-02-01T11:00:00Z", "Two"),
+        ]);
+
+        let body = report(&collection, &sync, r#"<c:calendar-query xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+            <d:prop><d:getetag/><c:calendar-data/></d:prop>
+            <c:filter><c:comp-filter name="VCALENDAR"><c:comp-filter name="VEVENT">
+                <c:time-range start="20300115T000000Z" end="20300301T000000Z"/>
+            </c:comp-filter></c:comp-filter></c:filter>
+        </c:calendar-query>"#).unwrap();
+        assert_eq!(responses(&body), vec![("/dav/guilds/10/2.ics".to_string(), vec!["HTTP/1.1 200 OK".to_string()])]);
+        let doc = roxmltree::Document::parse(&body).unwrap();
+        let data = doc.descendants().find(|n| n.has_tag_name((CALDAV, "calendar-data"))).unwrap().text().unwrap();
+        assert_eq!(data, collection.events.resources[1].data);
+
+        let body = report(&collection, &sync, r#"<c:calendar-multiget xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+            <d:prop><d:getetag/></d:prop>
+            <d:href>https://example.com/dav/guilds/10/1.ics</d:href>
+            <d:href>/dav/guilds/10/3.ics</d:href>
+        </c:calendar-multiget>"#).unwrap();
+        assert_eq!(responses(&body), vec![
+            ("/dav/guilds/10/1.ics".to_string(), vec!["HTTP/1.1 200 OK".to_string()]),
+            ("/dav/guilds/10/3.ics".to_string(), vec!["HTTP/1.1 404 Not Found".to_string()]),
+        ]);
+    }
+
+    #[test]
+    fn sync_tokens() {
+        let sync = SyncHistory::default();
+        let query = |token: &str| format!(
+            r#"<d:sync-collection xmlns:d="DAV:"><d:sync-token>{}</d:sync-token><d:sync-level>1</d:sync-level><d:prop><d:getetag/></d:prop></d:sync-collection>"#,
+            token
+        );
+        let first = collection(&sync, vec![
+            resource(1, "2030-01-01T10:00:00Z", "2030-01-01T11:00:00Z", "One"),
+            resource(2, "2030-02-01T10:00:00Z", "2030-02-01T11:00:00Z", "Two"),
+        ]);
+        let body = report(&first, &sync, &query("")).unwrap();
+        assert_eq!(responses(&body).len(), 2);
+        assert_eq!(report(&first, &sync, &query(&first.token)).map(|b| responses(&b).len()), Ok(0));
+
+        let second = collection(&sync, vec![
+            resource(1, "2030-01-01T10:00:00Z", "2030-01-01T11:00:00Z", "One"),
+            resource(3, "2030-03-01T10:00:00Z", "2030-03-01T11:00:00Z", "Three"),
+        ]);
+        assert_ne!(first.token, second.token);
+        let body = report(&second, &sync, &query(&first.token)).unwrap();
+        assert_eq!(responses(&body), vec![
+            ("/dav/guilds/10/2.ics".to_string(), vec!["HTTP/1.1 404 Not Found".to_string()]),
+            ("/dav/guilds/10/3.ics".to_string(), vec!["HTTP/1.1 200 OK".to_string()]),
+        ]);
+        assert!(body.contains(&format!("<d:sync-token>{}</d:sync-token>", second.token)));
+        assert_eq!(report(&second, &sync, &query("urn:x-discord-events:sync:0:1")), Err(Failure::Precondition("valid-sync-token")));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_reports() {
+        let sync = SyncHistory::default();
+        let collection = collection(&sync, vec![resource(1, "2030-01-01T10:00:00Z", "2030-01-01T11:00:00Z", "One")]);
+        let result = report(&collection, &sync,
+            r#"<c:free-busy-query xmlns:c="urn:ietf:params:xml:ns:caldav"><c:time-range start="20300101T000000Z"/></c:free-busy-query>"#
+        );
+        assert_eq!(result, Err(Failure::Precondition("supported-report")));
+
+        // Only a bad sync token should tell the client to throw away what it has
+        let res = multistatus(result);
+        assert_eq!(res.status(), 403);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert!(std::str::from_utf8(&body).unwrap().contains("<d:supported-report/>"));
+        assert_eq!(multistatus(report(&collection, &sync, "not xml")).status(), 400);
+    }
+
+    /// A server listing guild 10, with the guilds blocked in the given file
+    fn server(blocked: &std::path::Path) -> Server {
+        Server {
+            client: reqwest::Client::new(),
+            guilds: vec![discord::Snowflake(10)],
+            time_zones: Default::default(),
+            settings: std::sync::Arc::new(crate::settings::Settings::load(None).unwrap()),
+            registry: std::sync::Arc::new(crate::admin::Registry::load(blocked.to_path_buf()).unwrap()),
+            cache: Default::default(),
+            cache_ttl: std::time::Duration::from_secs(60),
+            sync: SyncHistory::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_cached_events() {
+        let server = server(&std::env::temp_dir().join(format!("blocked-{}.json", rand::random::<u64>())));
+        server.cache.insert_events(discord::Snowflake(10), std::sync::Arc::new(Events {
+            name: "Test & Co Events".to_string(),
+            resources: vec![resource(1, "2030-01-01T10:00:00Z", "2030-01-01T11:00:00Z", "One")],
+        }));
+
+        // Answered without going to Discord
+        let res = server.handle(&hyper::Method::GET, "/dav/guilds/10/1.ics", "0", "").await;
+        assert_eq!(res.status(), 200);
+        let res = server.handle(&hyper::Method::from_bytes(b"PROPFIND").unwrap(), "/dav/guilds/10/", "1", "").await;
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(responses(std::str::from_utf8(&body).unwrap()).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn refuses_blocked_guilds() {
+        let path = std::env::temp_dir().join(format!("blocked-{}.json", rand::random::<u64>()));
+        std::fs::write(&path, "[\"10\"]").unwrap();
+        let server = server(&path);
+        let propfind = r#"<d:propfind xmlns:d="DAV:"><d:prop><d:displayname/></d:prop></d:propfind>"#;
+
+        let res = server.handle(&hyper::Method::GET, "/dav/guilds/10/1.ics", "0", "").await;
+        assert_eq!(res.status(), 403);
+        let res = server.handle(&hyper::Method::from_bytes(b"PROPFIND").unwrap(), "/dav/guilds/", "1", propfind).await;
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(responses(std::str::from_utf8(&body).unwrap()).iter().map(|(h, _)| h.as_str()).collect::<Vec<_>>(), vec![HOME]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,352 @@
+use super::Resource;
+use crate::discord;
+
+/// A collection on another CalDAV server to write a guild's events into
+#[derive(Clone, Debug, Deserialize)]
+pub struct PushConfig {
+    pub guild: discord::Snowflake,
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl PushConfig {
+    fn request(&self, client: &reqwest::Client, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        let req = client.request(method, url);
+        match &self.username {
+            Some(username) => req.basic_auth(username, self.password.as_ref()),
+            None => req
+        }
+    }
+
+    fn href(&self, id: discord::Snowflake) -> String {
+        format!("{}/{}.ics", self.url.trim_end_matches('/'), id)
+    }
+}
+
+/// Where an event was written, and the ETag and content hash it had then
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Pushed {
+    href: String,
+    etag: Option<String>,
+    hash: String,
+}
+
+type Collection = std::collections::HashMap<discord::Snowflake, Pushed>;
+
+/// Mappings of each guild pushed to a collection URL, kept apart so guilds sharing a collection don't delete each
+/// other's events
+type Collections = std::collections::HashMap<String, std::collections::HashMap<discord::Snowflake, Collection>>;
+
+/// The resource each event was written to, per collection URL and guild, saved to disk so later runs update the same
+/// resources
+pub struct Mappings {
+    path: std::path::PathBuf,
+    collections: tokio::sync::Mutex<Collections>,
+}
+
+impl Mappings {
+    pub fn load(path: std::path::PathBuf) -> std::io::Result<Self> {
+        let collections = match std::fs::read(&path) {
+            Ok(d) => serde_json::from_slice(&d)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::collections::HashMap::new(),
+            Err(e) => return Err(e)
+        };
+        Ok(Mappings {
+            path,
+            collections: tokio::sync::Mutex::new(collections),
+        })
+    }
+
+    async fn save(&self, collections: &Collections) {
+        let data = match serde_json::to_vec(collections) {
+            Ok(d) => d,
+            Err(e) => {
+                println!("Unable to serialize CalDAV mappings: {}", e);
+                return
+            }
+        };
+        let tmp_path = self.path.with_extension("tmp");
+        if let Err(e) = async {
+            tokio::fs::write(&tmp_path, data).await?;
+            tokio::fs::rename(&tmp_path, &self.path).await
+        }.await {
+            println!("Unable to save CalDAV mappings: {}", e);
+        }
+    }
+
+    /// Writes a guild's events to a target collection, saving where they went
+    async fn push(&self, client: &reqwest::Client, target: &PushConfig, resources: &[Resource]) {
+        let mut collections = self.collections.lock().await;
+        let pushed = collections.entry(target.url.clone()).or_default().entry(target.guild).or_default();
+        push(client, target, pushed, resources).await;
+        self.save(&collections).await;
+    }
+}
+
+fn etag(res: &reqwest::Response) -> Option<String> {
+    res.headers().get(reqwest::header::ETAG).and_then(|e| e.to_str().ok()).map(ToString::to_string)
+}
+
+/// Fetches a resource's ETag, for servers that don't give one back when it's written
+async fn fetch_etag(client: &reqwest::Client, target: &PushConfig, href: &str) -> Result<Option<String>, reqwest::StatusCode> {
+    match target.request(client, reqwest::Method::HEAD, href).send().await {
+        Ok(r) if r.status().is_success() => Ok(etag(&r)),
+        Ok(r) => Err(r.status()),
+        Err(_) => Ok(None)
+    }
+}
+
+/// The UID of the event in an iCalendar object
+fn event_uid(data: &str) -> Option<&str> {
+    data.lines().skip_while(|l| l.trim_end() != "BEGIN:VEVENT").find_map(|l| l.trim_end().strip_prefix("UID:"))
+}
+
+/// Whether the resource at `href` is the event this would write there, say from a run that couldn't save where it
+/// wrote it, giving its ETag and whether it's already up to date if so
+async fn adoptable(client: &reqwest::Client, target: &PushConfig, href: &str, resource: &Resource)
+    -> Option<(Option<String>, bool)> {
+    let res = target.request(client, reqwest::Method::GET, href).send().await.ok()?.error_for_status().ok()?;
+    let etag = etag(&res);
+    let data = res.text().await.ok()?;
+    let uid = event_uid(&resource.data)?;
+    (event_uid(&data) == Some(uid)).then_some((etag, data == resource.data))
+}
+
+/// Writes new and changed events to a collection and deletes ones no longer in the guild. Resources changed on the
+/// server since they were last written are left alone.
+async fn push(client: &reqwest::Client, target: &PushConfig, pushed: &mut Collection, resources: &[Resource]) {
+    for resource in resources {
+        let previous = pushed.get(&resource.id);
+        if previous.is_some_and(|p| p.hash == resource.etag) {
+            continue;
+        }
+        let href = previous.map_or_else(|| target.href(resource.id), |p| p.href.clone());
+        let req = target.request(client, reqwest::Method::PUT, &href)
+            .header(reqwest::header::CONTENT_TYPE, "text/calendar; charset=utf-8")
+            .body(resource.data.clone());
+        let req = match previous {
+            Some(Pushed { etag: Some(etag), .. }) => req.header(reqwest::header::IF_MATCH, etag),
+            Some(_) => req,
+            None => req.header(reqwest::header::IF_NONE_MATCH, "*")
+        };
+        match req.send().await {
+            Ok(r) if r.status().is_success() => {
+                let etag = match etag(&r) {
+                    Some(e) => Some(e),
+                    None => fetch_etag(client, target, &href).await.unwrap_or(None)
+                };
+                pushed.insert(resource.id, Pushed { href, etag, hash: resource.etag.clone() });
+            },
+            Ok(r) if r.status() == reqwest::StatusCode::PRECONDITION_FAILED => match previous {
+                // An event of ours with an outdated body is given an empty hash, so it's updated next time
+                None => match adoptable(client, target, &href, resource).await {
+                    Some((etag, current)) => {
+                        let hash = if current { resource.etag.clone() } else { String::new() };
+                        pushed.insert(resource.id, Pushed { href, etag, hash });
+                    },
+                    None => println!("Not writing {}, as something else is already there", href)
+                },
+                // A resource deleted on the server is forgotten, so it's written again next time
+                Some(_) if fetch_etag(client, target, &href).await == Err(reqwest::StatusCode::NOT_FOUND) => {
+                    pushed.remove(&resource.id);
+                },
+                Some(_) => println!("Not updating {}, as it's been changed on the server", href)
+            },
+            Ok(r) => println!("Unable to write {}: {}", href, r.status()),
+            Err(e) => println!("Unable to write {}: {}", href, e)
+        }
+    }
+
+    let current = resources.iter().map(|r| r.id).collect::<std::collections::HashSet<_>>();
+    let stale = pushed.keys().filter(|id| !current.contains(id)).copied().collect::<Vec<_>>();
+    for id in stale {
+        let previous = &pushed[&id];
+        let mut req = target.request(client, reqwest::Method::DELETE, &previous.href);
+        if let Some(etag) = &previous.etag {
+            req = req.header(reqwest::header::IF_MATCH, etag);
+        }
+        match req.send().await {
+            Ok(r) if r.status().is_success() || r.status() == reqwest::StatusCode::NOT_FOUND => {
+                pushed.remove(&id);
+            },
+            Ok(r) if r.status() == reqwest::StatusCode::PRECONDITION_FAILED => {
+                println!("Not deleting {}, as it's been changed on the server", previous.href);
+                pushed.remove(&id);
+            },
+            Ok(r) => println!("Unable to delete {}: {}", previous.href, r.status()),
+            Err(e) => println!("Unable to delete {}: {}", previous.href, e)
+        }
+    }
+}
+
+/// Writes each configured guild's events to its collection, every `interval`, forever
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    bot_client: reqwest::Client, client: reqwest::Client, mappings: std::sync::Arc<Mappings>, targets: Vec<PushConfig>,
+    time_zones: std::collections::HashMap<discord::Snowflake, chrono_tz::Tz>,
+    settings: std::sync::Arc<crate::settings::Settings>, registry: std::sync::Arc<crate::admin::Registry>,
+    interval: std::time::Duration
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        for target in &targets {
+            // Leaves what was already pushed alone, rather than emptying the target collection
+            if registry.is_blocked(&target.guild).await {
+                continue;
+            }
+            let guild_settings = settings.guild(&target.guild);
+            let time_zone = guild_settings.tz.or_else(|| time_zones.get(&target.guild).copied());
+            let resources = match super::resources(&bot_client, target.guild, time_zone, &guild_settings).await {
+                Ok(e) => e.resources,
+                Err(e) => {
+                    println!("Unable to fetch events of guild {} to push to {}: {}", target.guild, target.url, e);
+                    continue;
+                }
+            };
+            mappings.push(&client, target, &resources).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+
+    type Store = std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, (String, String)>>>;
+
+    /// A CalDAV server that stores resources in memory, checking If-Match and If-None-Match like a real one would
+    async fn stand_in() -> (String, Store) {
+        let store: Store = Default::default();
+        let state = store.clone();
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let (store, counter) = (state.clone(), counter.clone());
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+                    let (store, counter) = (store.clone(), counter.clone());
+                    async move {
+                        let header = |name| req.headers().get(name).and_then(|v: &hyper::header::HeaderValue| v.to_str().ok())
+                            .map(ToString::to_string);
+                        let (if_match, if_none_match) = (header("If-Match"), header("If-None-Match"));
+                        let (method, path) = (req.method().clone(), req.uri().path().to_string());
+                        let body = String::from_utf8(hyper::body::to_bytes(req.into_body()).await.unwrap().to_vec()).unwrap();
+
+                        let mut store = store.lock().unwrap();
+                        let existing = store.get(&path).map(|(etag, _)| etag.clone());
+                        let precondition_failed = if_match.is_some_and(|m| Some(m) != existing)
+                            || (if_none_match.is_some() && existing.is_some());
+                        let res = hyper::Response::builder();
+                        Ok::<_, std::convert::Infallible>(match method {
+                            _ if precondition_failed => res.status(412).body(hyper::Body::empty()),
+                            hyper::Method::PUT => {
+                                let etag = format!("\"{}\"", counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst));
+                                store.insert(path, (etag.clone(), body));
+                                res.status(if existing.is_some() { 204 } else { 201 }).header("ETag", etag).body(hyper::Body::empty())
+                            },
+                            hyper::Method::DELETE if store.remove(&path).is_some() => res.status(204).body(hyper::Body::empty()),
+                            hyper::Method::HEAD if existing.is_some() => res.status(200).header("ETag", existing.unwrap()).body(hyper::Body::empty()),
+                            hyper::Method::GET if existing.is_some() => {
+                                let (etag, data) = store[&path].clone();
+                                res.status(200).header("ETag", etag).body(hyper::Body::from(data))
+                            },
+                            _ => res.status(404).body(hyper::Body::empty())
+                        }.unwrap())
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/calendars/discord/", server.local_addr());
+        tokio::spawn(server);
+        (url, store)
+    }
+
+    fn resource(id: u64, summary: &str) -> Resource {
+        Resource {
+            id: discord::Snowflake(id), start: Utc::now(), end: Utc::now(), etag: summary.to_string(),
+            data: format!(
+                "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:{}@e.discord-events.magicalcodewit.ch\r\nSUMMARY:{}\r\nEND:VEVENT\r\n\
+                END:VCALENDAR\r\n", id, summary
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn pushes_to_stand_in() {
+        let (url, store) = stand_in().await;
+        let target = PushConfig { guild: discord::Snowflake(10), url, username: None, password: None };
+        let client = crate::plain_client().unwrap();
+        let mut pushed = Collection::new();
+        let data = |path: &str| store.lock().unwrap().get(path).map(|(_, d)| d.clone());
+
+        push(&client, &target, &mut pushed, &[resource(1, "One"), resource(2, "Two")]).await;
+        assert_eq!(store.lock().unwrap().len(), 2);
+        assert!(data("/calendars/discord/1.ics").unwrap().contains("SUMMARY:One"));
+        assert_eq!(pushed[&discord::Snowflake(1)].etag, store.lock().unwrap().get("/calendars/discord/1.ics").map(|(e, _)| e.clone()));
+
+        push(&client, &target, &mut pushed, &[resource(1, "One again"), resource(3, "Three")]).await;
+        assert!(data("/calendars/discord/1.ics").unwrap().contains("SUMMARY:One again"));
+        assert!(data("/calendars/discord/2.ics").is_none());
+        assert!(data("/calendars/discord/3.ics").is_some());
+        assert_eq!(pushed.len(), 2);
+
+        // Someone edits the event on the server, so it isn't overwritten
+        store.lock().unwrap().insert("/calendars/discord/1.ics".to_string(), ("\"edited\"".to_string(), "edited".to_string()));
+        push(&client, &target, &mut pushed, &[resource(1, "One for the third time"), resource(3, "Three")]).await;
+        assert_eq!(data("/calendars/discord/1.ics").as_deref(), Some("edited"));
+        assert_eq!(pushed[&discord::Snowflake(1)].hash, "One again");
+
+        // Or deletes it, so it's written again on the next run
+        store.lock().unwrap().remove("/calendars/discord/1.ics");
+        push(&client, &target, &mut pushed, &[resource(1, "One for the third time"), resource(3, "Three")]).await;
+        assert!(!pushed.contains_key(&discord::Snowflake(1)));
+        push(&client, &target, &mut pushed, &[resource(1, "One for the third time"), resource(3, "Three")]).await;
+        assert!(data("/calendars/discord/1.ics").unwrap().contains("SUMMARY:One for the third time"));
+
+        // Events written by a run whose mappings weren't saved are taken back over, while anything else is left alone
+        let mut forgotten = Collection::new();
+        let resources = [resource(1, "One for the third time"), resource(3, "Three, changed"), resource(4, "Four")];
+        store.lock().unwrap().insert("/calendars/discord/4.ics".to_string(), ("\"other\"".to_string(), resource(5, "Five").data));
+        push(&client, &target, &mut forgotten, &resources).await;
+        assert_eq!(forgotten[&discord::Snowflake(1)].hash, "One for the third time");
+        assert_eq!(forgotten[&discord::Snowflake(3)].hash, "");
+        assert!(!forgotten.contains_key(&discord::Snowflake(4)));
+        push(&client, &target, &mut forgotten, &resources).await;
+        assert!(data("/calendars/discord/3.ics").unwrap().contains("SUMMARY:Three, changed"));
+        assert!(data("/calendars/discord/4.ics").unwrap().contains("SUMMARY:Five"));
+    }
+
+    #[tokio::test]
+    async fn keeps_guilds_sharing_a_collection_apart() {
+        let (url, store) = stand_in().await;
+        let client = crate::plain_client().unwrap();
+        let path = std::env::temp_dir().join(format!("caldav-push-{}.json", rand::random::<u64>()));
+        let mappings = Mappings::load(path.clone()).unwrap();
+        let first = PushConfig { guild: discord::Snowflake(10), url: url.clone(), username: None, password: None };
+        let second = PushConfig { guild: discord::Snowflake(20), url, username: None, password: None };
+        let etags = || {
+            let mut etags = store.lock().unwrap().iter().map(|(p, (e, _))| (p.clone(), e.clone())).collect::<Vec<_>>();
+            etags.sort();
+            etags
+        };
+
+        mappings.push(&client, &first, &[resource(1, "One")]).await;
+        mappings.push(&client, &second, &[resource(2, "Two")]).await;
+        let written = etags();
+        assert_eq!(written.len(), 2);
+
+        // Neither guild's events are taken as stale by the other, so nothing is deleted or written again
+        mappings.push(&client, &first, &[resource(1, "One")]).await;
+        mappings.push(&client, &second, &[resource(2, "Two")]).await;
+        assert_eq!(etags(), written);
+        let reloaded = Mappings::load(path.clone()).unwrap();
+        assert_eq!(reloaded.collections.lock().await.values().next().map(|g| g.len()), Some(2));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use crate::discord;
+use chrono::prelude::*;
+
+/// A field of an event that differs between two snapshots, with its old and new values
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "field", rename_all = "snake_case")]
+pub enum FieldChange {
+    Name { old: String, new: String },
+    Description { old: Option<String>, new: Option<String> },
+    ScheduledStartTime { old: DateTime<Utc>, new: DateTime<Utc> },
+    ScheduledEndTime { old: Option<DateTime<Utc>>, new: Option<DateTime<Utc>> },
+    Status { old: discord::GuildEventStatus, new: discord::GuildEventStatus },
+    EntityType { old: discord::GuildEventEntityType, new: discord::GuildEventEntityType },
+    ChannelId { old: Option<discord::Snowflake>, new: Option<discord::Snowflake> },
+    Location { old: Option<String>, new: Option<String> },
+    Image { old: Option<String>, new: Option<String> },
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChangeKind {
+    Created,
+    Updated { fields: Vec<FieldChange> },
+    Deleted,
+}
+
+/// Something that happened to an event between two snapshots of a guild's events
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Change {
+    pub guild_id: discord::Snowflake,
+    pub event_id: discord::Snowflake,
+    /// The event's name, as of the newest snapshot it's in
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: ChangeKind,
+}
+
+fn location(event: &discord::GuildEvent) -> Option<String> {
+    event.entity_metadata.as_ref().and_then(|m| m.location.clone())
+}
+
+macro_rules! diff_fields {
+    ($old:expr, $new:expr, $($variant:ident => $get:expr),* $(,)?) => {{
+        let mut fields = vec![];
+        $(
+            let (old, new) = ($get($old), $get($new));
+            if old != new {
+                fields.push(FieldChange::$variant { old, new });
+            }
+        )*
+        fields
+    }};
+}
+
+fn diff_event(old: &discord::GuildEvent, new: &discord::GuildEvent) -> Vec<FieldChange> {
+    diff_fields!(old, new,
+        Name => |e: &discord::GuildEvent| e.name.clone(),
+        Description => |e: &discord::GuildEvent| e.description.clone(),
+        ScheduledStartTime => |e: &discord::GuildEvent| e.scheduled_start_time,
+        ScheduledEndTime => |e: &discord::GuildEvent| e.scheduled_end_time,
+        Status => |e: &discord::GuildEvent| e.status,
+        EntityType => |e: &discord::GuildEvent| e.entity_type,
+        ChannelId => |e: &discord::GuildEvent| e.channel_id,
+        Location => location,
+        Image => |e: &discord::GuildEvent| e.image.clone(),
+    )
+}
+
+/// Compares consecutive snapshots of a guild's events, giving created and updated events in the order of the new
+/// snapshot, followed by deleted events in the order of the old one
+pub fn diff(old: &[discord::GuildEvent], new: &[discord::GuildEvent]) -> Vec<Change> {
+    let old_events = old.iter().map(|e| (e.id, e)).collect::<std::collections::HashMap<_, _>>();
+    let new_ids = new.iter().map(|e| e.id).collect::<std::collections::HashSet<_>>();
+
+    let mut changes = vec![];
+    for event in new {
+        let kind = match old_events.get(&event.id) {
+            None => ChangeKind::Created,
+            Some(old_event) => {
+                let fields = diff_event(old_event, event);
+                if fields.is_empty() {
+                    continue;
+                }
+                ChangeKind::Updated { fields }
+            }
+        };
+        changes.push(Change { guild_id: event.guild_id, event_id: event.id, name: event.name.clone(), kind });
+    }
+    for event in old.iter().filter(|e| !new_ids.contains(&e.id)) {
+        changes.push(Change { guild_id: event.guild_id, event_id: event.id, name: event.name.clone(), kind: ChangeKind::Deleted });
+    }
+    changes
+}
+
+#[cfg(test)]
+pub fn test_event(id: u64, name: &str, start: &str, location: &str) -> discord::GuildEvent {
+    serde_json::from_value(serde_json::json!({
+        "id": id.to_string(), "guild_id": "81384788765712384", "channel_id": null, "name": name, "image": null,
+        "scheduled_start_time": start, "scheduled_end_time": "2030-01-01T00:00:00Z", "privacy_level": 2, "status": 1,
+        "entity_type": 3, "entity_id": null, "entity_metadata": { "location": location }
+    })).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots() {
+        let old = vec![
+            test_event(1, "Game night", "2029-01-01T20:00:00Z", "Pub"),
+            test_event(2, "Movie night", "2029-01-02T20:00:00Z", "Cinema"),
+            test_event(3, "Book club", "2029-01-03T20:00:00Z", "Library"),
+        ];
+        let mut cancelled = test_event(2, "Movie night", "2029-01-02T20:00:00Z", "Cinema");
+        cancelled.status = discord::GuildEventStatus::Cancelled;
+        let new = vec![
+            test_event(1, "Game night", "2029-01-01T21:00:00Z", "Bar"),
+            cancelled,
+            test_event(4, "Quiz", "2029-01-04T20:00:00Z", "Pub"),
+        ];
+
+        assert!(diff(&old, &old).is_empty());
+        let changes = diff(&old, &new);
+        assert_eq!(changes.len(), 4);
+        assert_eq!(changes[0].kind, ChangeKind::Updated { fields: vec![
+            FieldChange::ScheduledStartTime {
+                old: "2029-01-01T20:00:00Z".parse().unwrap(), new: "2029-01-01T21:00:00Z".parse().unwrap()
+            },
+            FieldChange::Location { old: Some("Pub".to_string()), new: Some("Bar".to_string()) },
+        ] });
+        assert_eq!(changes[1].kind, ChangeKind::Updated { fields: vec![FieldChange::Status {
+            old: discord::GuildEventStatus::Scheduled, new: discord::GuildEventStatus::Cancelled
+        }] });
+        assert_eq!((changes[2].event_id, &changes[2].kind), (discord::Snowflake(4), &ChangeKind::Created));
+        assert_eq!((changes[3].event_id, &changes[3].kind), (discord::Snowflake(3), &ChangeKind::Deleted));
+
+        assert_eq!(serde_json::to_value(&changes[1]).unwrap(), serde_json::json!({
+            "guild_id": "81384788765712384", "event_id": "2", "name": "Movie night", "type": "updated",
+            "fields": [{ "field": "status", "old": 1, "new": 4 }]
+        }));
+    }
+}
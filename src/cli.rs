@@ -0,0 +1,184 @@
+const USAGE: &str = "Usage:
+    discord-events-export                                     Run the web server
+    discord-events-export export --guild <id> --out <file>    Export one guild's calendar
+    discord-events-export export-all --dir <dir>              Export the calendar of every guild the bot is in
+    discord-events-export register-commands [--guild <id>]    Register the /calendar command, in one guild or globally
+    discord-events-export import --guild <id> --feed <source> Sync a calendar into a guild's external events
+
+Export options:
+    --token-file <file>    Read the bot token from a file, instead of the DISCORD_TOKEN environment variable
+    --root-url <url>       Root URL the calendars will be served from, used for each calendar's URL
+    --attendees <limit>    List up to this many interested users per event as attendees
+    --tz <zone>            Give event times in this IANA time zone, such as Europe/London, instead of UTC
+    --settings <file>      Apply the guild settings in this TOML or YAML file
+    --lang <language>      Write generated text in this language, such as de, instead of English
+    --data-dir <dir>       Leave out the guilds blocked in this data_dir by export-all, default the current directory
+
+Import options:
+    --feed <source>        URL or file of the calendar to import
+    --state <file>         File remembering which events were imported, default imported.json
+    --dry-run              Show what would change on Discord without changing it";
+
+#[derive(Default)]
+struct ExportArgs {
+    guild: Option<crate::discord::Snowflake>,
+    out: Option<std::path::PathBuf>,
+    dir: Option<std::path::PathBuf>,
+    token_file: Option<std::path::PathBuf>,
+    root_url: Option<String>,
+    attendees: Option<usize>,
+    tz: Option<chrono_tz::Tz>,
+    settings: Option<std::path::PathBuf>,
+    lang: Option<crate::locale::Locale>,
+    data_dir: Option<std::path::PathBuf>,
+    feed: Option<String>,
+    state: Option<std::path::PathBuf>,
+    dry_run: bool,
+}
+
+impl ExportArgs {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut out = ExportArgs::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().cloned().ok_or_else(|| format!("Missing value for {}\n\n{}", arg, USAGE));
+            match arg.as_str() {
+                "--guild" => out.guild = Some(value()?.parse()
+                    .map_err(|e| format!("Invalid guild ID: {}", e))?),
+                "--out" => out.out = Some(value()?.into()),
+                "--dir" => out.dir = Some(value()?.into()),
+                "--token-file" => out.token_file = Some(value()?.into()),
+                "--root-url" => out.root_url = Some(value()?),
+                "--attendees" => out.attendees = Some(value()?.parse()
+                    .map_err(|e| format!("Invalid attendee limit: {}", e))?),
+                "--tz" => out.tz = Some(value()?.parse()
+                    .map_err(|e| format!("Invalid time zone: {}", e))?),
+                "--settings" => out.settings = Some(value()?.into()),
+                "--lang" => {
+                    let lang = value()?;
+                    out.lang = Some(crate::locale::Locale::parse(&lang).ok_or_else(|| format!("Unsupported language {}", lang))?);
+                },
+                "--data-dir" => out.data_dir = Some(value()?.into()),
+                "--feed" => out.feed = Some(value()?),
+                "--state" => out.state = Some(value()?.into()),
+                "--dry-run" => out.dry_run = true,
+                a => return Err(format!("Unknown argument {}\n\n{}", a, USAGE))
+            }
+        }
+        Ok(out)
+    }
+
+    fn client(&self) -> Result<reqwest::Client, String> {
+        let token = match &self.token_file {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read token file: {}", e))?
+                .trim().to_string(),
+            None => std::env::var("DISCORD_TOKEN")
+                .map_err(|_| format!("DISCORD_TOKEN is not set\n\n{}", USAGE))?
+        };
+        crate::bot_client(&token)
+    }
+}
+
+async fn export_guild(
+    client: &reqwest::Client, args: &ExportArgs, settings: &crate::settings::Settings, guild_id: &crate::discord::Snowflake,
+    out: &std::path::Path
+) -> Result<(), String> {
+    let settings = settings.guild(guild_id);
+    let time_zone = args.tz.or(settings.tz);
+    let calendar = crate::guild_calendar(
+        client, args.root_url.as_deref(), guild_id, None, args.attendees, time_zone, &settings, args.lang.unwrap_or_default()
+    ).await
+        .map_err(|e| format!("Unable to export guild {}: {}", guild_id, e))?;
+    let write = || {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(out)?);
+        calendar.write_io(&mut file)?;
+        std::io::Write::flush(&mut file)
+    };
+    write().map_err(|e| format!("Unable to write {}: {}", out.display(), e))
+}
+
+pub async fn run(args: &[String]) -> Result<(), String> {
+    match args[0].as_str() {
+        "export" => {
+            let args = ExportArgs::parse(&args[1..])?;
+            let guild = args.guild.as_ref().ok_or_else(|| format!("Missing --guild\n\n{}", USAGE))?;
+            let out = args.out.as_ref().ok_or_else(|| format!("Missing --out\n\n{}", USAGE))?;
+            let client = args.client()?;
+            let settings = crate::settings::Settings::load(args.settings.clone())?;
+            export_guild(&client, &args, &settings, guild, out).await
+        },
+        "export-all" => {
+            let args = ExportArgs::parse(&args[1..])?;
+            let dir = args.dir.as_ref().ok_or_else(|| format!("Missing --dir\n\n{}", USAGE))?;
+            let client = args.client()?;
+            let settings = crate::settings::Settings::load(args.settings.clone())?;
+            let data_dir = args.data_dir.clone().unwrap_or_else(crate::default_data_dir);
+            let registry = crate::admin::Registry::load(data_dir.join("blocked.json"))
+                .map_err(|e| format!("Unable to load blocked guilds: {}", e))?;
+            tokio::fs::create_dir_all(dir).await
+                .map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+            let guilds = crate::current_user_guilds(&client, None).await
+                .map_err(|e| format!("Unable to list guilds: {}", e))?;
+            let mut failed = false;
+            for guild in guilds {
+                if registry.is_blocked(&guild.id).await {
+                    continue;
+                }
+                let out = dir.join(format!("{}.ics", guild.id));
+                // One guild failing shouldn't stop the others from being exported
+                if let Err(e) = export_guild(&client, &args, &settings, &guild.id, &out).await {
+                    eprintln!("{}", e);
+                    failed = true;
+                }
+            }
+            if failed {
+                Err("Some guilds failed to export".to_string())
+            } else {
+                Ok(())
+            }
+        },
+        "register-commands" => {
+            let args = ExportArgs::parse(&args[1..])?;
+            let client = args.client()?;
+            crate::interactions::register_commands(&client, args.guild.as_ref(), args.root_url.as_deref()).await
+        },
+        "import" => {
+            let args = ExportArgs::parse(&args[1..])?;
+            let guild = args.guild.ok_or_else(|| format!("Missing --guild\n\n{}", USAGE))?;
+            let feed = args.feed.as_ref().ok_or_else(|| format!("Missing --feed\n\n{}", USAGE))?;
+            let client = args.client()?;
+            let plain_client = crate::plain_client()?;
+            let ics = if feed.starts_with("http://") || feed.starts_with("https://") {
+                plain_client.get(feed).send().await.and_then(|r| r.error_for_status())
+                    .map_err(|e| format!("Unable to fetch {}: {}", feed, e))?
+                    .text().await.map_err(|e| format!("Unable to fetch {}: {}", feed, e))?
+            } else {
+                std::fs::read_to_string(feed).map_err(|e| format!("Unable to read {}: {}", feed, e))?
+            };
+            let state_path = args.state.clone().unwrap_or_else(|| "imported.json".into());
+            let mut state = crate::import::ImportState::load(&state_path)?;
+            let api = crate::import::Api { client: &client, base: crate::API_BASE };
+            let (actions, failed) = crate::import::import(&api, &plain_client, guild, &ics, state.guild(guild), args.dry_run).await?;
+            for action in &actions {
+                println!("{}", action);
+            }
+            if actions.is_empty() {
+                println!("Nothing to change");
+            }
+            if !args.dry_run {
+                state.save(&state_path)?;
+            }
+            if failed > 0 {
+                Err(format!("{} changes failed", failed))
+            } else {
+                Ok(())
+            }
+        },
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        c => Err(format!("Unknown command {}\n\n{}", c, USAGE))
+    }
+}
@@ -1,10 +1,14 @@
 use chrono::prelude::*;
 
+/// Milliseconds from the Unix epoch to the Discord epoch, the first second of 2015
+const DISCORD_EPOCH: u64 = 1420070400000;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Snowflake(pub u64);
 
 impl Snowflake {
     pub fn timestamp(&self) -> DateTime<Utc> {
-        let v = (self.0 >> 22) + 1420070400000;
+        let v = (self.0 >> 22) + DISCORD_EPOCH;
         Utc.timestamp_millis(v as i64)
     }
 
@@ -33,24 +37,84 @@ impl std::fmt::Display for Snowflake {
     }
 }
 
+impl std::str::FromStr for Snowflake {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Snowflake, Self::Err> {
+        s.parse().map(Snowflake)
+    }
+}
+
+/// Discord sends snowflakes as strings, as they don't fit in a JavaScript number, but some payloads use numbers
+struct SnowflakeVisitor;
+
+impl serde::de::Visitor<'_> for SnowflakeVisitor {
+    type Value = Snowflake;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a snowflake, as a string or number")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Snowflake, E> {
+        v.parse().map_err(|e: std::num::ParseIntError| E::custom(e.to_string()))
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Snowflake, E> {
+        Ok(Snowflake(v))
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Snowflake, E> {
+        u64::try_from(v).map(Snowflake).map_err(|_| E::custom("snowflakes can't be negative"))
+    }
+}
+
 impl<'de> serde::Deserialize<'de> for Snowflake {
     fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Snowflake, D::Error> {
-        let v = String::deserialize(deserializer)?;
-        let n = v.parse::<u64>()
-            .map_err(|e| serde::de::Error::custom(e.to_string()))?;
-        Ok(Snowflake(n))
+        deserializer.deserialize_any(SnowflakeVisitor)
+    }
+}
+
+impl serde::Serialize for Snowflake {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'a> rocket::request::FromParam<'a> for Snowflake {
+    type Error = std::num::ParseIntError;
+
+    fn from_param(param: &'a str) -> Result<Snowflake, Self::Error> {
+        param.parse()
+    }
+}
+
+impl rocket::http::uri::fmt::UriDisplay<rocket::http::uri::fmt::Path> for Snowflake {
+    fn fmt(&self, f: &mut rocket::http::uri::fmt::Formatter<'_, rocket::http::uri::fmt::Path>) -> std::fmt::Result {
+        f.write_value(self.0)
     }
 }
 
+rocket::http::impl_from_uri_param_identity!([rocket::http::uri::fmt::Path] Snowflake);
+
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct User {
     pub id: Snowflake,
     pub username: String,
     pub discriminator: String,
+    #[serde(default)]
+    pub global_name: Option<String>,
     pub avatar: Option<String>
 }
 
+impl User {
+    pub fn display_name(&self) -> &str {
+        self.global_name.as_deref().unwrap_or(&self.username)
+    }
+}
+
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct Guild {
     pub id: Snowflake,
     pub name: String,
@@ -62,8 +126,28 @@ pub struct Guild {
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct PartialGuild {
+    pub id: Snowflake,
+    pub name: String,
+    pub icon: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct Invite {
+    pub code: String,
+    #[serde(default)]
+    pub guild: Option<PartialGuild>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct Channel {
     pub id: Snowflake,
+    pub r#type: u8,
     #[serde(default)]
     pub guild_id: Option<Snowflake>,
     #[serde(default)]
@@ -72,7 +156,15 @@ pub struct Channel {
     pub topic: Option<String>,
 }
 
+impl Channel {
+    /// Whether events can be held in the channel, which is the case for voice and stage channels
+    pub fn holds_events(&self) -> bool {
+        matches!(self.r#type, 2 | 13)
+    }
+}
+
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct GuildEvent {
     pub id: Snowflake,
     pub guild_id: Snowflake,
@@ -91,10 +183,39 @@ pub struct GuildEvent {
     pub entity_id: Option<String>,
     pub entity_metadata: Option<GuildEventEntityMetadata>,
     #[serde(default)]
-    pub creator: Option<User>
+    pub creator: Option<User>,
+    /// How many users are interested in the event, if it was asked for
+    #[serde(default)]
+    pub user_count: Option<u64>
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct GuildEventUser {
+    pub guild_scheduled_event_id: Snowflake,
+    pub user: User,
+    #[serde(default)]
+    pub member: Option<GuildMember>
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct GuildMember {
+    #[serde(default)]
+    pub user: Option<User>,
+    #[serde(default)]
+    pub nick: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct Role {
+    pub id: Snowflake,
+    pub name: String
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct GuildEventEntityMetadata {
     pub location: Option<String>
 }
@@ -105,7 +226,7 @@ pub enum GuildEventPrivacyLevel {
     GuildOnly = 2
 }
 
-#[derive(Debug, serde_repr::Deserialize_repr)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde_repr::Serialize_repr, serde_repr::Deserialize_repr)]
 #[repr(i32)]
 pub enum GuildEventStatus {
     Scheduled = 1,
@@ -114,10 +235,102 @@ pub enum GuildEventStatus {
     Cancelled = 4
 }
 
-#[derive(Debug, serde_repr::Deserialize_repr)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde_repr::Serialize_repr, serde_repr::Deserialize_repr)]
 #[repr(i32)]
 pub enum GuildEventEntityType {
     Stage = 1,
     Voice = 2,
     External = 3
-}
\ No newline at end of file
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct Application {
+    pub id: Snowflake,
+    pub name: String
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct Interaction {
+    pub id: Snowflake,
+    pub application_id: Snowflake,
+    #[serde(rename = "type")]
+    pub kind: InteractionType,
+    #[serde(default)]
+    pub data: Option<InteractionData>,
+    #[serde(default)]
+    pub guild_id: Option<Snowflake>,
+    /// Language the user's Discord client is set to
+    #[serde(default)]
+    pub locale: Option<String>,
+    pub token: String
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct InteractionData {
+    pub id: Snowflake,
+    pub name: String
+}
+
+#[derive(Debug, PartialEq, Eq, serde_repr::Deserialize_repr)]
+#[repr(i32)]
+pub enum InteractionType {
+    Ping = 1,
+    ApplicationCommand = 2,
+    MessageComponent = 3,
+    ApplicationCommandAutocomplete = 4,
+    ModalSubmit = 5
+}
+
+#[derive(Serialize, Debug)]
+pub struct InteractionResponse {
+    #[serde(rename = "type")]
+    pub kind: InteractionCallbackType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<InteractionCallbackData>
+}
+
+#[derive(Serialize, Debug)]
+pub struct InteractionCallbackData {
+    pub content: String,
+    pub flags: u64
+}
+
+/// Only the user who ran the command can see the reply
+pub const MESSAGE_FLAG_EPHEMERAL: u64 = 1 << 6;
+
+#[derive(Debug, serde_repr::Serialize_repr)]
+#[repr(i32)]
+pub enum InteractionCallbackType {
+    Pong = 1,
+    ChannelMessageWithSource = 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snowflake_forms() {
+        let id: Snowflake = serde_json::from_str("\"175928847299117063\"").unwrap();
+        assert_eq!(id, serde_json::from_str("175928847299117063").unwrap());
+        assert_eq!(serde_json::to_string(&id).unwrap(), "\"175928847299117063\"");
+        assert!(serde_json::from_str::<Snowflake>("\"not a snowflake\"").is_err());
+        assert!(serde_json::from_str::<Snowflake>("-1").is_err());
+
+        assert_eq!(id.timestamp(), Utc.timestamp_millis(1462015105796));
+    }
+
+    #[test]
+    fn channels_holding_events() {
+        let channel = |kind: u8| serde_json::from_value::<Channel>(serde_json::json!({
+            "id": "20", "type": kind, "guild_id": "10", "name": "general"
+        })).unwrap();
+        assert!(channel(2).holds_events());
+        assert!(channel(13).holds_events());
+        assert!(!channel(0).holds_events());
+        assert!(!channel(5).holds_events());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{discord, ical, locale, settings, Config, API_BASE};
+use chrono::prelude::*;
+
+/// Longest range a free/busy lookup can cover
+const MAX_RANGE_DAYS: i64 = 366;
+
+type Period = (DateTime<Utc>, DateTime<Utc>);
+
+/// Parses a time given in a request, as an RFC 3339 date and time or a date, which is taken as midnight UTC
+fn parse_time(time: &str) -> Result<DateTime<Utc>, rocket::http::Status> {
+    DateTime::parse_from_rfc3339(time).map(|t| t.with_timezone(&Utc))
+        .or_else(|_| NaiveDate::parse_from_str(time, "%Y-%m-%d").map(|d| Utc.from_utc_datetime(&d.and_hms(0, 0, 0))))
+        .map_err(|_| rocket::http::Status::BadRequest)
+}
+
+/// When each voice and stage channel is taken by an event between `start` and `end`, with overlapping events merged.
+/// Events without an end are taken to last the guild's default duration, or an hour.
+fn channel_periods(
+    events: &[discord::GuildEvent], settings: &settings::GuildSettings, start: DateTime<Utc>, end: DateTime<Utc>
+) -> std::collections::BTreeMap<discord::Snowflake, Vec<Period>> {
+    let mut channels = std::collections::BTreeMap::<_, Vec<Period>>::new();
+    for event in events {
+        let channel_id = match event.channel_id {
+            Some(c) if matches!(event.status, discord::GuildEventStatus::Scheduled | discord::GuildEventStatus::Active) => c,
+            _ => continue
+        };
+        let event_end = event.scheduled_end_time.unwrap_or_else(|| {
+            event.scheduled_start_time + settings.default_duration(event.entity_type).unwrap_or_else(|| chrono::Duration::hours(1))
+        });
+        let period = (event.scheduled_start_time.max(start), event_end.min(end));
+        if period.0 < period.1 {
+            channels.entry(channel_id).or_default().push(period);
+        }
+    }
+    for periods in channels.values_mut() {
+        periods.sort();
+        let mut merged: Vec<Period> = vec![];
+        for (start, end) in periods.drain(..) {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end))
+            }
+        }
+        *periods = merged;
+    }
+    channels
+}
+
+/// Reports when each of a guild's voice and stage channels is busy with an event, between `start` (now by default)
+/// and `end` (four weeks after the start by default). Channels without events in that time are listed as free.
+#[get("/guilds/<guild_id>/freebusy.ics?<start>&<end>&<lang>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn calendar(
+    client: &rocket::State<reqwest::Client>, config: &rocket::State<Config>,
+    settings: &rocket::State<std::sync::Arc<settings::Settings>>, registry: &rocket::State<std::sync::Arc<crate::admin::Registry>>,
+    accept_language: locale::AcceptLanguage, guild_id: Result<discord::Snowflake, std::num::ParseIntError>,
+    start: Option<String>, end: Option<String>, lang: Option<String>
+) -> Result<crate::CalendarResponse, rocket::http::Status> {
+    let guild_id = guild_id.map_err(|_| rocket::http::Status::BadRequest)?;
+    if registry.is_blocked(&guild_id).await {
+        return Err(rocket::http::Status::Forbidden);
+    }
+    let locale = accept_language.locale(lang.as_deref());
+    let from = start.as_deref().map(parse_time).transpose()?.unwrap_or_else(Utc::now);
+    let to = end.as_deref().map(parse_time).transpose()?.unwrap_or(from + chrono::Duration::weeks(4));
+    if to <= from || to - from > chrono::Duration::days(MAX_RANGE_DAYS) {
+        return Err(rocket::http::Status::BadRequest);
+    }
+
+    let discord_guild: discord::Guild = client.get(format!("{}/guilds/{}", API_BASE, guild_id))
+        .send().await.map_err(|_| rocket::http::Status::InternalServerError)?
+        .error_for_status().map_err(crate::map_discord_error)?
+        .json().await.map_err(|_| rocket::http::Status::InternalServerError)?;
+    let events = crate::guild_events(client, None, &guild_id).await?;
+
+    let now = Utc::now();
+    let mut calendar = ical::Calendar::new(format!("Discord Events Export {}", env!("CARGO_PKG_VERSION")))
+        .method(ical::Method::Publish)
+        .name(locale.text("free-busy-calendar-name", &[("guild", &discord_guild.name)]))
+        .language(locale.language().map(ToString::to_string))
+        .source(format!("{}{}", config.root_url, uri!(calendar(&guild_id, start.as_deref(), end.as_deref(), lang.as_deref()))))
+        .refresh_interval(config.cache_ttl());
+    let channels: Vec<discord::Channel> = client.get(format!("{}/guilds/{}/channels", API_BASE, guild_id))
+        .send().await.map_err(|_| rocket::http::Status::InternalServerError)?
+        .error_for_status().map_err(crate::map_discord_error)?
+        .json().await.map_err(|_| rocket::http::Status::InternalServerError)?;
+    let mut busy = channel_periods(&events, &settings.guild(&guild_id), from, to);
+    // Every channel gets a VFREEBUSY, so one without events shows up as free rather than unknown
+    for channel in channels.into_iter().filter(discord::Channel::holds_events) {
+        let free_busy = ical::FreeBusy::new(format!("{}@f.discord-events.magicalcodewit.ch", channel.id), now, from, to)
+            .comment(channel.name.map(|c| locale.text("channel-location", &[("channel", &c)])))
+            .url(format!("https://discord.com/channels/{}/{}", guild_id, channel.id));
+        calendar = calendar.free_busy(busy.remove(&channel.id).unwrap_or_default().into_iter()
+            .fold(free_busy, |f, (start, end)| f.period(ical::FreeBusyType::Busy, start, end)));
+    }
+
+    Ok(crate::CalendarResponse::new(calendar, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voice_event(id: u64, channel_id: u64, start: &str, end: Option<&str>) -> discord::GuildEvent {
+        let mut event = crate::changes::test_event(id, "Talk", start, "");
+        event.channel_id = Some(discord::Snowflake(channel_id));
+        event.entity_type = discord::GuildEventEntityType::Stage;
+        event.entity_metadata = None;
+        event.scheduled_end_time = end.map(|e| e.parse().unwrap());
+        event
+    }
+
+    #[test]
+    fn merges_busy_periods() {
+        let time = |t: &str| t.parse::<DateTime<Utc>>().unwrap();
+        let mut cancelled = voice_event(5, 1, "2029-01-03T10:00:00Z", None);
+        cancelled.status = discord::GuildEventStatus::Cancelled;
+        let events = vec![
+            voice_event(1, 1, "2029-01-01T18:00:00Z", Some("2029-01-01T20:00:00Z")),
+            voice_event(2, 1, "2029-01-01T19:30:00Z", None),
+            voice_event(3, 2, "2028-12-31T23:00:00Z", Some("2029-01-01T01:00:00Z")),
+            voice_event(4, 2, "2029-02-01T10:00:00Z", None),
+            crate::changes::test_event(6, "Game night", "2029-01-01T20:00:00Z", "Pub"),
+            cancelled,
+        ];
+        let settings = settings::GuildSettings {
+            durations: settings::Durations { voice: None, stage: Some(90) },
+            ..Default::default()
+        };
+
+        let channels = channel_periods(&events, &settings, time("2029-01-01T00:00:00Z"), time("2029-01-08T00:00:00Z"));
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[&discord::Snowflake(1)], vec![(time("2029-01-01T18:00:00Z"), time("2029-01-01T21:00:00Z"))]);
+        assert_eq!(channels[&discord::Snowflake(2)], vec![(time("2029-01-01T00:00:00Z"), time("2029-01-01T01:00:00Z"))]);
+
+        assert_eq!(parse_time("2029-01-01").unwrap(), time("2029-01-01T00:00:00Z"));
+        assert_eq!(parse_time("2029-01-01T01:00:00+01:00").unwrap(), time("2029-01-01T00:00:00Z"));
+        assert!(parse_time("tomorrow").is_err());
+    }
+}
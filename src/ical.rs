@@ -1,175 +1,623 @@
 use chrono::prelude::*;
+use chrono_tz::{OffsetComponents, OffsetName};
 
-struct ContentLine<'a> {
-    name: std::borrow::Cow<'a, str>,
-    params: std::borrow::Cow<'a, [Parameter<'a>]>,
-    value: std::borrow::Cow<'a, str>
+pub mod parse;
+mod validate;
+#[cfg(test)]
+mod bench;
+
+pub use validate::validate;
+
+type TzOffset = <chrono_tz::Tz as TimeZone>::Offset;
+
+/// Declares an enumerated property or parameter value, along with how it's written out
+macro_rules! value_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident => $text:literal),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        #[allow(dead_code)]
+        pub enum $name {
+            $($variant),*
+        }
+
+        impl $name {
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $text),*
+                }
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+    };
 }
 
-fn escape_text(param: &str) -> String {
-    param
-        .replace('\\', "\\\\")
-        .replace(";", "\\;")
-        .replace(",", "\\,")
-        .replace("\n", "\\n")
+value_enum!(
+    /// CALSCALE of a calendar
+    Scale { Gregorian => "GREGORIAN" }
+);
+
+value_enum!(
+    /// iTIP METHOD of a calendar
+    Method {
+        Publish => "PUBLISH", Request => "REQUEST", Reply => "REPLY", Add => "ADD", Cancel => "CANCEL",
+        Refresh => "REFRESH", Counter => "COUNTER", DeclineCounter => "DECLINECOUNTER",
+    }
+);
+
+value_enum!(
+    /// STATUS of an event
+    EventStatus { Tentative => "TENTATIVE", Confirmed => "CONFIRMED", Cancelled => "CANCELLED" }
+);
+
+value_enum!(
+    /// Access CLASS of an event
+    Class { Public => "PUBLIC", Private => "PRIVATE", Confidential => "CONFIDENTIAL" }
+);
+
+value_enum!(
+    /// TRANSP of an event, whether it blocks out time for free/busy lookups
+    Transparency { Opaque => "OPAQUE", Transparent => "TRANSPARENT" }
+);
+
+value_enum!(
+    /// FBTYPE parameter of a free/busy period
+    FreeBusyType {
+        Free => "FREE", Busy => "BUSY", BusyUnavailable => "BUSY-UNAVAILABLE", BusyTentative => "BUSY-TENTATIVE",
+    }
+);
+
+value_enum!(
+    /// ROLE parameter of an attendee
+    Role {
+        Chair => "CHAIR", RequiredParticipant => "REQ-PARTICIPANT", OptionalParticipant => "OPT-PARTICIPANT",
+        NonParticipant => "NON-PARTICIPANT",
+    }
+);
+
+value_enum!(
+    /// PARTSTAT parameter of an attendee to an event
+    ParticipationStatus {
+        NeedsAction => "NEEDS-ACTION", Accepted => "ACCEPTED", Declined => "DECLINED", Tentative => "TENTATIVE",
+        Delegated => "DELEGATED",
+    }
+);
+
+value_enum!(
+    /// FEATURE parameter of a conference
+    Feature {
+        Audio => "AUDIO", Chat => "CHAT", Feed => "FEED", Moderator => "MODERATOR", Phone => "PHONE",
+        Screen => "SCREEN", Video => "VIDEO",
+    }
+);
+
+value_enum!(
+    /// DISPLAY parameter of an image
+    ImageDisplay { Badge => "BADGE", Graphic => "GRAPHIC", Fullsize => "FULLSIZE", Thumbnail => "THUMBNAIL" }
+);
+
+/// A property value, written out according to its type
+enum Value<'a> {
+    Text(&'a str),
+    /// URI or CAL-ADDRESS
+    Uri(&'a str),
+    /// Enumerated values and others written out as is
+    Token(&'a str),
+    DateTime(DateTime<Utc>),
+    LocalDateTime(NaiveDateTime),
+    Duration(std::time::Duration),
+    /// A DURATION counting back from whatever it's relative to
+    DurationBefore(std::time::Duration),
+    UtcOffset(FixedOffset),
+    Binary(&'a [u8]),
+    /// A PERIOD between two UTC times
+    Period(DateTime<Utc>, DateTime<Utc>),
 }
 
-impl std::fmt::Display for ContentLine<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let line = format!("{}{}:{}", self.name, self.params.iter().map(ToString::to_string).fold(String::new(), |accum, i| {
-            format!("{};{}", accum, i)
-        }), escape_text(&self.value));
-
-        let mut lines = vec![];
-        let mut cur_line = String::new();
-        let mut a = 0;
-        let mut last_i = 0;
-        for (i, c) in line.char_indices() {
-            let s = i - last_i;
-            if a + s < 75 {
-                cur_line.push(c);
-                a += s;
-            } else {
-                lines.push(cur_line);
-                cur_line = format!(" {}", c);
-                a = s;
+fn write_local_datetime<W: std::fmt::Write>(out: &mut W, date_time: &NaiveDateTime) -> std::fmt::Result {
+    write!(
+        out, "{:04}{:02}{:02}T{:02}{:02}{:02}", date_time.year(), date_time.month(), date_time.day(),
+        date_time.hour(), date_time.minute(), date_time.second()
+    )
+}
+
+fn write_duration<W: std::fmt::Write>(out: &mut W, duration: &std::time::Duration) -> std::fmt::Result {
+    let seconds = duration.as_secs();
+    if seconds % 3600 == 0 {
+        write!(out, "PT{}H", seconds / 3600)
+    } else if seconds % 60 == 0 {
+        write!(out, "PT{}M", seconds / 60)
+    } else {
+        write!(out, "PT{}S", seconds)
+    }
+}
+
+fn write_utc_offset<W: std::fmt::Write>(out: &mut W, offset: &FixedOffset) -> std::fmt::Result {
+    let seconds = offset.local_minus_utc();
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.abs();
+    if seconds % 60 == 0 {
+        write!(out, "{}{:02}{:02}", sign, seconds / 3600, seconds / 60 % 60)
+    } else {
+        write!(out, "{}{:02}{:02}{:02}", sign, seconds / 3600, seconds / 60 % 60, seconds % 60)
+    }
+}
+
+fn write_escaped_text<W: std::fmt::Write>(out: &mut W, text: &str) -> std::fmt::Result {
+    for c in text.chars() {
+        match c {
+            '\\' => out.write_str("\\\\")?,
+            ';' => out.write_str("\\;")?,
+            ',' => out.write_str("\\,")?,
+            '\n' => out.write_str("\\n")?,
+            c => out.write_char(c)?
+        }
+    }
+    Ok(())
+}
+
+impl Value<'_> {
+    fn write_to<W: std::fmt::Write>(&self, out: &mut W) -> std::fmt::Result {
+        match self {
+            Value::Text(text) => write_escaped_text(out, text),
+            Value::Uri(s) | Value::Token(s) => out.write_str(s),
+            Value::DateTime(date_time) => {
+                write_local_datetime(out, &date_time.naive_utc())?;
+                out.write_char('Z')
+            },
+            Value::LocalDateTime(date_time) => write_local_datetime(out, date_time),
+            Value::Duration(duration) => write_duration(out, duration),
+            Value::DurationBefore(duration) => {
+                out.write_char('-')?;
+                write_duration(out, duration)
+            },
+            Value::UtcOffset(offset) => write_utc_offset(out, offset),
+            Value::Binary(data) => write!(out, "{}", base64::display::Base64Display::with_config(data, base64::STANDARD)),
+            Value::Period(start, end) => {
+                Value::DateTime(*start).write_to(out)?;
+                out.write_char('/')?;
+                Value::DateTime(*end).write_to(out)
             }
-            last_i = i;
         }
-        lines.push(cur_line);
+    }
+}
+
+fn write_param_value<W: std::fmt::Write>(out: &mut W, value: &str) -> std::fmt::Result {
+    // Parameter values can't contain double quotes at all, even when quoted
+    if value.chars().any(|c| c == ':' || c == ';' || c == ',' || c == '"') {
+        out.write_char('"')?;
+        for c in value.chars() {
+            out.write_char(if c == '"' { '\'' } else { c })?;
+        }
+        out.write_char('"')
+    } else {
+        out.write_str(value)
+    }
+}
+
+/// Writes content lines out, folding them at 75 octets as it goes
+struct Folder<'w, W: std::fmt::Write> {
+    out: &'w mut W,
+    line_length: usize,
+}
 
-        for line in lines {
-            write!(f, "{}\r\n", line)?;
+impl<W: std::fmt::Write> std::fmt::Write for Folder<'_, W> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        if self.line_length + s.len() <= 75 {
+            self.line_length += s.len();
+            return self.out.write_str(s);
+        }
+        for c in s.chars() {
+            self.write_char(c)?;
         }
         Ok(())
     }
+
+    // Continuation lines count their leading space, and characters are never split between lines
+    fn write_char(&mut self, c: char) -> std::fmt::Result {
+        if self.line_length + c.len_utf8() > 75 {
+            self.out.write_str("\r\n ")?;
+            self.line_length = 1;
+        }
+        self.line_length += c.len_utf8();
+        self.out.write_char(c)
+    }
 }
 
-#[derive(Clone)]
-struct Parameter<'a> {
-    name: std::borrow::Cow<'a, str>,
-    values: std::borrow::Cow<'a, [std::borrow::Cow<'a, str>]>
+/// A content line being written out, with parameters written as they're added and the value finishing the line
+struct ContentLine<'w, W: std::fmt::Write> {
+    folder: Folder<'w, W>,
+    result: std::fmt::Result,
 }
 
-fn escape_param<'a>(param: &'a std::borrow::Cow<'a, str>) -> std::borrow::Cow<'a, str> {
-    if param.chars().any(|c| c == ':' || c == ';' || c == ',') {
-        return std::borrow::Cow::Owned(format!("\"{}\"", param));
-    } else {
-        return std::borrow::Cow::Borrowed(&param);
+impl<'w, W: std::fmt::Write> ContentLine<'w, W> {
+    fn new(out: &'w mut W, name: &str) -> Self {
+        let mut folder = Folder { out, line_length: 0 };
+        let result = std::fmt::Write::write_str(&mut folder, name);
+        ContentLine { folder, result }
+    }
+
+    fn param(self, name: &str, value: &str) -> Self {
+        self.param_values(name, [value])
+    }
+
+    fn param_values<'v>(mut self, name: &str, values: impl IntoIterator<Item = &'v str>) -> Self {
+        fn write<W: std::fmt::Write>(out: &mut W, name: &str, values: impl IntoIterator<Item = impl AsRef<str>>)
+            -> std::fmt::Result {
+            out.write_char(';')?;
+            out.write_str(name)?;
+            out.write_char('=')?;
+            for (i, value) in values.into_iter().enumerate() {
+                if i > 0 {
+                    out.write_char(',')?;
+                }
+                write_param_value(out, value.as_ref())?;
+            }
+            Ok(())
+        }
+        if self.result.is_ok() {
+            self.result = write(&mut self.folder, name, values);
+        }
+        self
+    }
+
+    /// Marks a text value as written in `language`, if it's known
+    fn language(self, language: Option<&str>) -> Self {
+        match language {
+            Some(language) => self.param("LANGUAGE", language),
+            None => self
+        }
+    }
+
+    fn value(mut self, value: Value<'_>) -> std::fmt::Result {
+        self.result?;
+        std::fmt::Write::write_char(&mut self.folder, ':')?;
+        value.write_to(&mut self.folder)?;
+        self.folder.out.write_str("\r\n")
+    }
+
+    fn text(self, text: &str) -> std::fmt::Result {
+        self.value(Value::Text(text))
     }
 }
 
-impl std::fmt::Display for Parameter<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}={}", self.name, self.values.into_iter().map(escape_param).fold(String::new(), |accum, i| {
-            if accum.is_empty() {
-                i.to_string()
-            } else {
-                format!("{},{}", accum, i)
+/// A non-standard property, with a text value
+pub struct XProperty {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+    pub value: String,
+}
+
+impl XProperty {
+    fn write_to<W: std::fmt::Write>(&self, out: &mut W) -> std::fmt::Result {
+        self.params.iter()
+            .fold(ContentLine::new(out, &self.name), |line, (name, value)| line.param(name, value))
+            .text(&self.value)
+    }
+}
+
+/// Writes a DATE-TIME property, in UTC or local time in the given time zone
+fn write_date_time<W: std::fmt::Write>(out: &mut W, name: &str, date_time: &DateTime<Utc>, time_zone: Option<&chrono_tz::Tz>)
+    -> std::fmt::Result {
+    match time_zone {
+        Some(tz) => ContentLine::new(out, name).param("TZID", tz.name())
+            .value(Value::LocalDateTime(date_time.with_timezone(tz).naive_local())),
+        None => ContentLine::new(out, name).value(Value::DateTime(*date_time))
+    }
+}
+
+fn same_offset(a: &TzOffset, b: &TzOffset) -> bool {
+    a.fix() == b.fix() && a.dst_offset() == b.dst_offset() && a.abbreviation() == b.abbreviation()
+}
+
+/// Finds every change in UTC offset between two instants, as the instant and the offsets either side of it
+fn time_zone_transitions(tz: &chrono_tz::Tz, from: DateTime<Utc>, to: DateTime<Utc>)
+    -> Vec<(DateTime<Utc>, TzOffset, TzOffset)> {
+    let offset_at = |t: &DateTime<Utc>| tz.offset_from_utc_datetime(&t.naive_utc());
+    let mut out = vec![];
+    let mut cur = from;
+    while cur < to {
+        let next = cur + chrono::Duration::days(1);
+        let (cur_offset, next_offset) = (offset_at(&cur), offset_at(&next));
+        if !same_offset(&cur_offset, &next_offset) {
+            // Transitions fall on whole seconds, so narrow down to the first second with the new offset
+            let (mut lo, mut hi) = (cur, next);
+            while hi - lo > chrono::Duration::seconds(1) {
+                let mid = lo + (hi - lo) / 2;
+                if same_offset(&offset_at(&mid), &cur_offset) {
+                    lo = mid;
+                } else {
+                    hi = mid;
+                }
             }
-        }))
+            out.push((hi, cur_offset, next_offset));
+        }
+        cur = next;
     }
+    out
 }
 
-fn format_datetime(date_time: &DateTime<Utc>) -> String {
-    date_time.format("%Y%m%dT%H%M%SZ").to_string()
+fn write_observance<W: std::fmt::Write>(out: &mut W, onset: NaiveDateTime, from: &TzOffset, to: &TzOffset) -> std::fmt::Result {
+    let kind = if to.dst_offset().is_zero() { "STANDARD" } else { "DAYLIGHT" };
+    ContentLine::new(out, "BEGIN").value(Value::Token(kind))?;
+    ContentLine::new(out, "DTSTART").value(Value::LocalDateTime(onset))?;
+    ContentLine::new(out, "TZOFFSETFROM").value(Value::UtcOffset(from.fix()))?;
+    ContentLine::new(out, "TZOFFSETTO").value(Value::UtcOffset(to.fix()))?;
+    ContentLine::new(out, "TZNAME").text(to.abbreviation())?;
+    ContentLine::new(out, "END").value(Value::Token(kind))
+}
+
+/// Writes a VTIMEZONE with an observance for each offset the zone uses between two instants
+fn write_time_zone<W: std::fmt::Write>(out: &mut W, tz: &chrono_tz::Tz, from: DateTime<Utc>, to: DateTime<Utc>)
+    -> std::fmt::Result {
+    ContentLine::new(out, "BEGIN").value(Value::Token("VTIMEZONE"))?;
+    ContentLine::new(out, "TZID").value(Value::Token(tz.name()))?;
+
+    // Look back far enough to find the transition into the offset in effect at the start
+    let transitions = time_zone_transitions(tz, from - chrono::Duration::days(366), to);
+    let first = match transitions.iter().rposition(|(at, _, _)| *at <= from) {
+        Some(i) => i,
+        None => {
+            let offset = tz.offset_from_utc_datetime(&from.naive_utc());
+            write_observance(out, NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0), &offset, &offset)?;
+            0
+        }
+    };
+    for (at, from_offset, to_offset) in &transitions[first..] {
+        write_observance(out, (*at + from_offset.fix()).naive_utc(), from_offset, to_offset)?;
+    }
+
+    ContentLine::new(out, "END").value(Value::Token("VTIMEZONE"))
 }
 
 pub struct Calendar {
     pub product: String,
     pub version: String,
-    pub scale: Option<String>,
-    pub method: Option<String>,
+    pub scale: Option<Scale>,
+    pub method: Option<Method>,
     pub name: Option<String>,
     pub description: Option<String>,
     pub uid: Option<String>,
     pub url: Option<String>,
+    pub source: Option<String>,
+    pub refresh_interval: Option<std::time::Duration>,
+    pub color: Option<String>,
+    pub images: Vec<Image>,
+    pub time_zone: Option<chrono_tz::Tz>,
+    /// Language tag of the calendar's generated text
+    pub language: Option<String>,
+    /// Nothing outside this module sets these, so they have no builder
+    x_properties: Vec<XProperty>,
     pub events: Vec<Event>,
+    pub free_busy: Vec<FreeBusy>,
 }
 
 impl Calendar {
-    fn to_content_lines<'a>(&'a self) -> Vec<ContentLine<'a>> {
-        let mut out = vec![];
-        out.push(ContentLine {
-            name: std::borrow::Cow::Borrowed("BEGIN"),
-            params: std::borrow::Cow::Borrowed(&[]),
-            value: std::borrow::Cow::Borrowed("VCALENDAR")
-        });
-        out.push(ContentLine {
-            name: std::borrow::Cow::Borrowed("PRODID"),
-            params: std::borrow::Cow::Borrowed(&[]),
-            value: std::borrow::Cow::Borrowed(&self.product)
-        });
-        out.push(ContentLine {
-            name: std::borrow::Cow::Borrowed("VERSION"),
-            params: std::borrow::Cow::Borrowed(&[]),
-            value: std::borrow::Cow::Borrowed(&self.version)
-        });
+    /// An empty iCalendar 2.0 calendar using the Gregorian calendar
+    pub fn new(product: impl Into<String>) -> Self {
+        Calendar {
+            product: product.into(),
+            version: "2.0".to_string(),
+            scale: Some(Scale::Gregorian),
+            method: None,
+            name: None,
+            description: None,
+            uid: None,
+            url: None,
+            source: None,
+            refresh_interval: None,
+            color: None,
+            images: vec![],
+            time_zone: None,
+            language: None,
+            x_properties: vec![],
+            events: vec![],
+            free_busy: vec![],
+        }
+    }
+
+    pub fn method(mut self, method: impl Into<Option<Method>>) -> Self {
+        self.method = method.into();
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<Option<String>>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<Option<String>>) -> Self {
+        self.description = description.into();
+        self
+    }
+
+    pub fn uid(mut self, uid: impl Into<Option<String>>) -> Self {
+        self.uid = uid.into();
+        self
+    }
+
+    pub fn url(mut self, url: impl Into<Option<String>>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    pub fn source(mut self, source: impl Into<Option<String>>) -> Self {
+        self.source = source.into();
+        self
+    }
+
+    pub fn refresh_interval(mut self, refresh_interval: impl Into<Option<std::time::Duration>>) -> Self {
+        self.refresh_interval = refresh_interval.into();
+        self
+    }
+
+    pub fn color(mut self, color: impl Into<Option<String>>) -> Self {
+        self.color = color.into();
+        self
+    }
+
+    pub fn image(mut self, image: Image) -> Self {
+        self.images.push(image);
+        self
+    }
+
+    /// Gives times in local time in this zone, rather than UTC
+    pub fn time_zone(mut self, time_zone: impl Into<Option<chrono_tz::Tz>>) -> Self {
+        self.time_zone = time_zone.into();
+        self
+    }
+
+    pub fn language(mut self, language: impl Into<Option<String>>) -> Self {
+        self.language = language.into();
+        self
+    }
+
+    pub fn event(mut self, event: Event) -> Self {
+        self.events.push(event);
+        self
+    }
+
+    pub fn events(mut self, events: impl IntoIterator<Item = Event>) -> Self {
+        self.events.extend(events);
+        self
+    }
+
+    pub fn free_busy(mut self, free_busy: FreeBusy) -> Self {
+        self.free_busy.push(free_busy);
+        self
+    }
+
+    /// Writes the calendar from its start up to its events, including any VTIMEZONE
+    fn write_header<W: std::fmt::Write>(&self, out: &mut W) -> std::fmt::Result {
+        ContentLine::new(out, "BEGIN").value(Value::Token("VCALENDAR"))?;
+        ContentLine::new(out, "PRODID").text(&self.product)?;
+        ContentLine::new(out, "VERSION").value(Value::Token(&self.version))?;
         if let Some(scale) = &self.scale {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("CALSCALE"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Borrowed(scale)
-            });
+            ContentLine::new(out, "CALSCALE").value(Value::Token(scale.as_str()))?;
         }
         if let Some(method) = &self.method {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("METHOD"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Borrowed(method)
-            });
+            ContentLine::new(out, "METHOD").value(Value::Token(method.as_str()))?;
         }
+        let language = self.language.as_deref();
         if let Some(name) = &self.name {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("NAME"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Borrowed(name)
-            });
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("X-WR-CALNAME"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Borrowed(name)
-            });
+            ContentLine::new(out, "NAME").language(language).text(name)?;
+            ContentLine::new(out, "X-WR-CALNAME").language(language).text(name)?;
         }
         if let Some(description) = &self.description {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("DESCRIPTION"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Borrowed(description)
-            });
+            ContentLine::new(out, "DESCRIPTION").language(language).text(description)?;
         }
         if let Some(uid) = &self.uid {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("UID"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Borrowed(uid)
-            });
+            ContentLine::new(out, "UID").text(uid)?;
         }
         if let Some(url) = &self.url {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("URL"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Borrowed(url)
-            });
+            ContentLine::new(out, "URL").value(Value::Uri(url))?;
+        }
+        if let Some(source) = &self.source {
+            ContentLine::new(out, "SOURCE").param("VALUE", "URI").value(Value::Uri(source))?;
         }
+        if let Some(refresh_interval) = &self.refresh_interval {
+            ContentLine::new(out, "REFRESH-INTERVAL").param("VALUE", "DURATION").value(Value::Duration(*refresh_interval))?;
+            ContentLine::new(out, "X-PUBLISHED-TTL").value(Value::Duration(*refresh_interval))?;
+        }
+        if let Some(color) = &self.color {
+            ContentLine::new(out, "COLOR").text(color)?;
+        }
+        for image in &self.images {
+            image.write_to(out)?;
+        }
+        for x_property in &self.x_properties {
+            x_property.write_to(out)?;
+        }
+        if let Some(tz) = &self.time_zone {
+            let from = self.events.iter().map(|e| e.start).min().unwrap_or_else(Utc::now);
+            let to = self.events.iter().map(|e| e.end.unwrap_or(e.start)).max().unwrap_or_else(Utc::now);
+            write_time_zone(out, tz, from, to)?;
+        }
+        Ok(())
+    }
+
+    fn write_event<W: std::fmt::Write>(&self, event: &Event, out: &mut W) -> std::fmt::Result {
+        event.write_to(out, self.time_zone.as_ref(), self.language.as_deref())
+    }
+
+    // Free/busy components go out with the footer, as there are only ever a few of them
+    fn write_footer<W: std::fmt::Write>(&self, out: &mut W) -> std::fmt::Result {
+        for free_busy in &self.free_busy {
+            free_busy.write_to(out)?;
+        }
+        out.write_str("END:VCALENDAR\r\n")
+    }
+
+    /// Serializes the calendar straight into `out`, without building it up in memory first
+    pub fn write_to<W: std::fmt::Write>(&self, out: &mut W) -> std::fmt::Result {
+        self.write_header(out)?;
         for event in &self.events {
-            out.extend(event.to_content_lines());
+            self.write_event(event, out)?;
         }
-        out.push(ContentLine {
-            name: std::borrow::Cow::Borrowed("END"),
-            params: std::borrow::Cow::Borrowed(&[]),
-            value: std::borrow::Cow::Borrowed("VCALENDAR")
-        });
-        out
+        self.write_footer(out)
+    }
+
+    /// Serializes the calendar into an I/O writer, which should be buffered
+    pub fn write_io<W: std::io::Write>(&self, out: W) -> std::io::Result<()> {
+        let mut adapter = IoAdapter { out, error: Ok(()) };
+        match self.write_to(&mut adapter) {
+            Ok(()) => Ok(()),
+            Err(_) => adapter.error.and(Err(std::io::Error::other("formatter error")))
+        }
+    }
+
+    /// Splits the serialized calendar into chunks of one event each, for streaming out a piece at a time
+    pub fn into_chunks(self) -> Chunks {
+        Chunks { calendar: self, next: 0 }
     }
 }
 
 impl std::fmt::Display for Calendar {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for line in self.to_content_lines() {
-            write!(f, "{}", line)?;
-        }
-        Ok(())
+        self.write_to(f)
+    }
+}
+
+/// Passes formatted text on to an I/O writer, keeping hold of the first I/O error
+struct IoAdapter<W: std::io::Write> {
+    out: W,
+    error: std::io::Result<()>,
+}
+
+impl<W: std::io::Write> std::fmt::Write for IoAdapter<W> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.out.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Err(e);
+            std::fmt::Error
+        })
+    }
+}
+
+/// Serialized calendar, as its header, then each event, then its footer
+pub struct Chunks {
+    calendar: Calendar,
+    next: usize,
+}
+
+impl Iterator for Chunks {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let mut out = String::new();
+        let result = match self.next {
+            0 => self.calendar.write_header(&mut out),
+            n if n <= self.calendar.events.len() => self.calendar.write_event(&self.calendar.events[n - 1], &mut out),
+            n if n == self.calendar.events.len() + 1 => self.calendar.write_footer(&mut out),
+            _ => return None
+        };
+        // Writing into a String can't fail
+        result.expect("a String accepts any write");
+        self.next += 1;
+        Some(out)
     }
 }
 
@@ -180,64 +628,158 @@ pub struct Organiser {
 }
 
 impl Organiser {
-    fn to_content_line<'a>(&'a self) -> ContentLine<'a> {
-        let mut params = vec![];
+    fn write_to<W: std::fmt::Write>(&self, out: &mut W) -> std::fmt::Result {
+        let mut line = ContentLine::new(out, "ORGANIZER");
         if let Some(common_name) = &self.common_name {
-            params.push(Parameter {
-                name: std::borrow::Cow::Borrowed("CN"),
-                values: std::borrow::Cow::Owned(vec![std::borrow::Cow::Borrowed(common_name.as_str())])
-            });
+            line = line.param("CN", common_name);
         }
         if let Some(sent_by) = &self.sent_by {
-            params.push(Parameter {
-                name: std::borrow::Cow::Borrowed("SENT-BY"),
-                values: std::borrow::Cow::Owned(vec![std::borrow::Cow::Borrowed(sent_by.as_str())])
-            });
+            line = line.param("SENT-BY", sent_by);
+        }
+        line.value(Value::Uri(&self.address))
+    }
+}
+
+pub struct Attendee {
+    pub address: String,
+    pub common_name: Option<String>,
+    pub role: Option<Role>,
+    pub participation_status: Option<ParticipationStatus>,
+    pub rsvp: Option<bool>
+}
+
+impl Attendee {
+    fn write_to<W: std::fmt::Write>(&self, out: &mut W) -> std::fmt::Result {
+        let mut line = ContentLine::new(out, "ATTENDEE");
+        if let Some(common_name) = &self.common_name {
+            line = line.param("CN", common_name);
+        }
+        if let Some(role) = &self.role {
+            line = line.param("ROLE", role.as_str());
+        }
+        if let Some(participation_status) = &self.participation_status {
+            line = line.param("PARTSTAT", participation_status.as_str());
+        }
+        if let Some(rsvp) = &self.rsvp {
+            line = line.param("RSVP", if *rsvp { "TRUE" } else { "FALSE" });
+        }
+        line.value(Value::Uri(&self.address))
+    }
+}
+
+pub struct Conference {
+    pub uri: String,
+    pub features: Vec<Feature>,
+    pub label: Option<String>
+}
+
+impl Conference {
+    fn write_to<W: std::fmt::Write>(&self, out: &mut W) -> std::fmt::Result {
+        let mut line = ContentLine::new(out, "CONFERENCE").param("VALUE", "URI");
+        if !self.features.is_empty() {
+            line = line.param_values("FEATURE", self.features.iter().map(Feature::as_str));
         }
-        ContentLine {
-            name: std::borrow::Cow::Borrowed("ORGANIZER"),
-            params: std::borrow::Cow::Owned(params),
-            value: std::borrow::Cow::Borrowed(&self.address)
+        if let Some(label) = &self.label {
+            line = line.param("LABEL", label);
         }
+        line.value(Value::Uri(&self.uri))
     }
 }
 
 #[allow(dead_code)]
-pub enum Image {
+pub enum ImageData {
     Url(String),
     Binary(Vec<u8>)
 }
 
+pub struct Image {
+    pub data: ImageData,
+    pub display: Option<ImageDisplay>
+}
+
 impl Image {
-    fn to_content_line<'a>(&'a self) -> ContentLine<'a> {
-        match self {
-            Self::Url(url) => {
-                ContentLine {
-                    name: std::borrow::Cow::Borrowed("IMAGE"),
-                    params: std::borrow::Cow::Owned(vec![Parameter {
-                        name: std::borrow::Cow::Borrowed("VALUE"),
-                        values: std::borrow::Cow::Owned(vec![std::borrow::Cow::Borrowed("URI")])
-                    }]),
-                    value: std::borrow::Cow::Borrowed(url)
-                }
-            }
-            Self::Binary(data) => {
-                ContentLine {
-                    name: std::borrow::Cow::Borrowed("IMAGE"),
-                    params: std::borrow::Cow::Owned(vec![Parameter {
-                        name: std::borrow::Cow::Borrowed("VALUE"),
-                        values: std::borrow::Cow::Owned(vec![std::borrow::Cow::Borrowed("BINARY")])
-                    }, Parameter {
-                        name: std::borrow::Cow::Borrowed("ENCODING"),
-                        values: std::borrow::Cow::Owned(vec![std::borrow::Cow::Borrowed("BASE64")])
-                    }]),
-                    value: std::borrow::Cow::Owned(base64::encode(data))
-                }
-            }
+    fn write_to<W: std::fmt::Write>(&self, out: &mut W) -> std::fmt::Result {
+        let line = ContentLine::new(out, "IMAGE");
+        let (line, value) = match &self.data {
+            ImageData::Url(url) => (line.param("VALUE", "URI"), Value::Uri(url)),
+            ImageData::Binary(data) => (line.param("VALUE", "BINARY").param("ENCODING", "BASE64"), Value::Binary(data))
+        };
+        match &self.display {
+            Some(display) => line.param("DISPLAY", display.as_str()).value(value),
+            None => line.value(value)
         }
     }
 }
 
+/// A VALARM showing a reminder some time before an event starts
+pub struct Alarm {
+    pub before: std::time::Duration,
+    pub description: String,
+}
+
+impl Alarm {
+    fn write_to<W: std::fmt::Write>(&self, out: &mut W, language: Option<&str>) -> std::fmt::Result {
+        ContentLine::new(out, "BEGIN").value(Value::Token("VALARM"))?;
+        ContentLine::new(out, "ACTION").value(Value::Token("DISPLAY"))?;
+        ContentLine::new(out, "TRIGGER").value(Value::DurationBefore(self.before))?;
+        ContentLine::new(out, "DESCRIPTION").language(language).text(&self.description)?;
+        ContentLine::new(out, "END").value(Value::Token("VALARM"))
+    }
+}
+
+/// A VFREEBUSY, giving the times something is busy between `start` and `end`
+pub struct FreeBusy {
+    pub uid: String,
+    pub timestamp: DateTime<Utc>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub comment: Option<String>,
+    pub url: Option<String>,
+    pub periods: Vec<(FreeBusyType, DateTime<Utc>, DateTime<Utc>)>,
+}
+
+impl FreeBusy {
+    pub fn new(uid: impl Into<String>, timestamp: DateTime<Utc>, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        FreeBusy { uid: uid.into(), timestamp, start, end, comment: None, url: None, periods: vec![] }
+    }
+
+    pub fn comment(mut self, comment: impl Into<Option<String>>) -> Self {
+        self.comment = comment.into();
+        self
+    }
+
+    pub fn url(mut self, url: impl Into<Option<String>>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    pub fn period(mut self, kind: FreeBusyType, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        self.periods.push((kind, start, end));
+        self
+    }
+
+    fn write_to<W: std::fmt::Write>(&self, out: &mut W) -> std::fmt::Result {
+        ContentLine::new(out, "BEGIN").value(Value::Token("VFREEBUSY"))?;
+        ContentLine::new(out, "UID").text(&self.uid)?;
+        ContentLine::new(out, "DTSTAMP").value(Value::DateTime(self.timestamp))?;
+        ContentLine::new(out, "DTSTART").value(Value::DateTime(self.start))?;
+        ContentLine::new(out, "DTEND").value(Value::DateTime(self.end))?;
+        if let Some(comment) = &self.comment {
+            ContentLine::new(out, "COMMENT").text(comment)?;
+        }
+        if let Some(url) = &self.url {
+            ContentLine::new(out, "URL").value(Value::Uri(url))?;
+        }
+        // Periods are meant to be in order of when they start
+        let mut periods = self.periods.iter().collect::<Vec<_>>();
+        periods.sort_by_key(|(_, start, end)| (*start, *end));
+        for (kind, start, end) in periods {
+            ContentLine::new(out, "FREEBUSY").param("FBTYPE", kind.as_str()).value(Value::Period(*start, *end))?;
+        }
+        ContentLine::new(out, "END").value(Value::Token("VFREEBUSY"))
+    }
+}
+
 pub struct Event {
     pub uid: String,
     pub timestamp: DateTime<Utc>,
@@ -245,89 +787,162 @@ pub struct Event {
     pub end: Option<DateTime<Utc>>,
     pub created: Option<DateTime<Utc>>,
     pub description: Option<String>,
+    pub html_description: Option<String>,
     pub summary: Option<String>,
     pub location: Option<String>,
+    pub url: Option<String>,
+    pub conferences: Vec<Conference>,
     pub organiser: Option<Organiser>,
-    pub status: Option<String>,
-    pub images: Vec<Image>
+    pub attendees: Vec<Attendee>,
+    pub status: Option<EventStatus>,
+    pub images: Vec<Image>,
+    pub alarms: Vec<Alarm>,
+    /// Nothing outside this module sets these, so they have no builders
+    class: Option<Class>,
+    transparency: Option<Transparency>,
+    x_properties: Vec<XProperty>,
 }
 
 impl Event {
-    fn to_content_lines<'a>(&'a self) -> Vec<ContentLine<'a>> {
-        let mut out = vec![];
-        out.push(ContentLine {
-            name: std::borrow::Cow::Borrowed("BEGIN"),
-            params: std::borrow::Cow::Borrowed(&[]),
-            value: std::borrow::Cow::Borrowed("VEVENT")
-        });
-        out.push(ContentLine {
-            name: std::borrow::Cow::Borrowed("UID"),
-            params: std::borrow::Cow::Borrowed(&[]),
-            value: std::borrow::Cow::Borrowed(&self.uid)
-        });
-        out.push(ContentLine {
-            name: std::borrow::Cow::Borrowed("DTSTAMP"),
-            params: std::borrow::Cow::Borrowed(&[]),
-            value: std::borrow::Cow::Owned(format_datetime(&self.timestamp))
-        });
-        out.push(ContentLine {
-            name: std::borrow::Cow::Borrowed("DTSTART"),
-            params: std::borrow::Cow::Borrowed(&[]),
-            value: std::borrow::Cow::Owned(format_datetime(&self.start))
-        });
+    /// An event with just the properties every event needs
+    pub fn new(uid: impl Into<String>, timestamp: DateTime<Utc>, start: DateTime<Utc>) -> Self {
+        Event {
+            uid: uid.into(),
+            timestamp,
+            start,
+            end: None,
+            created: None,
+            description: None,
+            html_description: None,
+            summary: None,
+            location: None,
+            url: None,
+            conferences: vec![],
+            organiser: None,
+            attendees: vec![],
+            status: None,
+            class: None,
+            transparency: None,
+            images: vec![],
+            x_properties: vec![],
+            alarms: vec![],
+        }
+    }
+
+    pub fn end(mut self, end: impl Into<Option<DateTime<Utc>>>) -> Self {
+        self.end = end.into();
+        self
+    }
+
+    pub fn created(mut self, created: impl Into<Option<DateTime<Utc>>>) -> Self {
+        self.created = created.into();
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<Option<String>>) -> Self {
+        self.description = description.into();
+        self
+    }
+
+    pub fn html_description(mut self, html_description: impl Into<Option<String>>) -> Self {
+        self.html_description = html_description.into();
+        self
+    }
+
+    pub fn summary(mut self, summary: impl Into<Option<String>>) -> Self {
+        self.summary = summary.into();
+        self
+    }
+
+    pub fn location(mut self, location: impl Into<Option<String>>) -> Self {
+        self.location = location.into();
+        self
+    }
+
+    pub fn url(mut self, url: impl Into<Option<String>>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    pub fn conference(mut self, conference: Conference) -> Self {
+        self.conferences.push(conference);
+        self
+    }
+
+    pub fn organiser(mut self, organiser: impl Into<Option<Organiser>>) -> Self {
+        self.organiser = organiser.into();
+        self
+    }
+
+    pub fn attendees(mut self, attendees: impl IntoIterator<Item = Attendee>) -> Self {
+        self.attendees.extend(attendees);
+        self
+    }
+
+    pub fn status(mut self, status: impl Into<Option<EventStatus>>) -> Self {
+        self.status = status.into();
+        self
+    }
+
+    pub fn image(mut self, image: Image) -> Self {
+        self.images.push(image);
+        self
+    }
+
+    fn write_to<W: std::fmt::Write>(&self, out: &mut W, time_zone: Option<&chrono_tz::Tz>, language: Option<&str>)
+        -> std::fmt::Result {
+        ContentLine::new(out, "BEGIN").value(Value::Token("VEVENT"))?;
+        ContentLine::new(out, "UID").text(&self.uid)?;
+        ContentLine::new(out, "DTSTAMP").value(Value::DateTime(self.timestamp))?;
+        write_date_time(out, "DTSTART", &self.start, time_zone)?;
         if let Some(end) = &self.end {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("DTEND"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Owned(format_datetime(end))
-            });
+            write_date_time(out, "DTEND", end, time_zone)?;
         }
         if let Some(created) = &self.created {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("CREATED"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Owned(format_datetime(created))
-            });
+            ContentLine::new(out, "CREATED").value(Value::DateTime(*created))?;
         }
         if let Some(description) = &self.description {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("DESCRIPTION"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Borrowed(description)
-            });
+            ContentLine::new(out, "DESCRIPTION").language(language).text(description)?;
+        }
+        if let Some(html_description) = &self.html_description {
+            ContentLine::new(out, "X-ALT-DESC").param("FMTTYPE", "text/html").text(html_description)?;
         }
         if let Some(summary) = &self.summary {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("SUMMARY"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Borrowed(summary)
-            });
+            ContentLine::new(out, "SUMMARY").language(language).text(summary)?;
         }
         if let Some(location) = &self.location {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("LOCATION"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Borrowed(location)
-            });
+            ContentLine::new(out, "LOCATION").language(language).text(location)?;
+        }
+        if let Some(url) = &self.url {
+            ContentLine::new(out, "URL").value(Value::Uri(url))?;
+        }
+        for conference in &self.conferences {
+            conference.write_to(out)?;
         }
         if let Some(organiser) = &self.organiser {
-            out.push(organiser.to_content_line());
+            organiser.write_to(out)?;
+        }
+        for attendee in &self.attendees {
+            attendee.write_to(out)?;
         }
         if let Some(status) = &self.status {
-            out.push(ContentLine {
-                name: std::borrow::Cow::Borrowed("STATUS"),
-                params: std::borrow::Cow::Borrowed(&[]),
-                value: std::borrow::Cow::Borrowed(status)
-            });
+            ContentLine::new(out, "STATUS").value(Value::Token(status.as_str()))?;
+        }
+        if let Some(class) = &self.class {
+            ContentLine::new(out, "CLASS").value(Value::Token(class.as_str()))?;
+        }
+        if let Some(transparency) = &self.transparency {
+            ContentLine::new(out, "TRANSP").value(Value::Token(transparency.as_str()))?;
         }
         for image in &self.images {
-            out.push(image.to_content_line());
+            image.write_to(out)?;
         }
-        out.push(ContentLine {
-            name: std::borrow::Cow::Borrowed("END"),
-            params: std::borrow::Cow::Borrowed(&[]),
-            value: std::borrow::Cow::Borrowed("VEVENT")
-        });
-        out
+        for x_property in &self.x_properties {
+            x_property.write_to(out)?;
+        }
+        for alarm in &self.alarms {
+            alarm.write_to(out, language)?;
+        }
+        ContentLine::new(out, "END").value(Value::Token("VEVENT"))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,88 @@
+//! Serialization benchmarks over large calendars, run with `cargo test --release -- --ignored --nocapture`
+
+use crate::ical::*;
+
+const EVENTS: usize = 1000;
+const ROUNDS: u32 = 20;
+
+fn thousand_event_calendar(time_zone: Option<chrono_tz::Tz>) -> Calendar {
+    let events = (0..EVENTS).map(|i| {
+        let start = Utc.ymd(2022, 1, 1).and_hms(18, 0, 0) + chrono::Duration::hours(i as i64 * 7);
+        Event::new(format!("{}@e.discord-events.magicalcodewit.ch", i), Utc.ymd(2022, 1, 1).and_hms(0, 0, 0), start)
+            .end(start + chrono::Duration::hours(2))
+            .created(Utc.ymd(2021, 12, 1).and_hms(0, 0, 0))
+            .description(format!("Event number {}, with; some separators\nand a second line. ", i).repeat(5))
+            .html_description(format!("<!DOCTYPE html><html><body><p>Event number {}</p></body></html>", i))
+            .summary(format!("Game night #{}", i))
+            .location("#game-night".to_string())
+            .url(format!("https://discord.com/events/1/{}", i))
+            .conference(Conference {
+                uri: "https://discord.com/channels/1/2".to_string(),
+                features: vec![Feature::Audio, Feature::Video],
+                label: Some("Join #game-night on Discord".to_string()),
+            })
+            .organiser(Organiser {
+                address: "https://discord.com/channels/1".to_string(),
+                common_name: Some("Someone".to_string()),
+                sent_by: Some("https://discord.com/users/3".to_string()),
+            })
+            .attendees((0..10).map(|a| Attendee {
+                address: format!("https://discord.com/users/{}", a),
+                common_name: Some(format!("Attendee {}", a)),
+                role: Some(Role::OptionalParticipant),
+                participation_status: Some(ParticipationStatus::Tentative),
+                rsvp: Some(false),
+            }))
+            .status(EventStatus::Confirmed)
+    });
+    Calendar::new("Discord Events Export benchmark")
+        .name("Benchmark".to_string())
+        .time_zone(time_zone)
+        .events(events)
+}
+
+fn bench(name: &str, mut f: impl FnMut() -> usize) {
+    let started = std::time::Instant::now();
+    let mut bytes = 0;
+    for _ in 0..ROUNDS {
+        bytes += f();
+    }
+    let elapsed = started.elapsed();
+    println!(
+        "{}: {:?} per calendar, {:.1} MB/s", name, elapsed / ROUNDS,
+        bytes as f64 / elapsed.as_secs_f64() / 1_000_000.0
+    );
+}
+
+#[test]
+fn chunks_match_whole_calendar() {
+    let calendar = thousand_event_calendar(Some(chrono_tz::Europe::London));
+    let whole = calendar.to_string();
+    assert_eq!(calendar.into_chunks().collect::<String>(), whole);
+}
+
+#[test]
+#[ignore]
+fn serialize_thousand_events() {
+    for (name, time_zone) in [("UTC", None), ("Europe/London", Some(chrono_tz::Europe::London))] {
+        let calendar = thousand_event_calendar(time_zone);
+
+        bench(&format!("to_string, {}", name), || calendar.to_string().len());
+
+        let mut buffer = String::new();
+        bench(&format!("write_to a reused String, {}", name), || {
+            buffer.clear();
+            calendar.write_to(&mut buffer).unwrap();
+            buffer.len()
+        });
+
+        let mut bytes = vec![];
+        bench(&format!("write_io to a reused Vec, {}", name), || {
+            bytes.clear();
+            calendar.write_io(&mut bytes).unwrap();
+            bytes.len()
+        });
+    }
+
+    bench("into_chunks", || thousand_event_calendar(None).into_chunks().map(|c| c.len()).sum());
+}
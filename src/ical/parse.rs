@@ -0,0 +1,243 @@
+//! Reads calendars from other sources, leniently, as they won't all follow RFC 5545 to the letter
+
+use chrono::prelude::*;
+
+pub struct Property {
+    pub line: usize,
+    pub name: String,
+    pub params: Vec<(String, Vec<String>)>,
+    pub value: String,
+}
+
+impl Property {
+    pub fn param(&self, name: &str) -> Option<&[String]> {
+        self.params.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_slice())
+    }
+
+    pub fn value_type(&self) -> Option<&str> {
+        self.param("VALUE").and_then(|v| v.first()).map(String::as_str)
+    }
+
+    /// The value as TEXT, with escapes undone
+    pub fn text(&self) -> String {
+        let mut out = String::with_capacity(self.value.len());
+        let mut chars = self.value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('n' | 'N') => out.push('\n'),
+                    Some(c) => out.push(c),
+                    None => {}
+                },
+                c => out.push(c)
+            }
+        }
+        out
+    }
+
+    /// The value as a DATE-TIME or DATE, in its TZID if it has one. Floating times, and times in zones that aren't
+    /// IANA names, are taken to be UTC, and dates to start at midnight UTC.
+    pub fn date_time(&self) -> Option<DateTime<Utc>> {
+        if self.value_type() == Some("DATE") || self.value.len() == 8 {
+            return NaiveDate::parse_from_str(&self.value, "%Y%m%d").ok().map(|d| Utc.from_utc_datetime(&d.and_hms(0, 0, 0)));
+        }
+        if let Some(utc) = self.value.strip_suffix('Z') {
+            return NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok().map(|t| Utc.from_utc_datetime(&t));
+        }
+        let local = NaiveDateTime::parse_from_str(&self.value, "%Y%m%dT%H%M%S").ok()?;
+        match self.param("TZID").and_then(|t| t.first()).and_then(|t| t.parse::<chrono_tz::Tz>().ok()) {
+            Some(tz) => tz.from_local_datetime(&local).earliest().map(|t| t.with_timezone(&Utc)),
+            None => Some(Utc.from_utc_datetime(&local))
+        }
+    }
+}
+
+/// A component and everything in it
+pub struct Component {
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub components: Vec<Component>,
+}
+
+impl Component {
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|p| p.name == name)
+    }
+
+    pub fn components<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Component> + 'a {
+        self.components.iter().filter(move |c| c.name == name)
+    }
+}
+
+fn parse_params(s: &str) -> Option<Vec<(String, Vec<String>)>> {
+    let mut params = vec![];
+    let mut rest = s;
+    while let Some(r) = rest.strip_prefix(';') {
+        let (name, r) = r.split_once('=')?;
+        let mut values = vec![];
+        let mut r = r;
+        loop {
+            let (value, after) = match r.strip_prefix('"') {
+                Some(q) => {
+                    let end = q.find('"')?;
+                    (&q[..end], &q[end + 1..])
+                },
+                None => {
+                    let end = r.find([';', ',']).unwrap_or(r.len());
+                    if r[..end].contains('"') {
+                        return None;
+                    }
+                    (&r[..end], &r[end..])
+                }
+            };
+            values.push(value.to_string());
+            match after.strip_prefix(',') {
+                Some(a) => r = a,
+                None => {
+                    r = after;
+                    break;
+                }
+            }
+        }
+        params.push((name.to_ascii_uppercase(), values));
+        rest = r;
+    }
+    if rest.is_empty() {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+pub fn parse_property(line: usize, s: &str) -> Option<Property> {
+    let name_end = s.find([';', ':'])?;
+    let name = &s[..name_end];
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return None;
+    }
+    // The value starts at the first colon that isn't inside a quoted parameter value
+    let mut in_quotes = false;
+    let value_start = name_end + s[name_end..].char_indices().find(|(_, c)| {
+        if *c == '"' {
+            in_quotes = !in_quotes;
+        }
+        *c == ':' && !in_quotes
+    })?.0;
+    Some(Property {
+        line,
+        name: name.to_ascii_uppercase(),
+        params: parse_params(&s[name_end..value_start])?,
+        value: s[value_start + 1..].to_string(),
+    })
+}
+
+/// Parses a DURATION value
+pub fn duration(s: &str) -> Option<chrono::Duration> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s.strip_prefix('+').unwrap_or(s))
+    };
+    let s = s.strip_prefix('P')?;
+    // Bounded to 32 bits, so no combination of them overflows a Duration
+    let number = |n: &str| -> Option<i64> {
+        if n.is_empty() || !n.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        n.parse::<u32>().ok().map(i64::from)
+    };
+
+    let total = match s.strip_suffix('W') {
+        Some(weeks) => chrono::Duration::weeks(number(weeks)?),
+        None => {
+            let (date, time) = match s.split_once('T') {
+                Some((d, t)) => (d, Some(t)),
+                None => (s, None)
+            };
+            let mut total = match date {
+                "" => chrono::Duration::zero(),
+                d => chrono::Duration::days(number(d.strip_suffix('D')?)?)
+            };
+            match time {
+                None if date.is_empty() => return None,
+                None => {},
+                Some(t) => {
+                    // Each of H, M and S may appear once, in that order, each with a number
+                    let mut rest = t;
+                    let mut any = false;
+                    for (unit, seconds) in [('H', 3600), ('M', 60), ('S', 1)] {
+                        if let Some(i) = rest.find(unit) {
+                            total = total + chrono::Duration::seconds(number(&rest[..i])? * seconds);
+                            rest = &rest[i + 1..];
+                            any = true;
+                        }
+                    }
+                    if !any || !rest.is_empty() {
+                        return None;
+                    }
+                }
+            }
+            total
+        }
+    };
+    Some(if negative { -total } else { total })
+}
+
+/// Parses a calendar, accepting bare LF line endings, and skipping lines it can't make sense of
+pub fn parse(ics: &str) -> Result<Component, String> {
+    let mut lines: Vec<(usize, String)> = vec![];
+    for (i, line) in ics.split('\n').enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some((_, l))) => l.push_str(continuation),
+            _ if line.is_empty() => {},
+            _ => lines.push((i + 1, line.to_string()))
+        }
+    }
+
+    let mut stack: Vec<Component> = vec![];
+    for (number, line) in lines {
+        let property = match parse_property(number, &line) {
+            Some(p) => p,
+            None => continue
+        };
+        match property.name.as_str() {
+            "BEGIN" => stack.push(Component { name: property.value.to_ascii_uppercase(), properties: vec![], components: vec![] }),
+            "END" => {
+                let component = stack.pop().ok_or_else(|| format!("line {}: END without a BEGIN", number))?;
+                if !component.name.eq_ignore_ascii_case(&property.value) {
+                    return Err(format!("line {}: END:{} doesn't match BEGIN:{}", number, property.value, component.name));
+                }
+                match stack.last_mut() {
+                    Some(parent) => parent.components.push(component),
+                    None if component.name == "VCALENDAR" => return Ok(component),
+                    None => return Err(format!("line {}: expected a VCALENDAR, not {}", number, component.name))
+                }
+            },
+            _ => if let Some(component) = stack.last_mut() {
+                component.properties.push(property);
+            }
+        }
+    }
+    Err("no complete VCALENDAR found".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_other_calendars() {
+        let calendar = parse("BEGIN:VCALENDAR\nVERSION:2.0\nBEGIN:VEVENT\nUID:a@example.com\n\
+            SUMMARY:Meet\\, greet\\nand eat\nDTSTART;TZID=Europe/London:20300701T180000\n\
+            DESCRIPTION:Long\n  text\nDURATION:PT1H30M\nEND:VEVENT\nEND:VCALENDAR\n".replace("            ", "").as_str()).unwrap();
+        let event = calendar.components("VEVENT").next().unwrap();
+        assert_eq!(event.property("SUMMARY").unwrap().text(), "Meet, greet\nand eat");
+        assert_eq!(event.property("DESCRIPTION").unwrap().text(), "Long text");
+        assert_eq!(event.property("DTSTART").unwrap().date_time(), Some(Utc.ymd(2030, 7, 1).and_hms(17, 0, 0)));
+        assert_eq!(duration(&event.property("DURATION").unwrap().value), Some(chrono::Duration::minutes(90)));
+        assert_eq!(duration("-P1W"), Some(chrono::Duration::weeks(-1)));
+        assert_eq!(duration("PT1M1H"), None);
+
+        assert!(parse("BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nEND:VCALENDAR\r\n").is_err());
+    }
+}
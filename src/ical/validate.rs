@@ -0,0 +1,448 @@
+//! Checks serialized calendars against the parts of RFC 5545 (and RFC 7986) that we generate
+
+use super::parse::{parse_property, Property};
+
+pub struct Issue {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+struct Component {
+    line: usize,
+    name: String,
+    properties: Vec<Property>,
+    children: Vec<String>,
+}
+
+/// Properties a component must have exactly once, and those it may have at most once
+fn cardinality(component: &str) -> (&'static [&'static str], &'static [&'static str]) {
+    match component {
+        "VCALENDAR" => (&["PRODID", "VERSION"], &[
+            "CALSCALE", "METHOD", "UID", "LAST-MODIFIED", "URL", "REFRESH-INTERVAL", "SOURCE", "COLOR"
+        ]),
+        "VEVENT" => (&["UID", "DTSTAMP", "DTSTART"], &[
+            "CLASS", "CREATED", "DESCRIPTION", "GEO", "LAST-MODIFIED", "LOCATION", "ORGANIZER", "PRIORITY",
+            "SEQUENCE", "STATUS", "SUMMARY", "TRANSP", "URL", "RECURRENCE-ID", "DTEND", "DURATION", "COLOR"
+        ]),
+        "VALARM" => (&["ACTION", "TRIGGER"], &["DESCRIPTION", "DURATION", "REPEAT"]),
+        "VFREEBUSY" => (&["UID", "DTSTAMP"], &["CONTACT", "DTSTART", "DTEND", "ORGANIZER", "URL"]),
+        "VTIMEZONE" => (&["TZID"], &["LAST-MODIFIED", "TZURL"]),
+        "STANDARD" | "DAYLIGHT" => (&["DTSTART", "TZOFFSETTO", "TZOFFSETFROM"], &[]),
+        _ => (&[], &[])
+    }
+}
+
+fn is_date(s: &str) -> bool {
+    s.len() == 8 && s.chars().all(|c| c.is_ascii_digit()) &&
+        chrono::NaiveDate::parse_from_str(s, "%Y%m%d").is_ok()
+}
+
+fn is_date_time(s: &str, utc: bool) -> bool {
+    let local = match s.strip_suffix('Z') {
+        Some(l) => l,
+        None if utc => return false,
+        None => s
+    };
+    local.len() == 15 && chrono::NaiveDateTime::parse_from_str(local, "%Y%m%dT%H%M%S").is_ok()
+}
+
+fn is_uri(s: &str) -> bool {
+    let (scheme, rest) = match s.split_once(':') {
+        Some(p) => p,
+        None => return false
+    };
+    let mut scheme_chars = scheme.chars();
+    scheme_chars.next().is_some_and(|c| c.is_ascii_alphabetic()) &&
+        scheme_chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.') &&
+        !rest.is_empty() && !rest.chars().any(|c| c.is_whitespace() || c.is_control() || c == '"')
+}
+
+fn is_utc_offset(s: &str) -> bool {
+    let digits = match s.strip_prefix(['+', '-']) {
+        Some(d) => d,
+        None => return false
+    };
+    (digits.len() == 4 || digits.len() == 6) && digits.chars().all(|c| c.is_ascii_digit()) &&
+        digits[..2] < *"24" && digits[2..4] < *"60" && digits.get(4..).is_none_or(|s| s.is_empty() || s < "60")
+}
+
+/// Checks a FREEBUSY value is a list of periods, each a UTC start followed by a UTC end or a duration
+fn is_period_list(s: &str) -> bool {
+    s.split(',').all(|period| match period.split_once('/') {
+        Some((start, end)) => is_date_time(start, true)
+            && (is_date_time(end, true) || super::parse::duration(end).is_some()),
+        None => false
+    })
+}
+
+/// Checks that a TEXT value has only valid escapes, and no unescaped separators
+fn text_escaping_issue(s: &str) -> Option<&'static str> {
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\\' | ';' | ',' | 'n' | 'N') => {},
+                _ => return Some("invalid escape sequence in text")
+            },
+            ';' | ',' => return Some("unescaped separator in text"),
+            c if c.is_control() && c != '\t' => return Some("control character in text"),
+            _ => {}
+        }
+    }
+    None
+}
+
+const TEXT_PROPERTIES: &[&str] = &[
+    "SUMMARY", "DESCRIPTION", "LOCATION", "NAME", "X-WR-CALNAME", "X-WR-CALDESC", "TZNAME", "COMMENT", "X-ALT-DESC"
+];
+
+fn check_value(component: &str, property: &Property, time_zones: &mut Vec<(usize, String)>, issues: &mut Vec<Issue>) {
+    let mut issue = |message: String| issues.push(Issue { line: property.line, message });
+    let value = property.value.as_str();
+
+    match property.name.as_str() {
+        "DTSTAMP" | "CREATED" | "LAST-MODIFIED" if !is_date_time(value, true) => {
+            issue(format!("{} must be a UTC DATE-TIME, not {:?}", property.name, value));
+        },
+        "DTSTART" | "DTEND" if component == "VFREEBUSY" && !is_date_time(value, true) => {
+            issue(format!("{} of a VFREEBUSY must be a UTC DATE-TIME, not {:?}", property.name, value));
+        },
+        "FREEBUSY" => {
+            if !is_period_list(value) {
+                issue(format!("FREEBUSY must be a list of UTC periods, not {:?}", value));
+            }
+            let kind = property.param("FBTYPE").and_then(|t| t.first()).map_or("BUSY", String::as_str);
+            if !["FREE", "BUSY", "BUSY-UNAVAILABLE", "BUSY-TENTATIVE"].contains(&kind) && !kind.starts_with("X-") {
+                issue(format!("{:?} isn't a valid FBTYPE", kind));
+            }
+        },
+        "DTSTART" | "DTEND" | "RECURRENCE-ID" => {
+            let valid = match property.value_type() {
+                Some("DATE") => is_date(value),
+                Some("DATE-TIME") | None => is_date_time(value, false),
+                Some(_) => false
+            };
+            if !valid {
+                issue(format!("{} must be a DATE-TIME or DATE, not {:?}", property.name, value));
+            }
+            match property.param("TZID").and_then(|t| t.first()) {
+                Some(_) if value.ends_with('Z') => issue(format!("{} has a TZID but is in UTC", property.name)),
+                Some(_) if component == "STANDARD" || component == "DAYLIGHT" => {
+                    issue(format!("{} in a time zone observance can't have a TZID", property.name))
+                },
+                Some(tzid) => time_zones.push((property.line, tzid.clone())),
+                None => {}
+            }
+        },
+        "URL" | "SOURCE" | "TZURL" | "CONFERENCE" if !is_uri(value) => {
+            issue(format!("{} must be a URI, not {:?}", property.name, value));
+        },
+        "IMAGE" => match property.value_type() {
+            Some("URI") if !is_uri(value) => {
+                issue(format!("IMAGE must be a URI, not {:?}", value));
+            },
+            Some("BINARY") if property.param("ENCODING").and_then(|e| e.first()).map(String::as_str) != Some("BASE64") => {
+                issue("binary IMAGE must be BASE64 encoded".to_string());
+            },
+            Some("URI" | "BINARY") => {},
+            _ => issue("IMAGE must have a VALUE of URI or BINARY".to_string())
+        },
+        "ORGANIZER" | "ATTENDEE" => {
+            if !is_uri(value) {
+                issue(format!("{} must be a CAL-ADDRESS, not {:?}", property.name, value));
+            }
+            for param in ["SENT-BY", "DELEGATED-TO", "DELEGATED-FROM", "MEMBER"] {
+                for v in property.param(param).unwrap_or_default() {
+                    if !is_uri(v) {
+                        issue(format!("{} must be a CAL-ADDRESS, not {:?}", param, v));
+                    }
+                }
+            }
+        },
+        "REFRESH-INTERVAL" | "DURATION" | "X-PUBLISHED-TTL" if super::parse::duration(value).is_none() => {
+            issue(format!("{} must be a DURATION, not {:?}", property.name, value));
+        },
+        "TRIGGER" if property.value_type() != Some("DATE-TIME") && super::parse::duration(value).is_none() => {
+            issue(format!("TRIGGER must be a DURATION, not {:?}", value));
+        },
+        "TZOFFSETFROM" | "TZOFFSETTO" if !is_utc_offset(value) => {
+            issue(format!("{} must be a UTC-OFFSET, not {:?}", property.name, value));
+        },
+        "VERSION" if value != "2.0" => {
+            issue(format!("VERSION must be 2.0, not {:?}", value));
+        },
+        "STATUS" if component == "VEVENT" && !["TENTATIVE", "CONFIRMED", "CANCELLED"].contains(&value) => {
+            issue(format!("{:?} isn't a valid event STATUS", value));
+        },
+        _ => {}
+    }
+
+    if TEXT_PROPERTIES.contains(&property.name.as_str()) {
+        if let Some(message) = text_escaping_issue(value) {
+            issue(format!("{} has {}", property.name, message));
+        }
+    }
+}
+
+fn check_component(component: &Component, issues: &mut Vec<Issue>) {
+    let (required, single) = cardinality(&component.name);
+    for name in required.iter().chain(single) {
+        let count = component.properties.iter().filter(|p| p.name == *name).count();
+        if count == 0 && required.contains(name) {
+            issues.push(Issue { line: component.line, message: format!("{} is missing {}", component.name, name) });
+        } else if count > 1 {
+            issues.push(Issue { line: component.line, message: format!("{} has {} {} properties, but may only have one", component.name, count, name) });
+        }
+    }
+
+    let has = |name: &str| component.properties.iter().any(|p| p.name == name);
+    if component.name == "VEVENT" && has("DTEND") && has("DURATION") {
+        issues.push(Issue { line: component.line, message: "VEVENT can't have both DTEND and DURATION".to_string() });
+    }
+    if component.name == "VTIMEZONE" && !component.children.iter().any(|c| c == "STANDARD" || c == "DAYLIGHT") {
+        issues.push(Issue { line: component.line, message: "VTIMEZONE must have a STANDARD or DAYLIGHT observance".to_string() });
+    }
+}
+
+/// Checks a serialized calendar, returning every problem found
+pub fn validate(ics: &str) -> Vec<Issue> {
+    let mut issues = vec![];
+
+    // Check the physical lines, then unfold them into content lines
+    let mut content_lines: Vec<(usize, String)> = vec![];
+    if !ics.ends_with("\r\n") {
+        issues.push(Issue { line: ics.split("\r\n").count(), message: "calendar doesn't end with CRLF".to_string() });
+    }
+    for (i, line) in ics.strip_suffix("\r\n").unwrap_or(ics).split("\r\n").enumerate() {
+        let number = i + 1;
+        if line.contains('\n') || line.contains('\r') {
+            issues.push(Issue { line: number, message: "line isn't terminated by CRLF".to_string() });
+        }
+        if line.len() > 75 {
+            issues.push(Issue { line: number, message: format!("line is {} octets long, longer than 75", line.len()) });
+        }
+        match line.strip_prefix([' ', '\t']) {
+            Some(continuation) => match content_lines.last_mut() {
+                Some((_, l)) => l.push_str(continuation),
+                None => issues.push(Issue { line: number, message: "calendar starts with a continuation line".to_string() })
+            },
+            None => content_lines.push((number, line.to_string()))
+        }
+    }
+
+    let mut stack: Vec<Component> = vec![];
+    let mut defined_time_zones = vec![];
+    let mut used_time_zones = vec![];
+    let mut seen_calendar = false;
+
+    for (number, line) in content_lines {
+        let property = match parse_property(number, &line) {
+            Some(p) => p,
+            None => {
+                issues.push(Issue { line: number, message: format!("invalid content line {:?}", line) });
+                continue;
+            }
+        };
+
+        match property.name.as_str() {
+            "BEGIN" => {
+                if stack.is_empty() {
+                    if property.value != "VCALENDAR" || seen_calendar {
+                        issues.push(Issue { line: number, message: format!("unexpected top level component {}", property.value) });
+                    }
+                    seen_calendar = true;
+                }
+                if let Some(parent) = stack.last_mut() {
+                    parent.children.push(property.value.clone());
+                }
+                stack.push(Component { line: number, name: property.value, properties: vec![], children: vec![] });
+            },
+            "END" => match stack.pop() {
+                Some(component) if component.name == property.value => {
+                    if component.name == "VTIMEZONE" {
+                        if let Some(tzid) = component.properties.iter().find(|p| p.name == "TZID") {
+                            defined_time_zones.push(tzid.value.clone());
+                        }
+                    }
+                    check_component(&component, &mut issues);
+                },
+                Some(component) => issues.push(Issue {
+                    line: number, message: format!("END:{} doesn't match BEGIN:{} on line {}", property.value, component.name, component.line)
+                }),
+                None => issues.push(Issue { line: number, message: format!("END:{} without a BEGIN", property.value) })
+            },
+            _ => match stack.last_mut() {
+                Some(component) => {
+                    check_value(&component.name, &property, &mut used_time_zones, &mut issues);
+                    component.properties.push(property);
+                },
+                None => issues.push(Issue { line: number, message: format!("{} is outside of any component", property.name) })
+            }
+        }
+    }
+
+    for component in stack {
+        issues.push(Issue { line: component.line, message: format!("{} is never ended", component.name) });
+    }
+    if !seen_calendar {
+        issues.push(Issue { line: 1, message: "no VCALENDAR found".to_string() });
+    }
+    for (line, tzid) in used_time_zones {
+        if !defined_time_zones.contains(&tzid) {
+            issues.push(Issue { line, message: format!("TZID {} has no matching VTIMEZONE", tzid) });
+        }
+    }
+
+    issues.sort_by_key(|i| i.line);
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate;
+    use crate::ical::*;
+
+    fn event(uid: &str) -> Event {
+        Event::new(
+            format!("{}@e.discord-events.magicalcodewit.ch", uid), Utc.ymd(2022, 6, 1).and_hms(12, 0, 0),
+            Utc.ymd(2022, 7, 1).and_hms(18, 30, 0)
+        )
+            .summary("Game night".to_string())
+            .status(EventStatus::Confirmed)
+    }
+
+    fn calendar(events: Vec<Event>) -> Calendar {
+        Calendar::new("Discord Events Export test")
+            .name("Test Server Events".to_string())
+            .uid("1@c.discord-events.magicalcodewit.ch".to_string())
+            .url("https://discord-events.magicalcodewit.ch/guilds/1/calendar.ics".to_string())
+            .events(events)
+    }
+
+    fn fixtures() -> Vec<(&'static str, Calendar)> {
+        let mut voice = event("voice")
+            .end(Utc.ymd(2022, 7, 1).and_hms(21, 0, 0))
+            .created(Utc.ymd(2022, 5, 30).and_hms(9, 15, 0))
+            .location("#game-night".to_string())
+            .url("https://discord.com/events/1/2?with=a,comma;and=semicolon".to_string())
+            .conference(Conference {
+                uri: "https://discord.com/channels/1/3".to_string(),
+                features: vec![Feature::Audio, Feature::Video],
+                label: Some("Join #game-night on Discord".to_string()),
+            })
+            .organiser(Organiser {
+                address: "https://discord.com/channels/1".to_string(),
+                common_name: Some("someone#1234".to_string()),
+                sent_by: Some("https://discord.com/users/4".to_string()),
+            })
+            .image(Image {
+                data: ImageData::Url("https://cdn.discordapp.com/guild-events/2/abc.png".to_string()),
+                display: None,
+            });
+        voice.attendees.push(Attendee {
+            address: "https://discord.com/users/5".to_string(),
+            common_name: Some("Nick; with, \"separators\": and colons".to_string()),
+            role: Some(Role::OptionalParticipant),
+            participation_status: Some(ParticipationStatus::Tentative),
+            rsvp: Some(false),
+        });
+        voice.class = Some(Class::Public);
+        voice.transparency = Some(Transparency::Transparent);
+        voice.x_properties.push(XProperty {
+            name: "X-DISCORD-ENTITY-TYPE".to_string(), params: vec![], value: "VOICE".to_string()
+        });
+        voice.alarms.push(Alarm { before: std::time::Duration::from_secs(900), description: "Game night".to_string() });
+
+        let external = event("external")
+            .location("The Pub, 1 High Street; back room".to_string())
+            .status(EventStatus::Cancelled)
+            .description("Bring snacks, drinks; and a \\ backslash\nSecond line with ünïcödé and emoji 🎲🎲🎲 to fold across the line limit properly".repeat(3))
+            .html_description("<!DOCTYPE html><html><body><strong>Bring snacks</strong></body></html>".to_string())
+            .image(Image { data: ImageData::Binary(vec![0; 200]), display: Some(ImageDisplay::Thumbnail) });
+
+        let url = "https://discord-events.magicalcodewit.ch/guilds/1/calendar.ics".to_string();
+        let mut rich = calendar(vec![voice, external])
+            .method(Method::Publish)
+            .description("A server, with; separators".to_string())
+            .source(url)
+            .refresh_interval(std::time::Duration::from_secs(3600))
+            .color("slateblue".to_string())
+            .image(Image {
+                data: ImageData::Url("https://cdn.discordapp.com/icons/1/abc.png".to_string()),
+                display: Some(ImageDisplay::Badge),
+            });
+        rich.x_properties.push(XProperty { name: "X-WR-TIMEZONE".to_string(), params: vec![], value: "Europe/London".to_string() });
+
+        let time_zone = calendar(vec![event("tz")]).time_zone(chrono_tz::Europe::London);
+        let no_dst = calendar(vec![event("no-dst")]).time_zone(chrono_tz::Asia::Kolkata);
+        let mut german = event("language").location("#spieleabend".to_string());
+        german.alarms.push(Alarm { before: std::time::Duration::from_secs(900), description: "Spieleabend".to_string() });
+        let language = calendar(vec![german])
+            .description("Ein Server".to_string())
+            .language("de".to_string());
+
+        vec![
+            ("empty", calendar(vec![])),
+            ("minimal", calendar(vec![event("minimal")])),
+            ("rich", rich),
+            ("time zone", time_zone),
+            ("time zone without DST", no_dst),
+            ("language", language),
+            ("free/busy", calendar(vec![]).method(Method::Publish).free_busy(
+                FreeBusy::new("3@f.discord-events.magicalcodewit.ch", Utc.ymd(2022, 6, 1).and_hms(12, 0, 0),
+                    Utc.ymd(2022, 7, 1).and_hms(0, 0, 0), Utc.ymd(2022, 7, 8).and_hms(0, 0, 0))
+                    .comment("#stage, for talks".to_string())
+                    .period(FreeBusyType::Busy, Utc.ymd(2022, 7, 2).and_hms(18, 0, 0), Utc.ymd(2022, 7, 2).and_hms(20, 0, 0))
+                    .period(FreeBusyType::Busy, Utc.ymd(2022, 7, 1).and_hms(18, 0, 0), Utc.ymd(2022, 7, 1).and_hms(19, 0, 0))
+            )),
+        ]
+    }
+
+    #[test]
+    fn fixtures_are_valid() {
+        for (name, calendar) in fixtures() {
+            let issues = validate(&calendar.to_string());
+            assert!(issues.is_empty(), "{} fixture is invalid:\n{}", name, issues.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"));
+        }
+    }
+
+    #[test]
+    fn marks_language() {
+        let ics = calendar(vec![event("language")]).language("fr".to_string()).to_string();
+        assert!(ics.contains("\r\nX-WR-CALNAME;LANGUAGE=fr:Test Server Events\r\n"));
+        assert!(ics.contains("\r\nSUMMARY;LANGUAGE=fr:Game night\r\n"));
+        assert!(calendar(vec![event("language")]).to_string().contains("\r\nSUMMARY:Game night\r\n"));
+    }
+
+    #[test]
+    fn catches_broken_output() {
+        let cases = [
+            ("ORGANIZER:https//discord.com/channels/1\r\n", "CAL-ADDRESS"),
+            ("SUMMARY:unescaped, comma\r\n", "unescaped separator"),
+            ("SUMMARY:bad \\x escape\r\n", "invalid escape"),
+            (&format!("SUMMARY:{}\r\n", "x".repeat(80)), "longer than 75"),
+            ("DTSTART;TZID=Nowhere/Special:20220701T183000\r\n", "no matching VTIMEZONE"),
+            ("DTEND:2022-07-01\r\n", "DATE-TIME"),
+            ("STATUS:MAYBE\r\n", "STATUS"),
+            ("UID:again\r\n", "may only have one"),
+            ("FREEBUSY:20220701T183000/20220701T193000Z\r\n", "UTC periods"),
+        ];
+        for (line, expected) in cases {
+            let ics = format!(
+                "BEGIN:VCALENDAR\r\nPRODID:test\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:a\r\nDTSTAMP:20220601T120000Z\r\n\
+                DTSTART:20220701T183000Z\r\n{}END:VEVENT\r\nEND:VCALENDAR\r\n", line
+            );
+            let issues = validate(&ics);
+            assert!(issues.iter().any(|i| i.message.contains(expected)), "{:?} wasn't caught", line);
+        }
+
+        assert!(validate("BEGIN:VCALENDAR\r\nPRODID:test\r\nEND:VCALENDAR\r\n").iter().any(|i| i.message.contains("missing VERSION")));
+        assert!(validate("BEGIN:VCALENDAR\nPRODID:test\nVERSION:2.0\nEND:VCALENDAR\n").iter().any(|i| i.message.contains("CRLF")));
+    }
+}
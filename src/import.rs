@@ -0,0 +1,490 @@
+use crate::{discord, ical};
+use chrono::prelude::*;
+
+const NAME_LIMIT: usize = 100;
+const DESCRIPTION_LIMIT: usize = 1000;
+const LOCATION_LIMIT: usize = 100;
+
+/// An event from the source calendar, as it should appear on Discord
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceEvent {
+    uid: String,
+    name: String,
+    description: Option<String>,
+    location: String,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    image: Option<String>,
+}
+
+/// The Discord event made from a source event, and the image URL it was given
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Imported {
+    event_id: discord::Snowflake,
+    image: Option<String>,
+}
+
+/// Events imported into each guild, by source UID, so each is only ever created once
+#[derive(Default, Serialize, Deserialize)]
+pub struct ImportState(std::collections::HashMap<discord::Snowflake, std::collections::HashMap<String, Imported>>);
+
+impl ImportState {
+    pub fn load(path: &std::path::Path) -> Result<Self, String> {
+        match std::fs::read(path) {
+            Ok(d) => serde_json::from_slice(&d).map_err(|e| format!("Unable to parse {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ImportState::default()),
+            Err(e) => Err(format!("Unable to read {}: {}", path.display(), e))
+        }
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> Result<(), String> {
+        let data = serde_json::to_vec(self).map_err(|e| format!("Unable to serialize import state: {}", e))?;
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, data)
+            .and_then(|_| std::fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+    }
+
+    pub fn guild(&mut self, guild_id: discord::Snowflake) -> &mut std::collections::HashMap<String, Imported> {
+        self.0.entry(guild_id).or_default()
+    }
+}
+
+fn truncate(s: &str, limit: usize) -> String {
+    s.chars().take(limit).collect()
+}
+
+/// Reads the events to import from a calendar, leaving out cancelled events and changes to single recurrences.
+/// Recurring events are left out too, with a warning, as Discord has no way to repeat an external event.
+fn source_events(calendar: &ical::parse::Component) -> Vec<SourceEvent> {
+    let mut seen = std::collections::HashSet::new();
+    calendar.components("VEVENT")
+        .filter(|e| e.property("RECURRENCE-ID").is_none())
+        .filter(|e| {
+            let recurring = e.property("RRULE").is_some() || e.property("RDATE").is_some();
+            if recurring {
+                let name = e.property("SUMMARY").map(|s| s.text()).unwrap_or_default();
+                let uid = e.property("UID").map(|u| u.value.as_str()).unwrap_or_default();
+                println!("Skipping recurring event {:?} ({}), as recurring events can't be imported", name, uid);
+            }
+            !recurring
+        })
+        .filter(|e| e.property("STATUS").map(|s| s.value.as_str()) != Some("CANCELLED"))
+        .filter_map(|e| {
+            let uid = e.property("UID")?.value.clone();
+            let start = e.property("DTSTART")?.date_time()?;
+            // Discord needs an end time for external events
+            let end = e.property("DTEND").and_then(|p| p.date_time())
+                .or_else(|| e.property("DURATION").and_then(|d| ical::parse::duration(&d.value)).map(|d| start + d))
+                .filter(|end| *end > start)
+                .unwrap_or(start + chrono::Duration::hours(1));
+            let location = e.property("LOCATION").map(|l| l.text()).filter(|l| !l.trim().is_empty())
+                .or_else(|| e.property("URL").map(|u| u.value.clone()))
+                .unwrap_or_else(|| "See the event description".to_string());
+            let image = e.properties.iter()
+                .find(|p| (p.name == "IMAGE" || (p.name == "ATTACH" && p.param("FMTTYPE").and_then(|f| f.first())
+                    .is_some_and(|f| f.starts_with("image/")))) && p.value_type() != Some("BINARY"))
+                .map(|p| p.value.clone());
+            if !seen.insert(uid.clone()) {
+                return None;
+            }
+            Some(SourceEvent {
+                uid,
+                name: truncate(&e.property("SUMMARY").map(|s| s.text()).unwrap_or_else(|| "Untitled event".to_string()), NAME_LIMIT),
+                description: e.property("DESCRIPTION").map(|d| truncate(&d.text(), DESCRIPTION_LIMIT)).filter(|d| !d.is_empty()),
+                location: truncate(&location, LOCATION_LIMIT),
+                start,
+                end,
+                image,
+            })
+        })
+        .collect()
+}
+
+/// A field that differs between a Discord event and its source
+#[derive(Debug, PartialEq)]
+pub struct FieldDiff {
+    field: &'static str,
+    old: String,
+    new: String,
+}
+
+/// Something the import will do to Discord
+#[derive(Debug)]
+pub enum Action {
+    Create(SourceEvent),
+    Update { event_id: discord::Snowflake, source: SourceEvent, diffs: Vec<FieldDiff> },
+    Delete { uid: String, event_id: discord::Snowflake, name: String },
+    /// A source event removed after its Discord event was already deleted or completed
+    Forget { uid: String },
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Create(s) => write!(f, "+ {:?} at {} ({})", s.name, s.start.to_rfc3339(), s.uid),
+            Action::Update { source, diffs, .. } => {
+                write!(f, "~ {:?} ({})", source.name, source.uid)?;
+                for diff in diffs {
+                    write!(f, "\n    {}: {:?} -> {:?}", diff.field, diff.old, diff.new)?;
+                }
+                Ok(())
+            },
+            Action::Delete { uid, name, .. } => write!(f, "- {:?} ({})", name, uid),
+            Action::Forget { uid } => write!(f, "  {} is gone from Discord, forgetting it", uid)
+        }
+    }
+}
+
+fn diff_event(event: &discord::GuildEvent, imported: &Imported, source: &SourceEvent) -> Vec<FieldDiff> {
+    let location = event.entity_metadata.as_ref().and_then(|m| m.location.clone());
+    let fields = [
+        ("name", Some(event.name.clone()), Some(source.name.clone())),
+        ("description", event.description.clone().filter(|d| !d.is_empty()), source.description.clone()),
+        ("scheduled_start_time", Some(event.scheduled_start_time.to_rfc3339()), Some(source.start.to_rfc3339())),
+        ("scheduled_end_time", event.scheduled_end_time.map(|t| t.to_rfc3339()), Some(source.end.to_rfc3339())),
+        ("location", location, Some(source.location.clone())),
+        ("image", imported.image.clone(), source.image.clone()),
+    ];
+    fields.into_iter()
+        .filter(|(_, old, new)| old != new)
+        .map(|(field, old, new)| FieldDiff { field, old: old.unwrap_or_default(), new: new.unwrap_or_default() })
+        .collect()
+}
+
+/// Works out how to bring a guild's imported events in line with the source calendar
+fn plan(
+    sources: &[SourceEvent], existing: &[discord::GuildEvent], imported: &std::collections::HashMap<String, Imported>,
+    now: DateTime<Utc>
+) -> Vec<Action> {
+    let existing = existing.iter().map(|e| (e.id, e)).collect::<std::collections::HashMap<_, _>>();
+    let mut actions = vec![];
+    for source in sources {
+        match imported.get(&source.uid) {
+            // Events that have started can't be rescheduled, and ones deleted on Discord aren't brought back
+            Some(i) => if let Some(event) = existing.get(&i.event_id).filter(|e| e.status == discord::GuildEventStatus::Scheduled) {
+                let diffs = diff_event(event, i, source);
+                if !diffs.is_empty() {
+                    actions.push(Action::Update { event_id: i.event_id, source: source.clone(), diffs });
+                }
+            },
+            // Discord can't schedule events in the past
+            None if source.start > now => actions.push(Action::Create(source.clone())),
+            None => {}
+        }
+    }
+
+    let current = sources.iter().map(|s| s.uid.as_str()).collect::<std::collections::HashSet<_>>();
+    let mut removed = imported.iter().filter(|(uid, _)| !current.contains(uid.as_str())).collect::<Vec<_>>();
+    removed.sort_by_key(|(uid, _)| uid.as_str());
+    for (uid, i) in removed {
+        actions.push(match existing.get(&i.event_id) {
+            Some(event) => Action::Delete { uid: uid.clone(), event_id: i.event_id, name: event.name.clone() },
+            None => Action::Forget { uid: uid.clone() }
+        });
+    }
+    actions
+}
+
+/// The Discord API, at a base URL that tests can point somewhere else
+pub struct Api<'a> {
+    pub client: &'a reqwest::Client,
+    pub base: &'a str,
+}
+
+impl Api<'_> {
+    /// Makes a request, waiting and trying again if it's rate limited
+    async fn request(&self, method: reqwest::Method, path: &str, body: Option<&serde_json::Value>) -> Result<reqwest::Response, String> {
+        for _ in 0..3 {
+            let mut req = self.client.request(method.clone(), format!("{}{}", self.base, path));
+            if let Some(body) = body {
+                req = req.json(body);
+            }
+            let res = req.send().await.map_err(|e| format!("Unable to {} {}: {}", method, path, e))?;
+            if res.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Ok(res);
+            }
+            let wait = res.headers().get("Retry-After").and_then(|r| r.to_str().ok()).and_then(|r| r.parse::<f64>().ok())
+                .unwrap_or(1.0);
+            tokio::time::sleep(std::time::Duration::from_secs_f64(wait.min(60.0))).await;
+        }
+        Err(format!("Rate limited on {} {}", method, path))
+    }
+}
+
+/// Fetches an image, as the data URI Discord takes cover images as
+async fn image_data(client: &reqwest::Client, url: &str) -> Result<String, String> {
+    let res = client.get(url).send().await.and_then(|r| r.error_for_status())
+        .map_err(|e| format!("Unable to fetch image {}: {}", url, e))?;
+    let content_type = res.headers().get(reqwest::header::CONTENT_TYPE).and_then(|c| c.to_str().ok())
+        .filter(|c| ["image/png", "image/jpeg", "image/gif", "image/webp"].contains(c))
+        .map(ToString::to_string)
+        .ok_or_else(|| format!("Image {} isn't a PNG, JPEG, GIF or WebP", url))?;
+    let bytes = res.bytes().await.map_err(|e| format!("Unable to fetch image {}: {}", url, e))?;
+    Ok(format!("data:{};base64,{}", content_type, base64::encode(bytes)))
+}
+
+/// Adds a source event's image to a request body, leaving it out if it can't be fetched. Gives whether it was added.
+async fn add_image(client: &reqwest::Client, body: &mut serde_json::Value, image: &Option<String>) -> bool {
+    match image {
+        Some(url) => match image_data(client, url).await {
+            Ok(data) => {
+                body["image"] = data.into();
+                true
+            },
+            Err(e) => {
+                println!("{}", e);
+                false
+            }
+        },
+        None => {
+            body["image"] = serde_json::Value::Null;
+            true
+        }
+    }
+}
+
+/// Carries out a plan, giving how many actions failed
+async fn apply(
+    api: &Api<'_>, client: &reqwest::Client, guild_id: discord::Snowflake, actions: &[Action],
+    imported: &mut std::collections::HashMap<String, Imported>
+) -> usize {
+    let mut failed = 0;
+    for action in actions {
+        let result = match action {
+            Action::Create(source) => {
+                let mut body = serde_json::json!({
+                    "name": source.name,
+                    "description": source.description,
+                    "scheduled_start_time": source.start.to_rfc3339(),
+                    "scheduled_end_time": source.end.to_rfc3339(),
+                    "privacy_level": 2,
+                    "entity_type": 3,
+                    "entity_metadata": { "location": source.location },
+                });
+                // Images that couldn't be uploaded aren't recorded, so they're tried again next time
+                let image = match source.image.is_some() && add_image(client, &mut body, &source.image).await {
+                    true => source.image.clone(),
+                    false => None
+                };
+                match api.request(reqwest::Method::POST, &format!("/guilds/{}/scheduled-events", guild_id), Some(&body)).await
+                    .and_then(|r| r.error_for_status().map_err(|e| e.to_string())) {
+                    Ok(r) => r.json::<discord::GuildEvent>().await
+                        .map(|event| {
+                            imported.insert(source.uid.clone(), Imported { event_id: event.id, image });
+                        })
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(e)
+                }
+            },
+            Action::Update { event_id, source, diffs } => {
+                let mut body = serde_json::json!({});
+                let mut image = imported.get(&source.uid).and_then(|i| i.image.clone());
+                for diff in diffs {
+                    match diff.field {
+                        "name" => body["name"] = source.name.clone().into(),
+                        "description" => body["description"] = serde_json::json!(source.description),
+                        "scheduled_start_time" => body["scheduled_start_time"] = source.start.to_rfc3339().into(),
+                        "scheduled_end_time" => body["scheduled_end_time"] = source.end.to_rfc3339().into(),
+                        "location" => body["entity_metadata"] = serde_json::json!({ "location": source.location }),
+                        "image" if add_image(client, &mut body, &source.image).await => image = source.image.clone(),
+                        _ => {}
+                    }
+                }
+                let path = format!("/guilds/{}/scheduled-events/{}", guild_id, event_id);
+                api.request(reqwest::Method::PATCH, &path, Some(&body)).await
+                    .and_then(|r| r.error_for_status().map_err(|e| e.to_string()))
+                    .map(|_| {
+                        imported.insert(source.uid.clone(), Imported { event_id: *event_id, image });
+                    })
+            },
+            Action::Delete { uid, event_id, .. } => {
+                let path = format!("/guilds/{}/scheduled-events/{}", guild_id, event_id);
+                match api.request(reqwest::Method::DELETE, &path, None).await {
+                    Ok(r) if r.status().is_success() || r.status() == reqwest::StatusCode::NOT_FOUND => {
+                        imported.remove(uid);
+                        Ok(())
+                    },
+                    Ok(r) => Err(r.status().to_string()),
+                    Err(e) => Err(e)
+                }
+            },
+            Action::Forget { uid } => {
+                imported.remove(uid);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            println!("Unable to apply {}: {}", action, e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+/// Syncs a guild's external events with a calendar, giving what was (or with `dry_run`, would be) done and how many
+/// of those actions failed
+pub async fn import(
+    api: &Api<'_>, client: &reqwest::Client, guild_id: discord::Snowflake, ics: &str,
+    imported: &mut std::collections::HashMap<String, Imported>, dry_run: bool
+) -> Result<(Vec<Action>, usize), String> {
+    let calendar = ical::parse::parse(ics).map_err(|e| format!("Unable to parse calendar: {}", e))?;
+    let existing: Vec<discord::GuildEvent> = api.request(reqwest::Method::GET, &format!("/guilds/{}/scheduled-events", guild_id), None).await?
+        .error_for_status().map_err(|e| format!("Unable to fetch events: {}", e))?
+        .json().await.map_err(|e| format!("Unable to parse events: {}", e))?;
+
+    let actions = plan(&source_events(&calendar), &existing, imported, Utc::now());
+    let failed = match dry_run {
+        true => 0,
+        false => apply(api, client, guild_id, &actions, imported).await
+    };
+    Ok((actions, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Events = std::sync::Arc<std::sync::Mutex<(Vec<serde_json::Value>, Vec<String>)>>;
+
+    /// Enough of the Discord API to manage a guild's scheduled events, logging each request made to it
+    async fn mock_discord() -> (String, Events) {
+        let events: Events = Default::default();
+        let state = events.clone();
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let events = state.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+                    let events = events.clone();
+                    async move {
+                        let (method, path) = (req.method().clone(), req.uri().path().to_string());
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let mut events = events.lock().unwrap();
+                        events.1.push(format!("{} {}", method, path));
+                        let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+                        let res = hyper::Response::builder;
+                        let json = |v: &serde_json::Value| res().header("Content-Type", "application/json").body(hyper::Body::from(v.to_string()));
+                        Ok::<_, std::convert::Infallible>(match (method, segments.as_slice()) {
+                            (hyper::Method::GET, ["cover.png"]) => hyper::Response::builder()
+                                .header("Content-Type", "image/png").body(hyper::Body::from(&b"\x89PNG"[..])),
+                            (hyper::Method::GET, ["guilds", _, "scheduled-events"]) => json(&serde_json::Value::from(events.0.clone())),
+                            (hyper::Method::POST, ["guilds", guild, "scheduled-events"]) => {
+                                let mut event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                                event["has_image"] = event.get("image").is_some_and(|i| i.is_string()).into();
+                                event["id"] = (events.0.len() + 100).to_string().into();
+                                event["guild_id"] = guild.to_string().into();
+                                event["status"] = 1.into();
+                                for field in ["channel_id", "image", "entity_id"] {
+                                    event[field] = serde_json::Value::Null;
+                                }
+                                events.0.push(event.clone());
+                                json(&event)
+                            },
+                            (method, ["guilds", _, "scheduled-events", id]) => {
+                                match events.0.iter().position(|e| e["id"] == *id) {
+                                    Some(i) if method == hyper::Method::DELETE => {
+                                        events.0.remove(i);
+                                        res().status(204).body(hyper::Body::empty())
+                                    },
+                                    Some(i) => {
+                                        let patch: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                                        for (key, value) in patch.as_object().unwrap() {
+                                            events.0[i][key] = value.clone();
+                                        }
+                                        json(&events.0[i])
+                                    },
+                                    None => res().status(404).body(hyper::Body::empty())
+                                }
+                            },
+                            _ => res().status(404).body(hyper::Body::empty())
+                        }.unwrap())
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let base = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (base, events)
+    }
+
+    fn feed(events: &[(&str, &str, &str)], image: &str) -> String {
+        let mut ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:test\r\n".to_string();
+        for (uid, summary, location) in events {
+            ics += &format!(
+                "BEGIN:VEVENT\r\nUID:{}\r\nDTSTAMP:20300101T000000Z\r\nDTSTART:20300601T180000Z\r\nDURATION:PT2H\r\n\
+                SUMMARY:{}\r\nLOCATION:{}\r\nIMAGE;VALUE=URI:{}\r\nEND:VEVENT\r\n",
+                uid, summary, location, image
+            );
+        }
+        ics + "END:VCALENDAR\r\n"
+    }
+
+    #[tokio::test]
+    async fn syncs_with_mock_discord() {
+        let (base, events) = mock_discord().await;
+        let client = crate::plain_client().unwrap();
+        let api = Api { client: &client, base: &base };
+        let guild = discord::Snowflake(10);
+        let image = format!("{}/cover.png", base);
+        let mut imported = std::collections::HashMap::new();
+
+        let source = feed(&[("a@example.com", "Game night", "The pub"), ("b@example.com", "Quiz", "The library")], &image);
+        let (actions, failed) = import(&api, &client, guild, &source, &mut imported, false).await.unwrap();
+        assert_eq!((actions.len(), failed), (2, 0));
+        {
+            let events = events.lock().unwrap();
+            assert_eq!(events.0.len(), 2);
+            assert_eq!(events.0[0]["entity_type"], 3);
+            assert_eq!(events.0[0]["entity_metadata"]["location"], "The pub");
+            assert_eq!(events.0[0]["scheduled_end_time"], "2030-06-01T20:00:00+00:00");
+            assert_eq!(events.0[0]["has_image"], true);
+        }
+
+        // Importing the same calendar again doesn't duplicate anything
+        let (actions, _) = import(&api, &client, guild, &source, &mut imported, false).await.unwrap();
+        assert!(actions.is_empty());
+
+        // A dry run shows the changes without making them
+        let source = feed(&[("a@example.com", "Game night", "The bar")], &image);
+        events.lock().unwrap().1.clear();
+        let (actions, _) = import(&api, &client, guild, &source, &mut imported, true).await.unwrap();
+        assert_eq!(actions.iter().map(ToString::to_string).collect::<Vec<_>>(), vec![
+            "~ \"Game night\" (a@example.com)\n    location: \"The pub\" -> \"The bar\"".to_string(),
+            "- \"Quiz\" (b@example.com)".to_string(),
+        ]);
+        assert_eq!(events.lock().unwrap().1, vec!["GET /guilds/10/scheduled-events".to_string()]);
+
+        let (_, failed) = import(&api, &client, guild, &source, &mut imported, false).await.unwrap();
+        assert_eq!(failed, 0);
+        let events = events.lock().unwrap();
+        assert_eq!(events.0.len(), 1);
+        assert_eq!(events.0[0]["entity_metadata"]["location"], "The bar");
+        assert_eq!(imported.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn skips_recurring_events_and_missing_images() {
+        let (base, events) = mock_discord().await;
+        let client = crate::plain_client().unwrap();
+        let api = Api { client: &client, base: &base };
+        let guild = discord::Snowflake(10);
+        let mut imported = std::collections::HashMap::new();
+
+        let source = feed(&[("a@example.com", "Game night", "The pub")], &format!("{}/missing.png", base))
+            .replace("END:VCALENDAR", "BEGIN:VEVENT\r\nUID:weekly@example.com\r\nDTSTAMP:20300101T000000Z\r\n\
+                DTSTART:20300601T180000Z\r\nRRULE:FREQ=WEEKLY\r\nSUMMARY:Weekly quiz\r\nLOCATION:The library\r\n\
+                END:VEVENT\r\nEND:VCALENDAR");
+        let (actions, failed) = import(&api, &client, guild, &source, &mut imported, false).await.unwrap();
+        assert_eq!((actions.len(), failed), (1, 0));
+        assert_eq!(events.lock().unwrap().0[0]["has_image"], false);
+        assert_eq!(imported["a@example.com"].image, None);
+
+        // Once the image can be fetched, it's added
+        let source = source.replace("missing.png", "cover.png");
+        let (actions, failed) = import(&api, &client, guild, &source, &mut imported, false).await.unwrap();
+        assert_eq!((actions.len(), failed), (1, 0));
+        assert_eq!(imported["a@example.com"].image, Some(format!("{}/cover.png", base)));
+        assert_eq!(events.lock().unwrap().0.len(), 1);
+    }
+}
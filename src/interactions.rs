@@ -0,0 +1,198 @@
+use crate::{discord, Config, API_BASE};
+
+/// The application's public key, which Discord signs interaction requests with
+pub struct PublicKey(pub Option<ed25519_dalek::VerifyingKey>);
+
+impl PublicKey {
+    pub fn parse(hex_key: Option<&str>) -> Result<Self, String> {
+        let hex_key = match hex_key {
+            Some(k) => k,
+            None => return Ok(PublicKey(None))
+        };
+        let bytes: [u8; 32] = hex::decode(hex_key).ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| "Public key must be 64 hex digits".to_string())?;
+        ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+            .map(|k| PublicKey(Some(k)))
+            .map_err(|e| format!("Invalid public key: {}", e))
+    }
+}
+
+/// Signature headers Discord sends with every interaction request
+pub struct Signature {
+    signature: String,
+    timestamp: String,
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for Signature {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> rocket::request::Outcome<Self, ()> {
+        let headers = request.headers();
+        match (headers.get_one("X-Signature-Ed25519"), headers.get_one("X-Signature-Timestamp")) {
+            (Some(signature), Some(timestamp)) => rocket::request::Outcome::Success(Signature {
+                signature: signature.to_string(),
+                timestamp: timestamp.to_string(),
+            }),
+            _ => rocket::request::Outcome::Failure((rocket::http::Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// Checks a request was signed by Discord, over its timestamp followed by its body
+fn verify(key: &ed25519_dalek::VerifyingKey, signature: &Signature, body: &str) -> bool {
+    let bytes: [u8; 64] = match hex::decode(&signature.signature).ok().and_then(|s| s.try_into().ok()) {
+        Some(s) => s,
+        None => return false
+    };
+    let message = [signature.timestamp.as_bytes(), body.as_bytes()].concat();
+    key.verify_strict(&message, &ed25519_dalek::Signature::from_bytes(&bytes)).is_ok()
+}
+
+fn message(content: String) -> discord::InteractionResponse {
+    discord::InteractionResponse {
+        kind: discord::InteractionCallbackType::ChannelMessageWithSource,
+        data: Some(discord::InteractionCallbackData {
+            content,
+            flags: discord::MESSAGE_FLAG_EPHEMERAL
+        })
+    }
+}
+
+/// Replies to the `/calendar` command with the links to subscribe to the server's calendar, in the user's language
+fn calendar_command(config: &Config, guild_id: Option<discord::Snowflake>, locale: crate::locale::Locale)
+    -> discord::InteractionResponse {
+    let path = match guild_id {
+        Some(g) => uri!(crate::calendar(g, _, _, locale.language(), _)),
+        None => return message(locale.text("calendar-command-outside-server", &[]))
+    };
+    let host = config.root_url.split_once("://").map_or(config.root_url.as_str(), |(_, h)| h);
+    message(locale.text("calendar-command", &[
+        ("webcal", &format!("<webcal://{}{}>", host, path)), ("url", &format!("<{}{}>", config.root_url, path))
+    ]))
+}
+
+#[post("/interactions", data = "<body>")]
+pub fn endpoint(config: &rocket::State<Config>, key: &rocket::State<PublicKey>, signature: Signature, body: String)
+    -> Result<(rocket::http::ContentType, String), rocket::http::Status> {
+    let key = key.0.as_ref().ok_or(rocket::http::Status::NotFound)?;
+    if !verify(key, &signature, &body) {
+        return Err(rocket::http::Status::Unauthorized);
+    }
+    let interaction: discord::Interaction = serde_json::from_str(&body)
+        .map_err(|_| rocket::http::Status::BadRequest)?;
+
+    let response = match interaction.kind {
+        discord::InteractionType::Ping => discord::InteractionResponse {
+            kind: discord::InteractionCallbackType::Pong,
+            data: None
+        },
+        discord::InteractionType::ApplicationCommand if interaction.data.as_ref().is_some_and(|d| d.name == "calendar") =>
+            calendar_command(
+                config, interaction.guild_id,
+                interaction.locale.as_deref().and_then(crate::locale::Locale::parse).unwrap_or_default()
+            ),
+        _ => return Err(rocket::http::Status::BadRequest)
+    };
+    serde_json::to_string(&response)
+        .map(|r| (rocket::http::ContentType::JSON, r))
+        .map_err(|_| rocket::http::Status::InternalServerError)
+}
+
+/// Registers the bot's application commands, in one guild for testing or globally otherwise
+pub async fn register_commands(client: &reqwest::Client, guild_id: Option<&discord::Snowflake>, root_url: Option<&str>)
+    -> Result<(), String> {
+    let application: discord::Application = client.get(format!("{}/applications/@me", API_BASE))
+        .send().await.and_then(|r| r.error_for_status())
+        .map_err(|e| format!("Unable to fetch application: {}", e))?
+        .json().await
+        .map_err(|e| format!("Unable to parse application: {}", e))?;
+
+    let url = match guild_id {
+        Some(g) => format!("{}/applications/{}/guilds/{}/commands", API_BASE, application.id, g),
+        None => format!("{}/applications/{}/commands", API_BASE, application.id)
+    };
+    let commands = serde_json::json!([{
+        "name": "calendar",
+        "type": 1,
+        "description": "Get a link to subscribe to this server's events in your calendar app",
+        "dm_permission": false
+    }]);
+    client.put(url)
+        .json(&commands)
+        .send().await.and_then(|r| r.error_for_status())
+        .map_err(|e| format!("Unable to register commands: {}", e))?;
+    println!("Registered commands for {}", application.name);
+    if let Some(root_url) = root_url {
+        println!("Set its interactions endpoint URL to {}{}", root_url, uri!(endpoint));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(key: &ed25519_dalek::SigningKey, timestamp: &str, body: &str) -> Signature {
+        let message = [timestamp.as_bytes(), body.as_bytes()].concat();
+        Signature {
+            signature: hex::encode(ed25519_dalek::Signer::sign(key, &message).to_bytes()),
+            timestamp: timestamp.to_string(),
+        }
+    }
+
+    #[test]
+    fn signatures() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+        let other_key = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+        let body = r#"{"type":1}"#;
+
+        assert!(verify(&key.verifying_key(), &sign(&key, "1700000000", body), body));
+        assert!(!verify(&key.verifying_key(), &sign(&key, "1700000000", body), r#"{"type":2}"#));
+        assert!(!verify(&key.verifying_key(), &sign(&other_key, "1700000000", body), body));
+        let mut signature = sign(&key, "1700000000", body);
+        signature.timestamp = "1700000001".to_string();
+        assert!(!verify(&key.verifying_key(), &signature, body));
+        signature.signature = "not hex".to_string();
+        assert!(!verify(&key.verifying_key(), &signature, body));
+
+        let public_key = PublicKey::parse(Some(&hex::encode(key.verifying_key().as_bytes()))).unwrap();
+        assert_eq!(public_key.0, Some(key.verifying_key()));
+        assert!(PublicKey::parse(Some("abcd")).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn commands() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+        let figment = rocket::Config::figment()
+            .merge(("discord_token", "token"))
+            .merge(("root_url", "https://events.example.com"))
+            .merge(("public_key", hex::encode(key.verifying_key().as_bytes())));
+        let client = rocket::local::asynchronous::Client::tracked(crate::rocket().configure(figment)).await.unwrap();
+        let post = |body: &'static str, signature: Signature| client.post(uri!(endpoint))
+            .header(rocket::http::Header::new("X-Signature-Ed25519", signature.signature))
+            .header(rocket::http::Header::new("X-Signature-Timestamp", signature.timestamp))
+            .body(body);
+
+        let ping = r#"{"id":"1","application_id":"2","type":1,"token":"t"}"#;
+        let res = post(ping, sign(&key, "1700000000", ping)).dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::Ok);
+        assert_eq!(res.into_string().await.unwrap(), r#"{"type":1}"#);
+
+        let res = post(ping, sign(&key, "1700000000", "{}")).dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::Unauthorized);
+        let res = client.post(uri!(endpoint)).body(ping).dispatch().await;
+        assert_eq!(res.status(), rocket::http::Status::Unauthorized);
+
+        let command = r#"{"id":"1","application_id":"2","type":2,"guild_id":"81384788765712384","token":"t",
+            "data":{"id":"3","name":"calendar"}}"#;
+        let res = post(command, sign(&key, "1700000000", command)).dispatch().await;
+        let res: serde_json::Value = serde_json::from_str(&res.into_string().await.unwrap()).unwrap();
+        assert_eq!(res["type"], 4);
+        assert_eq!(res["data"]["flags"], 64);
+        let content = res["data"]["content"].as_str().unwrap();
+        assert!(content.contains("<webcal://events.example.com/guilds/81384788765712384/calendar.ics>"));
+        assert!(content.contains("<https://events.example.com/guilds/81384788765712384/calendar.ics>"));
+    }
+}
@@ -0,0 +1,145 @@
+use chrono::prelude::*;
+
+const DEFAULT_LANGUAGE: &str = "en";
+
+/// Message catalogs built into the binary, by language tag
+const CATALOGS: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.toml")),
+    ("de", include_str!("../locales/de.toml")),
+    ("es", include_str!("../locales/es.toml")),
+    ("fr", include_str!("../locales/fr.toml")),
+];
+
+#[derive(Deserialize)]
+struct Catalog {
+    months: Vec<String>,
+    weekdays: Vec<String>,
+    #[serde(flatten)]
+    messages: std::collections::HashMap<String, String>,
+}
+
+fn catalogs() -> &'static std::collections::HashMap<&'static str, Catalog> {
+    static CATALOGS_PARSED: std::sync::OnceLock<std::collections::HashMap<&'static str, Catalog>> = std::sync::OnceLock::new();
+    CATALOGS_PARSED.get_or_init(|| CATALOGS.iter()
+        .map(|(tag, data)| (*tag, toml::from_str(data).unwrap_or_else(|e| panic!("Invalid {} catalog: {}", tag, e))))
+        .collect())
+}
+
+/// The language generated text is written in, and whether it was asked for rather than fallen back to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Locale {
+    tag: &'static str,
+    explicit: bool,
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Locale { tag: DEFAULT_LANGUAGE, explicit: false }
+    }
+}
+
+impl Locale {
+    /// The supported language with the same primary subtag as `tag`, so `de-AT` gets German
+    pub fn parse(tag: &str) -> Option<Self> {
+        let primary = tag.trim().split(['-', '_']).next()?.to_ascii_lowercase();
+        CATALOGS.iter().find(|(t, _)| *t == primary).map(|(tag, _)| Locale { tag, explicit: true })
+    }
+
+    /// Picks the language from a `lang` query parameter, then an Accept-Language header, then the default
+    pub fn negotiate(lang: Option<&str>, accept_language: Option<&str>) -> Self {
+        if let Some(locale) = lang.and_then(Locale::parse) {
+            return locale;
+        }
+        let mut ranges = accept_language.unwrap_or_default().split(',').filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts.find_map(|p| p.trim().strip_prefix("q=")).map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            Some((tag, quality)).filter(|_| quality > 0.0)
+        }).collect::<Vec<_>>();
+        // Stable, so ranges of equal quality keep the order they were given in
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranges.into_iter().find_map(|(tag, _)| Locale::parse(tag)).unwrap_or_default()
+    }
+
+    /// The language to mark generated text with, which is left off when nobody asked for one
+    pub fn language(&self) -> Option<&'static str> {
+        Some(self.tag).filter(|_| self.explicit)
+    }
+
+    fn catalog(&self) -> &'static Catalog {
+        &catalogs()[self.tag]
+    }
+
+    /// Looks up a message, falling back to English if it hasn't been translated, and fills in its `{placeholders}`
+    pub fn text(&self, key: &str, args: &[(&str, &str)]) -> String {
+        let message = self.catalog().messages.get(key)
+            .or_else(|| catalogs()[DEFAULT_LANGUAGE].messages.get(key))
+            .map_or(key, String::as_str);
+        args.iter().fold(message.to_string(), |message, (name, value)| message.replace(&format!("{{{}}}", name), value))
+    }
+
+    /// Formats a time with one of the catalog's date and time formats, in which `%B` and `%A` are the month and weekday
+    /// names from the catalog
+    pub fn format<Tz: TimeZone>(&self, key: &str, date_time: &DateTime<Tz>) -> String where Tz::Offset: std::fmt::Display {
+        let catalog = self.catalog();
+        let format = self.text(key, &[])
+            .replace("%B", &catalog.months[date_time.month0() as usize])
+            .replace("%A", &catalog.weekdays[date_time.weekday().num_days_from_monday() as usize]);
+        date_time.format(&format).to_string()
+    }
+}
+
+/// The Accept-Language header of a request, if it had one
+pub struct AcceptLanguage(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for AcceptLanguage {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> rocket::request::Outcome<Self, Self::Error> {
+        rocket::request::Outcome::Success(AcceptLanguage(request.headers().get_one("Accept-Language").map(ToString::to_string)))
+    }
+}
+
+impl AcceptLanguage {
+    pub fn locale(&self, lang: Option<&str>) -> Locale {
+        Locale::negotiate(lang, self.0.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalogs_are_complete() {
+        let english = &catalogs()[DEFAULT_LANGUAGE];
+        for (tag, catalog) in catalogs() {
+            let mut missing = english.messages.keys().filter(|k| !catalog.messages.contains_key(*k)).collect::<Vec<_>>();
+            missing.sort();
+            assert!(missing.is_empty(), "{} is missing {:?}", tag, missing);
+            assert!(catalog.messages.keys().all(|k| english.messages.contains_key(k)), "{} has extra messages", tag);
+            assert_eq!((catalog.months.len(), catalog.weekdays.len()), (12, 7), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn negotiates() {
+        assert_eq!(Locale::negotiate(None, None), Locale::default());
+        assert_eq!(Locale::negotiate(None, None).language(), None);
+        assert_eq!(Locale::negotiate(Some("fr"), Some("de")).language(), Some("fr"));
+        assert_eq!(Locale::negotiate(Some("xx"), Some("de-AT")).language(), Some("de"));
+        assert_eq!(Locale::negotiate(None, Some("ja, fr;q=0.5, es-MX;q=0.8")).language(), Some("es"));
+        assert_eq!(Locale::negotiate(None, Some("de;q=0, *;q=0.1")), Locale::default());
+        assert_eq!(Locale::negotiate(None, Some("en-GB,en;q=0.9")).language(), Some("en"));
+    }
+
+    #[test]
+    fn formats() {
+        let locale = Locale::parse("de").unwrap();
+        assert_eq!(locale.text("calendar-name", &[("guild", "Club")]), "Events von Club");
+        let date_time = Utc.ymd(2029, 3, 5).and_hms(20, 0, 0);
+        assert_eq!(locale.format("date-time-long", &date_time), "Montag, 5. März 2029 20:00 UTC");
+        assert_eq!(Locale::default().format("date", &date_time), "5 March 2029");
+    }
+}
@@ -1,103 +1,710 @@
-#![feature(proc_macro_hygiene, decl_macro)]
-
 #[macro_use] extern crate rocket;
 #[macro_use] extern crate serde;
 
+mod admin;
+mod caldav;
+mod changes;
+mod cli;
 mod discord;
+mod freebusy;
 mod ical;
+mod import;
+mod interactions;
+mod locale;
+mod markup;
+mod oauth;
+mod settings;
+mod subscribers;
+mod template;
+mod webhooks;
 
-const API_BASE: &'static str = "https://discord.com/api/v10";
+const API_BASE: &str = "https://discord.com/api/v10";
+const READINESS_TTL: std::time::Duration = std::time::Duration::from_secs(60);
+/// Kept under the readiness probe's timeout in kube.yaml
+const READINESS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 
 #[derive(Debug, Deserialize)]
 struct Config {
     discord_token: String,
-    root_url: String
+    root_url: String,
+    #[serde(default)]
+    oauth: Option<oauth::OAuthConfig>,
+    #[serde(default)]
+    feed_secret: Option<String>,
+    #[serde(default)]
+    public_key: Option<String>,
+    #[serde(default = "default_attendee_limit")]
+    attendee_limit: usize,
+    #[serde(default)]
+    time_zones: std::collections::HashMap<discord::Snowflake, chrono_tz::Tz>,
+    #[serde(default)]
+    colors: std::collections::HashMap<discord::Snowflake, String>,
+    #[serde(default = "default_cache_ttl")]
+    cache_ttl: u64,
+    #[serde(default = "default_data_dir")]
+    data_dir: std::path::PathBuf,
+    #[serde(default)]
+    webhooks: Vec<webhooks::WebhookConfig>,
+    #[serde(default = "default_webhook_interval")]
+    webhook_interval: u64,
+    #[serde(default)]
+    caldav: Option<caldav::CalDavConfig>,
+    #[serde(default)]
+    caldav_push: Vec<caldav::push::PushConfig>,
+    #[serde(default = "default_caldav_push_interval")]
+    caldav_push_interval: u64,
+    /// TOML or YAML file of settings for each guild
+    #[serde(default)]
+    settings: Option<std::path::PathBuf>,
+    /// Bearer token for the admin API, which is off without one
+    #[serde(default)]
+    admin_token: Option<String>
 }
 
-#[get("/guilds/<guild_id>/calendar.ics")]
-async fn calendar(client: &rocket::State<reqwest::Client>, config: &rocket::State<Config>, guild_id: String)
-    -> Result<(rocket::http::ContentType, String), rocket::http::Status> {
-    let discord_guild: discord::Guild = client.get(format!("{}/guilds/{guild_id}", API_BASE, guild_id = guild_id))
-        .send().await.map_err(|_| rocket::http::Status::InternalServerError)?
-        .error_for_status().map_err(|e| match e.status() {
-            Some(reqwest::StatusCode::FORBIDDEN) | Some(reqwest::StatusCode::NOT_FOUND) => rocket::http::Status::NotFound,
-            _ => rocket::http::Status::InternalServerError
-        })?
-        .json().await.map_err(|_| rocket::http::Status::InternalServerError)?;
+fn default_data_dir() -> std::path::PathBuf {
+    std::path::PathBuf::from(".")
+}
 
-    let discord_events: Vec<discord::GuildEvent> = client.get(format!("{}/guilds/{guild_id}/scheduled-events", API_BASE, guild_id = guild_id))
-        .send().await.map_err(|_| rocket::http::Status::InternalServerError)?
-        .error_for_status().map_err(|e| match e.status() {
-            Some(reqwest::StatusCode::FORBIDDEN) | Some(reqwest::StatusCode::NOT_FOUND) => rocket::http::Status::NotFound,
-            _ => rocket::http::Status::InternalServerError
-        })?
-        .json().await.map_err(|_| rocket::http::Status::InternalServerError)?;
+fn default_attendee_limit() -> usize {
+    100
+}
 
-    let mut events = vec![];
+fn default_cache_ttl() -> u64 {
+    3600
+}
+
+fn default_webhook_interval() -> u64 {
+    300
+}
+
+fn default_caldav_push_interval() -> u64 {
+    900
+}
 
-    for event in discord_events {
-        let discord_channel = match event.channel_id {
-            Some(i) => {
-                match client.get(format!("{}/channels/{channel_id}", API_BASE, channel_id = i))
-                    .send().await.ok()
-                    .and_then(|r| r.error_for_status().ok())
-                    .map(|r| r.json::<discord::Channel>()) {
-                    Some(c) => c.await.ok(),
-                    None => None,
+impl Config {
+    fn cache_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.cache_ttl)
+    }
+}
+
+/// A serialized calendar shared with the cache, so serving it doesn't copy it
+struct SharedBody(std::sync::Arc<String>);
+
+impl AsRef<[u8]> for SharedBody {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// Where to keep a calendar's chunks once they've all been sent
+struct CacheFill {
+    cache: std::sync::Arc<CalendarCache>,
+    key: CacheKey,
+    body: String,
+}
+
+/// A calendar serialized a chunk at a time, which is also collected into the cache if it's to be cached
+struct CalendarChunks {
+    chunks: ical::Chunks,
+    fill: Option<CacheFill>,
+}
+
+impl Iterator for CalendarChunks {
+    type Item = std::io::Cursor<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.chunks.next() {
+            Some(chunk) => {
+                if let Some(fill) = &mut self.fill {
+                    fill.body.push_str(&chunk);
                 }
+                Some(std::io::Cursor::new(chunk))
             },
-            None => None
+            None => {
+                if let Some(fill) = self.fill.take() {
+                    fill.cache.insert(fill.key, std::sync::Arc::new(fill.body));
+                }
+                None
+            }
+        }
+    }
+}
+
+type CalendarStream = rocket::response::stream::ReaderStream<rocket::futures::stream::Iter<CalendarChunks>>;
+
+/// A calendar response, streamed out an event at a time or served from the cache, which clients and proxies may
+/// cache for the configured TTL
+struct CalendarResponse(rocket::Either<CalendarStream, SharedBody>, rocket::http::Header<'static>);
+
+// Written out by hand, as streams only respond for the lifetime of the request
+impl<'r> rocket::response::Responder<'r, 'r> for CalendarResponse {
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'r> {
+        let response = match self.0 {
+            rocket::Either::Left(stream) => stream.respond_to(request)?,
+            rocket::Either::Right(body) => rocket::Response::build()
+                .sized_body(body.0.len(), std::io::Cursor::new(body))
+                .finalize()
         };
+        rocket::Response::build_from(response)
+            .header(rocket::http::ContentType::Calendar)
+            .header(self.1)
+            .raw_header("Vary", "Accept-Language")
+            .ok()
+    }
+}
+
+impl CalendarResponse {
+    fn stream(calendar: ical::Calendar, fill: Option<CacheFill>, config: &Config) -> Self {
+        let chunks = CalendarChunks { chunks: calendar.into_chunks(), fill };
+        CalendarResponse(
+            rocket::Either::Left(rocket::futures::stream::iter(chunks).into()),
+            rocket::http::Header::new("Cache-Control", format!("max-age={}", config.cache_ttl))
+        )
+    }
+
+    fn new(calendar: ical::Calendar, config: &Config) -> Self {
+        CalendarResponse::stream(calendar, None, config)
+    }
+
+    /// Streams a calendar out, keeping it in the cache under `key` once it's all been sent
+    fn caching(calendar: ical::Calendar, cache: &std::sync::Arc<CalendarCache>, key: CacheKey, config: &Config) -> Self {
+        CalendarResponse::stream(calendar, Some(CacheFill { cache: cache.clone(), key, body: String::new() }), config)
+    }
+
+    fn cached(body: std::sync::Arc<String>, config: &Config) -> Self {
+        CalendarResponse(
+            rocket::Either::Right(SharedBody(body)),
+            rocket::http::Header::new("Cache-Control", format!("max-age={}", config.cache_ttl))
+        )
+    }
+}
+
+/// A guild, the URL of one of its calendars and the language it's in
+type CacheKey = (discord::Snowflake, String, locale::Locale);
+
+/// Serialized guild calendars by URL and language, and guilds' events as served over CalDAV, kept for the cache TTL so
+/// repeated requests don't go to Discord
+#[derive(Default)]
+struct CalendarCache {
+    calendars: std::sync::Mutex<std::collections::HashMap<CacheKey, (std::time::Instant, std::sync::Arc<String>)>>,
+    events: std::sync::Mutex<std::collections::HashMap<discord::Snowflake, (std::time::Instant, std::sync::Arc<caldav::Events>)>>,
+}
+
+impl CalendarCache {
+    fn get(&self, key: &CacheKey, ttl: std::time::Duration) -> Option<std::sync::Arc<String>> {
+        let mut calendars = self.calendars.lock().unwrap();
+        calendars.retain(|_, (cached, _)| cached.elapsed() < ttl);
+        calendars.get(key).map(|(_, c)| c.clone())
+    }
+
+    fn insert(&self, key: CacheKey, calendar: std::sync::Arc<String>) {
+        self.calendars.lock().unwrap().insert(key, (std::time::Instant::now(), calendar));
+    }
+
+    fn events(&self, guild_id: &discord::Snowflake, ttl: std::time::Duration) -> Option<std::sync::Arc<caldav::Events>> {
+        let mut events = self.events.lock().unwrap();
+        events.retain(|_, (cached, _)| cached.elapsed() < ttl);
+        events.get(guild_id).map(|(_, e)| e.clone())
+    }
+
+    fn insert_events(&self, guild_id: discord::Snowflake, events: std::sync::Arc<caldav::Events>) {
+        self.events.lock().unwrap().insert(guild_id, (std::time::Instant::now(), events));
+    }
+
+    fn clear(&self, guild_id: &discord::Snowflake) {
+        self.calendars.lock().unwrap().retain(|(g, _, _), _| g != guild_id);
+        self.events.lock().unwrap().remove(guild_id);
+    }
 
-        events.push(ical::Event {
-            uid: format!("{}@e.discord-events.magicalcodewit.ch", event.id),
-            timestamp: event.id.timestamp(),
-            start: event.scheduled_start_time,
-            end: event.scheduled_end_time,
-            created: Some(event.id.timestamp()),
-            summary: Some(event.name),
-            description: event.description.or(discord_channel.as_ref().and_then(|c| c.topic.clone())),
-            location: match event.entity_type {
-                discord::GuildEventEntityType::External => event.entity_metadata.and_then(|m| m.location),
-                discord::GuildEventEntityType::Voice | discord::GuildEventEntityType::Stage => discord_channel.and_then(|c| c.name).map(|c| format!("#{}", c))
+    fn clear_all(&self) {
+        self.calendars.lock().unwrap().clear();
+        self.events.lock().unwrap().clear();
+    }
+}
+
+/// Result of the last readiness check, so probes don't hit Discord on every request
+#[derive(Default)]
+struct Readiness(std::sync::Mutex<Option<(std::time::Instant, bool)>>);
+
+#[get("/healthz")]
+fn healthz() -> &'static str {
+    "OK"
+}
+
+/// Whether Discord accepts the bot token. Only a rejected token counts as a failure, so a Discord outage doesn't stop
+/// cached calendars being served too.
+async fn token_accepted(client: &reqwest::Client, api_base: &str) -> bool {
+    match client.get(format!("{}/users/@me", api_base)).timeout(READINESS_TIMEOUT).send().await
+        .and_then(|r| r.error_for_status()) {
+        Ok(_) => true,
+        Err(e) if matches!(e.status(), Some(reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN)) => {
+            println!("Readiness check failed: {}", e);
+            false
+        },
+        Err(e) => {
+            println!("Unable to reach Discord for readiness check: {}", e);
+            true
+        }
+    }
+}
+
+#[get("/readyz")]
+async fn readyz(client: &rocket::State<reqwest::Client>, readiness: &rocket::State<Readiness>)
+    -> Result<&'static str, rocket::http::Status> {
+    let cached = readiness.0.lock().unwrap().filter(|(checked, _)| checked.elapsed() < READINESS_TTL);
+    let ready = match cached {
+        Some((_, ready)) => ready,
+        // Checked without holding the lock, so a slow check doesn't hold up other probes
+        None => {
+            let ready = token_accepted(client, API_BASE).await;
+            *readiness.0.lock().unwrap() = Some((std::time::Instant::now(), ready));
+            ready
+        }
+    };
+
+    if ready {
+        Ok("OK")
+    } else {
+        Err(rocket::http::Status::ServiceUnavailable)
+    }
+}
+
+/// Parses an IANA time zone name given in a request
+fn parse_time_zone(tz: Option<&str>) -> Result<Option<chrono_tz::Tz>, rocket::http::Status> {
+    tz.map(|tz| tz.parse().map_err(|_| rocket::http::Status::BadRequest)).transpose()
+}
+
+fn map_discord_error(e: reqwest::Error) -> rocket::http::Status {
+    match e.status() {
+        Some(reqwest::StatusCode::FORBIDDEN) | Some(reqwest::StatusCode::NOT_FOUND) => rocket::http::Status::NotFound,
+        _ => rocket::http::Status::InternalServerError
+    }
+}
+
+/// Builds a GET request against the Discord API, using a user's bearer token in place of the bot token if given
+fn discord_get(client: &reqwest::Client, bearer: Option<&str>, url: String) -> reqwest::RequestBuilder {
+    let req = client.get(url);
+    match bearer {
+        Some(token) => req.bearer_auth(token),
+        None => req
+    }
+}
+
+/// Lists every guild the bot, or the user owning the bearer token, is a member of
+async fn current_user_guilds(client: &reqwest::Client, bearer: Option<&str>)
+    -> Result<Vec<discord::PartialGuild>, rocket::http::Status> {
+    let mut guilds: Vec<discord::PartialGuild> = vec![];
+    loop {
+        let mut url = format!("{}/users/@me/guilds?limit=200", API_BASE);
+        if let Some(last) = guilds.last() {
+            url.push_str(&format!("&after={}", last.id));
+        }
+        let page: Vec<discord::PartialGuild> = discord_get(client, bearer, url)
+            .send().await.map_err(|_| rocket::http::Status::InternalServerError)?
+            .error_for_status().map_err(|_| rocket::http::Status::InternalServerError)?
+            .json().await.map_err(|_| rocket::http::Status::InternalServerError)?;
+        let done = page.len() < 200;
+        guilds.extend(page);
+        if done {
+            return Ok(guilds);
+        }
+    }
+}
+
+async fn guild_events(client: &reqwest::Client, bearer: Option<&str>, guild_id: &discord::Snowflake)
+    -> Result<Vec<discord::GuildEvent>, rocket::http::Status> {
+    discord_get(client, bearer, format!("{}/guilds/{guild_id}/scheduled-events?with_user_count=true", API_BASE, guild_id = guild_id))
+        .send().await.map_err(|_| rocket::http::Status::InternalServerError)?
+        .error_for_status().map_err(map_discord_error)?
+        .json().await.map_err(|_| rocket::http::Status::InternalServerError)
+}
+
+/// Converts a Discord event, filling in its text from a guild's templates where it has them
+async fn event_to_ical(
+    client: &reqwest::Client, bearer: Option<&str>, event: discord::GuildEvent, time_zone: Option<chrono_tz::Tz>,
+    locale: locale::Locale, templates: Option<(&settings::Templates, &discord::Guild)>
+) -> ical::Event {
+    let discord_channel = match &event.channel_id {
+        Some(i) => {
+            match discord_get(client, bearer, format!("{}/channels/{channel_id}", API_BASE, channel_id = i))
+                .send().await.ok()
+                .and_then(|r| r.error_for_status().ok())
+                .map(|r| r.json::<discord::Channel>()) {
+                Some(c) => c.await.ok(),
+                None => None,
+            }
+        },
+        None => None
+    };
+
+    let fields = templates.map(|(_, guild)| template::fields(&event, discord_channel.as_ref(), guild, time_zone, locale));
+    let render = |pick: fn(&settings::Templates) -> &Option<template::Template>| match (templates, &fields) {
+        (Some((templates, _)), Some(fields)) => pick(templates).as_ref().map(|t| t.render(fields)),
+        _ => None
+    };
+    let (summary, templated_description, templated_location) =
+        (render(|t| &t.summary), render(|t| &t.description), render(|t| &t.location));
+
+    let description = match templated_description {
+        Some(d) => d,
+        None => event.description.or(discord_channel.as_ref().and_then(|c| c.topic.clone()))
+    };
+    let resolver = match &description {
+        Some(d) => Some(markup::Resolver::fetch(client, bearer, &event.guild_id, d, time_zone, locale).await),
+        None => None
+    };
+    let (description, html_description) = match (description, resolver) {
+        (Some(d), Some(r)) => (Some(markup::to_text(&d, &r)), Some(markup::to_html(&d, &r))),
+        _ => (None, None)
+    };
+
+    let conference = match (&event.entity_type, &event.channel_id) {
+        (discord::GuildEventEntityType::Voice | discord::GuildEventEntityType::Stage, Some(channel_id)) => Some(ical::Conference {
+            uri: format!("https://discord.com/channels/{}/{}", event.guild_id, channel_id),
+            features: match event.entity_type {
+                discord::GuildEventEntityType::Voice => vec![ical::Feature::Audio, ical::Feature::Video],
+                _ => vec![ical::Feature::Audio]
             },
-            organiser: Some(ical::Organiser {
-                address: format!("https//discord.com/channels/{}", event.guild_id),
-                common_name: event.creator.as_ref().map(|c| format!("{}#{}", c.username, c.discriminator)),
-                sent_by: event.creator.as_ref().map(|c|format!("https//discord.com/channels/@me/{}", c.id))
-            }),
-            status: Some(match event.status {
-                discord::GuildEventStatus::Cancelled => "CANCELLED",
-                _ => "CONFIRMED"
-            }.to_string()),
-            images: event.image.map(|i| vec![
-                ical::Image::Url(format!(
-                    "https://cdn.discordapp.com/guild-events/{scheduled_event_id}/{scheduled_event_cover_image}.png",
-                    scheduled_event_id = event.id, scheduled_event_cover_image = i
-                ))
-            ]).unwrap_or_else(|| vec![])
+            label: Some(discord_channel.as_ref().and_then(|c| c.name.as_ref())
+                .map(|c| locale.text("join-channel", &[("channel", c)]))
+                .unwrap_or_else(|| locale.text("join", &[])))
+        }),
+        _ => None
+    };
+
+    let mut ical_event = ical::Event::new(
+        format!("{}@e.discord-events.magicalcodewit.ch", event.id), event.id.timestamp(), event.scheduled_start_time
+    )
+        .end(event.scheduled_end_time)
+        .created(event.id.timestamp())
+        // An event always has a summary, even if its template comes out empty
+        .summary(summary.flatten().unwrap_or(event.name))
+        .description(description)
+        .html_description(html_description)
+        .location(templated_location.unwrap_or_else(|| match event.entity_type {
+            discord::GuildEventEntityType::External => event.entity_metadata.and_then(|m| m.location),
+            discord::GuildEventEntityType::Voice | discord::GuildEventEntityType::Stage => discord_channel.and_then(|c| c.name)
+                .map(|c| locale.text("channel-location", &[("channel", &c)]))
+        }))
+        .url(format!("https://discord.com/events/{}/{}", event.guild_id, event.id))
+        .organiser(ical::Organiser {
+            address: format!("https://discord.com/channels/{}", event.guild_id),
+            common_name: event.creator.as_ref().map(|c| format!("{}#{}", c.username, c.discriminator)),
+            sent_by: event.creator.as_ref().map(|c| format!("https://discord.com/users/{}", c.id))
         })
+        .status(match event.status {
+            discord::GuildEventStatus::Cancelled => ical::EventStatus::Cancelled,
+            _ => ical::EventStatus::Confirmed
+        });
+    if let Some(conference) = conference {
+        ical_event = ical_event.conference(conference);
     }
+    if let Some(image) = event.image {
+        ical_event = ical_event.image(ical::Image {
+            data: ical::ImageData::Url(format!(
+                "https://cdn.discordapp.com/guild-events/{scheduled_event_id}/{scheduled_event_cover_image}.png",
+                scheduled_event_id = event.id, scheduled_event_cover_image = image
+            )),
+            display: None
+        });
+    }
+    ical_event
+}
+
+/// Builds a guild's calendar, of only the events in one channel if `channel_id` is given
+#[allow(clippy::too_many_arguments)]
+async fn guild_calendar(
+    client: &reqwest::Client, root_url: Option<&str>, guild_id: &discord::Snowflake, channel_id: Option<discord::Snowflake>,
+    attendee_limit: Option<usize>, time_zone: Option<chrono_tz::Tz>, settings: &settings::GuildSettings,
+    locale: locale::Locale
+) -> Result<ical::Calendar, rocket::http::Status> {
+    let discord_guild: discord::Guild = client.get(format!("{}/guilds/{guild_id}", API_BASE, guild_id = guild_id))
+        .send().await.map_err(|_| rocket::http::Status::InternalServerError)?
+        .error_for_status().map_err(map_discord_error)?
+        .json().await.map_err(|_| rocket::http::Status::InternalServerError)?;
+
+    let discord_events = guild_events(client, None, guild_id).await?;
+
+    let mut events = vec![];
+    let in_channel = |e: &discord::GuildEvent| channel_id.is_none_or(|c| e.channel_id == Some(c));
+    for event in discord_events.into_iter().filter(|e| settings.shows(e) && in_channel(e)) {
+        let event_attendees = match attendee_limit {
+            Some(limit) => subscribers::event_attendees(client, &event.guild_id, &event.id, limit).await,
+            None => vec![]
+        };
+        let default_duration = settings.default_duration(event.entity_type);
+        let mut ical_event = event_to_ical(client, None, event, time_zone, locale, Some((&settings.templates, &discord_guild))).await
+            .attendees(event_attendees);
+        if ical_event.end.is_none() {
+            ical_event.end = default_duration.map(|d| ical_event.start + d);
+        }
+        ical_event.alarms = settings.alarms(ical_event.summary.as_deref().unwrap_or_default());
+        events.push(ical_event);
+    }
+
+    let url = root_url.map(|r| format!("{}{}", r, uri!(calendar(*guild_id, _, _, locale.language(), _))));
+    let mut calendar = ical::Calendar::new(format!("Discord Events Export {}", env!("CARGO_PKG_VERSION")))
+        .name(settings.name.clone().unwrap_or_else(|| locale.text("calendar-name", &[("guild", &discord_guild.name)])))
+        .description(settings.description.clone().or(discord_guild.description))
+        .uid(format!("{}@c.discord-events.magicalcodewit.ch", discord_guild.id))
+        .url(url.clone())
+        .source(url)
+        .time_zone(time_zone)
+        .language(locale.language().map(ToString::to_string))
+        .color(settings.color.clone())
+        .events(events);
+    if let Some(icon) = &discord_guild.icon {
+        calendar = calendar.image(ical::Image {
+            data: ical::ImageData::Url(format!("https://cdn.discordapp.com/icons/{}/{}.png", discord_guild.id, icon)),
+            display: Some(ical::ImageDisplay::Badge)
+        });
+    }
+    if let Some(splash) = &discord_guild.splash {
+        calendar = calendar.image(ical::Image {
+            data: ical::ImageData::Url(format!("https://cdn.discordapp.com/splashes/{}/{}.png", discord_guild.id, splash)),
+            display: Some(ical::ImageDisplay::Fullsize)
+        });
+    }
+    if let Some(discovery_splash) = &discord_guild.discovery_splash {
+        calendar = calendar.image(ical::Image {
+            data: ical::ImageData::Url(format!("https://cdn.discordapp.com/discovery-splashes/{}/{}.png", discord_guild.id, discovery_splash)),
+            display: Some(ical::ImageDisplay::Graphic)
+        });
+    }
+    Ok(calendar)
+}
 
-    let calendar = ical::Calendar {
-        product: format!("Discord Events Export {}", env!("CARGO_PKG_VERSION")),
-        version: "2.0".to_string(),
-        scale: Some("GREGORIAN".to_string()),
-        method: None,
-        name: Some(format!("{} Events", discord_guild.name)),
-        description: discord_guild.description,
-        uid: Some(format!("{}@c.discord-events.magicalcodewit.ch", discord_guild.id)),
-        url: Some(format!("{}{}", config.root_url, uri!(calendar(guild_id = guild_id)))),
-        events,
+/// What the guild calendar routes work out before fetching a calendar
+struct GuildRequest {
+    locale: locale::Locale,
+    key: CacheKey,
+    settings: settings::GuildSettings,
+    time_zone: Option<chrono_tz::Tz>,
+    cached: Option<std::sync::Arc<String>>,
+}
+
+impl GuildRequest {
+    /// Refuses blocked guilds and counts the request, then finds the calendar's language, time zone and any cached copy of
+    /// it, by the URL it's served at
+    #[allow(clippy::too_many_arguments)]
+    async fn new(
+        config: &Config, settings: &settings::Settings, registry: &admin::Registry, cache: &CalendarCache,
+        accept_language: &locale::AcceptLanguage, guild_id: discord::Snowflake, source: String, tz: Option<&str>,
+        lang: Option<&str>
+    ) -> Result<Self, rocket::http::Status> {
+        if registry.is_blocked(&guild_id).await {
+            return Err(rocket::http::Status::Forbidden);
+        }
+        registry.record_request(guild_id);
+        let locale = accept_language.locale(lang);
+        let key = (guild_id, source, locale);
+        let cached = cache.get(&key, config.cache_ttl());
+        let settings = settings.guild(&guild_id);
+        let time_zone = parse_time_zone(tz)?.or(settings.tz).or_else(|| config.time_zones.get(&guild_id).copied());
+        Ok(GuildRequest { locale, key, settings, time_zone, cached })
+    }
+
+    /// Fills in what a served calendar has wherever it comes from
+    fn finish(&self, calendar: &mut ical::Calendar, config: &Config) {
+        calendar.source = Some(self.key.1.clone());
+        calendar.refresh_interval = Some(config.cache_ttl());
+        calendar.color = calendar.color.take().or_else(|| config.colors.get(&self.key.0).cloned());
+    }
+}
+
+#[get("/guilds/<guild_id>/calendar.ics?<attendees>&<tz>&<lang>&<validate>")]
+#[allow(clippy::too_many_arguments)]
+async fn calendar(
+    client: &rocket::State<reqwest::Client>, config: &rocket::State<Config>,
+    settings: &rocket::State<std::sync::Arc<settings::Settings>>, registry: &rocket::State<std::sync::Arc<admin::Registry>>,
+    cache: &rocket::State<std::sync::Arc<CalendarCache>>, accept_language: locale::AcceptLanguage,
+    guild_id: Result<discord::Snowflake, std::num::ParseIntError>, attendees: Option<u8>, tz: Option<String>,
+    lang: Option<String>, validate: Option<u8>
+) -> Result<rocket::Either<CalendarResponse, String>, rocket::http::Status> {
+    let guild_id = guild_id.map_err(|_| rocket::http::Status::BadRequest)?;
+    let validate = validate.unwrap_or(0) != 0;
+    let source = format!(
+        "{}{}", config.root_url, uri!(calendar(&guild_id, attendees.filter(|a| *a != 0), tz.as_deref(), lang.as_deref(), _))
+    );
+    let request = GuildRequest::new(
+        config, settings, registry, cache, &accept_language, guild_id, source, tz.as_deref(), lang.as_deref()
+    ).await?;
+    if let Some(cached) = request.cached.clone().filter(|_| !validate) {
+        return Ok(rocket::Either::Left(CalendarResponse::cached(cached, config)));
+    }
+    let attendee_limit = match attendees {
+        Some(a) if a != 0 => Some(config.attendee_limit),
+        _ => None
+    };
+    let result = guild_calendar(
+        client, Some(&config.root_url), &guild_id, None, attendee_limit, request.time_zone, &request.settings, request.locale
+    ).await;
+    registry.record_fetch(guild_id, result.as_ref().map(|c| c.events.len()).map_err(|s| *s));
+    let mut calendar = result?;
+    request.finish(&mut calendar, config);
+
+    // Debugging aid: report problems with the calendar instead of serving it
+    if validate {
+        let issues = ical::validate(&calendar.to_string());
+        if issues.is_empty() {
+            return Ok(rocket::Either::Right("No issues found".to_string()));
+        }
+        return Ok(rocket::Either::Right(issues.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n")));
+    }
+
+    Ok(rocket::Either::Left(CalendarResponse::caching(calendar, cache, request.key, config)))
+}
+
+/// Serves the events held in one of a guild's voice or stage channels, as a calendar named after the channel
+#[get("/guilds/<guild_id>/channels/<channel_id>/calendar.ics?<attendees>&<tz>&<lang>")]
+#[allow(clippy::too_many_arguments)]
+async fn channel_calendar(
+    client: &rocket::State<reqwest::Client>, config: &rocket::State<Config>,
+    settings: &rocket::State<std::sync::Arc<settings::Settings>>, registry: &rocket::State<std::sync::Arc<admin::Registry>>,
+    cache: &rocket::State<std::sync::Arc<CalendarCache>>, accept_language: locale::AcceptLanguage,
+    guild_id: Result<discord::Snowflake, std::num::ParseIntError>,
+    channel_id: Result<discord::Snowflake, std::num::ParseIntError>, attendees: Option<u8>, tz: Option<String>,
+    lang: Option<String>
+) -> Result<CalendarResponse, rocket::http::Status> {
+    let (guild_id, channel_id) = match (guild_id, channel_id) {
+        (Ok(g), Ok(c)) => (g, c),
+        _ => return Err(rocket::http::Status::BadRequest)
     };
+    let source = format!("{}{}", config.root_url, uri!(channel_calendar(
+        &guild_id, &channel_id, attendees.filter(|a| *a != 0), tz.as_deref(), lang.as_deref()
+    )));
+    let request = GuildRequest::new(
+        config, settings, registry, cache, &accept_language, guild_id, source, tz.as_deref(), lang.as_deref()
+    ).await?;
+    if let Some(cached) = request.cached.clone() {
+        return Ok(CalendarResponse::cached(cached, config));
+    }
+
+    // A channel of another guild, or one events can't be held in, would otherwise get an empty calendar, rather than not
+    // being found
+    let channel: discord::Channel = client.get(format!("{}/channels/{}", API_BASE, channel_id))
+        .send().await.map_err(|_| rocket::http::Status::InternalServerError)?
+        .error_for_status().map_err(map_discord_error)?
+        .json().await.map_err(|_| rocket::http::Status::InternalServerError)?;
+    if channel.guild_id != Some(guild_id) || !channel.holds_events() {
+        return Err(rocket::http::Status::NotFound);
+    }
+
+    // Fetch stats are left to the guild's own calendar, as these only show some of its events
+    let attendee_limit = attendees.filter(|a| *a != 0).map(|_| config.attendee_limit);
+    let mut calendar = guild_calendar(
+        client, Some(&config.root_url), &guild_id, Some(channel_id), attendee_limit, request.time_zone, &request.settings,
+        request.locale
+    ).await?;
+    let url = format!("{}{}", config.root_url, uri!(channel_calendar(&guild_id, &channel_id, _, _, lang.as_deref())));
+    calendar.name = channel.name.map(|c| request.locale.text("channel-calendar-name", &[("channel", &c)])).or(calendar.name);
+    calendar.description = channel.topic;
+    calendar.uid = Some(format!("{}@c.discord-events.magicalcodewit.ch", channel_id));
+    calendar.url = Some(url);
+    request.finish(&mut calendar, config);
+
+    Ok(CalendarResponse::caching(calendar, cache, request.key, config))
+}
+
+/// Starts an HTML page, marked with its language if one was asked for
+fn html_start(locale: locale::Locale) -> String {
+    match locale.language() {
+        Some(language) => format!("<!DOCTYPE html><html lang=\"{}\">", language),
+        None => "<!DOCTYPE html><html>".to_string()
+    }
+}
 
-    Ok((rocket::http::ContentType::Calendar, calendar.to_string()))
+/// A plain error page, for people who open a calendar link in their browser
+fn error_page(status: rocket::http::Status, locale: locale::Locale, message: &str)
+    -> (rocket::http::Status, rocket::response::content::RawHtml<String>) {
+    (status, rocket::response::content::RawHtml(format!(
+        "{}<head><title>Discord Events Export</title></head><body><p>{}</p></body></html>",
+        html_start(locale), locale.text(message, &[])
+    )))
 }
 
-#[launch]
-fn rocket() -> _ {
+/// Redirects an invite or vanity URL code to the calendar of the server it's for
+#[get("/invites/<code>/calendar.ics?<attendees>&<tz>&<lang>")]
+async fn invite_calendar(
+    client: &rocket::State<reqwest::Client>, accept_language: locale::AcceptLanguage, code: String, attendees: Option<u8>,
+    tz: Option<String>, lang: Option<String>
+) -> Result<rocket::response::Redirect, (rocket::http::Status, rocket::response::content::RawHtml<String>)> {
+    let locale = accept_language.locale(lang.as_deref());
+    let unknown = || error_page(rocket::http::Status::NotFound, locale, "invite-unknown");
+    // Codes go into the Discord API URL, so don't let them reach anything other than an invite
+    if code.is_empty() || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(unknown());
+    }
+
+    let invite: discord::Invite = match client.get(format!("{}/invites/{}?with_expiration=true", API_BASE, code))
+        .send().await.and_then(|r| r.error_for_status()) {
+        Ok(r) => r.json().await
+            .map_err(|_| error_page(rocket::http::Status::InternalServerError, locale, "invite-unreadable"))?,
+        Err(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => return Err(unknown()),
+        Err(e) => {
+            println!("Unable to look up invite {}: {}", code, e);
+            return Err(error_page(rocket::http::Status::InternalServerError, locale, "invite-lookup-failed"));
+        }
+    };
+    if invite.expires_at.is_some_and(|e| e <= chrono::Utc::now()) {
+        return Err(error_page(rocket::http::Status::Gone, locale, "invite-expired"));
+    }
+    let guild = invite.guild.ok_or_else(|| error_page(rocket::http::Status::NotFound, locale, "invite-group-chat"))?;
+
+    Ok(rocket::response::Redirect::temporary(uri!(calendar(
+        guild.id, attendees.filter(|a| *a != 0), tz.as_deref(), lang.as_deref(), _
+    ))))
+}
+
+/// Builds an HTTP client that authenticates to Discord as the bot
+fn bot_client(discord_token: &str) -> Result<reqwest::Client, String> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    let mut auth_value = reqwest::header::HeaderValue::from_str(&format!("Bot {}", discord_token))
+        .map_err(|e| format!("Unable to make auth header: {}", e))?;
+    auth_value.set_sensitive(true);
+    headers.insert("Authorization", auth_value);
+    reqwest::Client::builder()
+        .https_only(true)
+        .user_agent(format!("DiscordEventExport ({})", env!("CARGO_PKG_VERSION")))
+        .default_headers(headers)
+        .build()
+        .map_err(|e| format!("Unable to build request client: {}", e))
+}
+
+/// Builds an HTTP client for services other than Discord, such as webhooks, which may be served over plain HTTP
+fn plain_client() -> Result<reqwest::Client, String> {
+    reqwest::Client::builder()
+        .user_agent(format!("DiscordEventExport ({})", env!("CARGO_PKG_VERSION")))
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .map_err(|e| format!("Unable to build request client: {}", e))
+}
+
+#[rocket::main]
+async fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(e) = rocket().launch().await {
+        println!("Unable to launch: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn rocket() -> rocket::Rocket<rocket::Build> {
     rocket::build()
-        .mount("/", routes![calendar])
+        .mount("/", routes![healthz, readyz, calendar, channel_calendar, invite_calendar, oauth::login, oauth::callback, oauth::feed,
+            subscribers::calendar, freebusy::calendar, interactions::endpoint, admin::guilds, admin::set_blocked, admin::refresh
+        ])
+        .manage(Readiness::default())
+        .manage(subscribers::SubscriberIndex::default())
+        .manage(std::sync::Arc::new(CalendarCache::default()))
         .attach(rocket::fairing::AdHoc::config::<Config>())
         .attach(rocket::fairing::AdHoc::try_on_ignite("HTTP client", |rocket| async {
             let config = match rocket.state::<Config>() {
@@ -107,27 +714,239 @@ fn rocket() -> _ {
                     return Err(rocket)
                 }
             };
-            let mut headers = reqwest::header::HeaderMap::new();
-            let mut auth_value = match reqwest::header::HeaderValue::from_str(&format!("Bot {}", config.discord_token)) {
-                Ok(a) => a,
+            let client = match bot_client(&config.discord_token) {
+                Ok(c) => c,
                 Err(e) => {
-                    println!("Unable to make auth header: {}", e);
+                    println!("{}", e);
                     return Err(rocket)
                 }
             };
-            auth_value.set_sensitive(true);
-            headers.insert("Authorization", auth_value);
-            let client = match reqwest::Client::builder()
-                .https_only(true)
-                .user_agent(format!("DiscordEventExport ({})", env!("CARGO_PKG_VERSION")))
-                .default_headers(headers)
-                .build() {
-                Ok(a) => a,
+            Ok(rocket.manage(client))
+        }))
+        .attach(rocket::fairing::AdHoc::try_on_ignite("User store", |rocket| async {
+            let path = match rocket.state::<Config>() {
+                Some(c) => c.data_dir.join("users.json"),
+                None => {
+                    println!("Unable to access config");
+                    return Err(rocket)
+                }
+            };
+            let store = match oauth::UserStore::load(path) {
+                Ok(s) => s,
                 Err(e) => {
-                    println!("Unable to build request client: {}", e);
+                    println!("Unable to load user store: {}", e);
                     return Err(rocket)
                 }
             };
-            Ok(rocket.manage(client))
+            Ok(rocket.manage(store))
+        }))
+        .attach(rocket::fairing::AdHoc::try_on_ignite("Admin registry", |rocket| async {
+            let path = match rocket.state::<Config>() {
+                Some(c) => {
+                    if c.admin_token.is_some() {
+                        println!("Admin API enabled at {}{}", c.root_url, uri!(admin::guilds));
+                    }
+                    c.data_dir.join("blocked.json")
+                },
+                None => {
+                    println!("Unable to access config");
+                    return Err(rocket)
+                }
+            };
+            match admin::Registry::load(path) {
+                Ok(r) => Ok(rocket.manage(std::sync::Arc::new(r))),
+                Err(e) => {
+                    println!("Unable to load blocked guilds: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(rocket::fairing::AdHoc::try_on_ignite("Guild settings", |rocket| async {
+            let path = match rocket.state::<Config>() {
+                Some(c) => c.settings.clone(),
+                None => {
+                    println!("Unable to access config");
+                    return Err(rocket)
+                }
+            };
+            match settings::Settings::load(path) {
+                Ok(s) => Ok(rocket.manage(std::sync::Arc::new(s))),
+                Err(e) => {
+                    println!("{}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(rocket::fairing::AdHoc::on_liftoff("Guild settings reload", |rocket| Box::pin(async move {
+            if let (Some(Config { settings: Some(_), .. }), Some(settings), Some(cache)) = (
+                rocket.state::<Config>(), rocket.state::<std::sync::Arc<settings::Settings>>(),
+                rocket.state::<std::sync::Arc<CalendarCache>>()
+            ) {
+                tokio::spawn(settings::watch(settings.clone(), cache.clone()));
+            }
+        })))
+        .attach(rocket::fairing::AdHoc::try_on_ignite("Interactions key", |rocket| async {
+            let key = match rocket.state::<Config>() {
+                Some(c) => interactions::PublicKey::parse(c.public_key.as_deref()),
+                None => {
+                    println!("Unable to access config");
+                    return Err(rocket)
+                }
+            };
+            match key {
+                Ok(k) => Ok(rocket.manage(k)),
+                Err(e) => {
+                    println!("{}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(rocket::fairing::AdHoc::try_on_ignite("Webhook queue", |rocket| async {
+            let path = match rocket.state::<Config>() {
+                Some(c) => c.data_dir.join("webhooks.json"),
+                None => {
+                    println!("Unable to access config");
+                    return Err(rocket)
+                }
+            };
+            match webhooks::Queue::load(path) {
+                Ok(q) => Ok(rocket.manage(std::sync::Arc::new(q))),
+                Err(e) => {
+                    println!("Unable to load webhook queue: {}", e);
+                    Err(rocket)
+                }
+            }
         }))
+        .attach(rocket::fairing::AdHoc::on_liftoff("Webhooks", |rocket| Box::pin(async move {
+            let (config, bot_client, queue, registry) = match (
+                rocket.state::<Config>(), rocket.state::<reqwest::Client>(), rocket.state::<std::sync::Arc<webhooks::Queue>>(),
+                rocket.state::<std::sync::Arc<admin::Registry>>()
+            ) {
+                (Some(c), Some(b), Some(q), Some(r)) if !c.webhooks.is_empty() => (c, b, q, r),
+                _ => return
+            };
+            let client = match plain_client() {
+                Ok(c) => c,
+                Err(e) => {
+                    println!("{}", e);
+                    return
+                }
+            };
+            tokio::spawn(webhooks::run(
+                bot_client.clone(), client, queue.clone(), registry.clone(), config.webhooks.clone(),
+                std::time::Duration::from_secs(config.webhook_interval)
+            ));
+        })))
+        .attach(rocket::fairing::AdHoc::on_liftoff("CalDAV", |rocket| Box::pin(async move {
+            let (config, caldav, client, settings, registry, cache) = match (
+                rocket.state::<Config>(), rocket.state::<reqwest::Client>(), rocket.state::<std::sync::Arc<settings::Settings>>(),
+                rocket.state::<std::sync::Arc<admin::Registry>>(), rocket.state::<std::sync::Arc<CalendarCache>>()
+            ) {
+                (Some(c @ Config { caldav: Some(caldav), .. }), Some(client), Some(s), Some(r), Some(cache)) =>
+                    (c, caldav, client, s, r, cache),
+                _ => return
+            };
+            let server = caldav::Server {
+                client: client.clone(),
+                guilds: caldav.guilds.clone(),
+                time_zones: config.time_zones.clone(),
+                settings: settings.clone(),
+                registry: registry.clone(),
+                cache: cache.clone(),
+                cache_ttl: config.cache_ttl(),
+                sync: caldav::SyncHistory::default(),
+            };
+            tokio::spawn(caldav::serve(std::sync::Arc::new(server), (caldav.address, caldav.port).into()));
+        })))
+        .attach(rocket::fairing::AdHoc::try_on_ignite("CalDAV mappings", |rocket| async {
+            let path = match rocket.state::<Config>() {
+                Some(c) => c.data_dir.join("caldav-push.json"),
+                None => {
+                    println!("Unable to access config");
+                    return Err(rocket)
+                }
+            };
+            match caldav::push::Mappings::load(path) {
+                Ok(m) => Ok(rocket.manage(std::sync::Arc::new(m))),
+                Err(e) => {
+                    println!("Unable to load CalDAV mappings: {}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(rocket::fairing::AdHoc::on_liftoff("CalDAV push", |rocket| Box::pin(async move {
+            let (config, bot_client, mappings, settings, registry) = match (
+                rocket.state::<Config>(), rocket.state::<reqwest::Client>(),
+                rocket.state::<std::sync::Arc<caldav::push::Mappings>>(), rocket.state::<std::sync::Arc<settings::Settings>>(),
+                rocket.state::<std::sync::Arc<admin::Registry>>()
+            ) {
+                (Some(c), Some(b), Some(m), Some(s), Some(r)) if !c.caldav_push.is_empty() => (c, b, m, s, r),
+                _ => return
+            };
+            let client = match plain_client() {
+                Ok(c) => c,
+                Err(e) => {
+                    println!("{}", e);
+                    return
+                }
+            };
+            tokio::spawn(caldav::push::run(
+                bot_client.clone(), client, mappings.clone(), config.caldav_push.clone(), config.time_zones.clone(),
+                settings.clone(), registry.clone(), std::time::Duration::from_secs(config.caldav_push_interval)
+            ));
+        })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every request with the given status
+    async fn respond_with(status: u16) -> String {
+        let make_service = hyper::service::make_service_fn(move |_| async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |_| async move {
+                hyper::Response::builder().status(status).body(hyper::Body::from("{}"))
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let base = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        base
+    }
+
+    #[tokio::test]
+    async fn checks_token() {
+        let client = plain_client().unwrap();
+        assert!(token_accepted(&client, &respond_with(200).await).await);
+        assert!(!token_accepted(&client, &respond_with(401).await).await);
+        // Discord being down isn't the bot's fault
+        assert!(token_accepted(&client, &respond_with(502).await).await);
+        assert!(token_accepted(&client, "http://127.0.0.1:1").await);
+    }
+
+    #[test]
+    fn caches_streamed_calendars() {
+        let cache = std::sync::Arc::new(CalendarCache::default());
+        let ttl = std::time::Duration::from_secs(60);
+        let start = "2029-01-01T20:00:00Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+        let calendar = || ical::Calendar::new("test")
+            .events((1..=3).map(|i| ical::Event::new(format!("{}@e.discord-events.magicalcodewit.ch", i), start, start)));
+        let key = (discord::Snowflake(1), "https://events.example.com/guilds/1/calendar.ics".to_string(), locale::Locale::default());
+
+        let mut chunks = CalendarChunks {
+            chunks: calendar().into_chunks(),
+            fill: Some(CacheFill { cache: cache.clone(), key: key.clone(), body: String::new() }),
+        };
+        let first = chunks.next().unwrap().into_inner();
+        // Half sent calendars aren't cached
+        assert!(cache.get(&key, ttl).is_none());
+        let body = first + &chunks.map(std::io::Cursor::into_inner).collect::<String>();
+        assert_eq!(body, calendar().to_string());
+        assert_eq!(cache.get(&key, ttl).as_deref(), Some(&body));
+
+        cache.clear(&discord::Snowflake(2));
+        assert!(cache.get(&key, ttl).is_some());
+        cache.clear_all();
+        assert!(cache.get(&key, ttl).is_none());
+    }
 }
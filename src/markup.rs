@@ -0,0 +1,586 @@
+use crate::{discord, API_BASE};
+use chrono::prelude::*;
+
+enum Inline {
+    Text(String),
+    Bold(Vec<Inline>),
+    Italic(Vec<Inline>),
+    Underline(Vec<Inline>),
+    Strikethrough(Vec<Inline>),
+    Spoiler(Vec<Inline>),
+    Code(String),
+    Link { text: Vec<Inline>, url: String },
+    User(u64),
+    Channel(u64),
+    Role(u64),
+    Emoji { name: String, id: u64, animated: bool },
+    Timestamp { seconds: i64, style: Option<char> },
+}
+
+enum Block {
+    Line(Vec<Inline>),
+    Heading(usize, Vec<Inline>),
+    ListItem(Vec<Inline>),
+    Quote(Vec<Block>),
+    Code(String),
+}
+
+/// Finds the end of a formatting span opened at the start of `s`, returning the span's contents and the rest of `s`
+fn delimited<'a>(s: &'a str, delimiter: &str) -> Option<(&'a str, &'a str)> {
+    let inner = &s[delimiter.len()..];
+    let end = inner.find(delimiter)?;
+    if end == 0 {
+        return None;
+    }
+    Some((&inner[..end], &inner[end + delimiter.len()..]))
+}
+
+/// Parses a `<...>` token such as a mention, custom emoji or timestamp
+fn parse_angle(token: &str) -> Option<Inline> {
+    if let Some(id) = token.strip_prefix("@&") {
+        return id.parse().ok().map(Inline::Role);
+    }
+    if let Some(id) = token.strip_prefix('@') {
+        return id.trim_start_matches('!').parse().ok().map(Inline::User);
+    }
+    if let Some(id) = token.strip_prefix('#') {
+        return id.parse().ok().map(Inline::Channel);
+    }
+    if let Some(t) = token.strip_prefix("t:") {
+        let mut parts = t.splitn(2, ':');
+        let seconds = parts.next()?.parse().ok()?;
+        let style = match parts.next() {
+            Some(s) if s.len() == 1 && "tTdDfFR".contains(s) => s.chars().next(),
+            Some(_) => return None,
+            None => None
+        };
+        return Some(Inline::Timestamp { seconds, style });
+    }
+    let (animated, emoji) = match token.strip_prefix("a:") {
+        Some(e) => (true, e),
+        None => (false, token.strip_prefix(':')?)
+    };
+    let (name, id) = emoji.split_once(':')?;
+    Some(Inline::Emoji { name: name.to_string(), id: id.parse().ok()?, animated })
+}
+
+fn is_word_char(c: Option<char>) -> bool {
+    c.is_some_and(char::is_alphanumeric)
+}
+
+/// Parses the formatting, mention or link at the start of `s`, if there is one
+fn parse_token(s: &str, prev: Option<char>) -> Option<(Inline, &str)> {
+    if s.starts_with('`') {
+        let (code, after) = delimited(s, "`")?;
+        return Some((Inline::Code(code.to_string()), after));
+    }
+    if s.starts_with('<') {
+        let end = s.find('>')?;
+        let token = &s[1..end];
+        if token.starts_with("https://") || token.starts_with("http://") {
+            return Some((Inline::Link { text: vec![Inline::Text(token.to_string())], url: token.to_string() }, &s[end + 1..]));
+        }
+        return parse_angle(token).map(|i| (i, &s[end + 1..]));
+    }
+    if s.starts_with('[') {
+        let end = s.find("](")?;
+        let url_end = end + 2 + s[end + 2..].find(')')?;
+        let url = &s[end + 2..url_end];
+        if url.starts_with("https://") || url.starts_with("http://") {
+            return Some((Inline::Link { text: parse_inline(&s[1..end]), url: url.to_string() }, &s[url_end + 1..]));
+        }
+        return None;
+    }
+    if (s.starts_with("https://") || s.starts_with("http://")) && !is_word_char(prev) {
+        let end = s.find(char::is_whitespace).unwrap_or(s.len());
+        let url = s[..end].trim_end_matches(['.', ',', ')', '!', '?']);
+        return Some((Inline::Link { text: vec![Inline::Text(url.to_string())], url: url.to_string() }, &s[url.len()..]));
+    }
+    for delimiter in ["**", "__", "~~", "||", "*", "_"] {
+        if !s.starts_with(delimiter) {
+            continue;
+        }
+        // Underscores inside words, like in snake_case, aren't formatting
+        if delimiter == "_" && is_word_char(prev) {
+            return None;
+        }
+        let (inner, after) = match delimited(s, delimiter) {
+            Some(d) => d,
+            None => continue
+        };
+        if delimiter == "_" && is_word_char(after.chars().next()) {
+            return None;
+        }
+        let inner = parse_inline(inner);
+        return Some((match delimiter {
+            "**" => Inline::Bold(inner),
+            "__" => Inline::Underline(inner),
+            "~~" => Inline::Strikethrough(inner),
+            "||" => Inline::Spoiler(inner),
+            _ => Inline::Italic(inner)
+        }, after));
+    }
+    None
+}
+
+fn parse_inline(s: &str) -> Vec<Inline> {
+    let mut out = vec![];
+    let mut text = String::new();
+    let mut rest = s;
+    let mut prev = None;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '\\' {
+            if let Some(escaped) = rest[1..].chars().next().filter(char::is_ascii_punctuation) {
+                text.push(escaped);
+                rest = &rest[1 + escaped.len_utf8()..];
+                prev = Some(escaped);
+                continue;
+            }
+        }
+        if let Some((inline, after)) = parse_token(rest, prev) {
+            if !text.is_empty() {
+                out.push(Inline::Text(std::mem::take(&mut text)));
+            }
+            out.push(inline);
+            rest = after;
+            prev = None;
+            continue;
+        }
+        text.push(c);
+        rest = &rest[c.len_utf8()..];
+        prev = Some(c);
+    }
+
+    if !text.is_empty() {
+        out.push(Inline::Text(text));
+    }
+    out
+}
+
+fn parse_blocks(s: &str) -> Vec<Block> {
+    let mut out = vec![];
+    let mut lines = s.lines();
+    while let Some(line) = lines.next() {
+        if let Some(first) = line.strip_prefix("```") {
+            // The language name on the opening line isn't part of the code
+            let mut code = vec![];
+            if let Some((inline, _)) = first.split_once("```") {
+                out.push(Block::Code(inline.to_string()));
+                continue;
+            }
+            for line in lines.by_ref() {
+                if let Some(last) = line.strip_suffix("```") {
+                    if !last.is_empty() {
+                        code.push(last);
+                    }
+                    break;
+                }
+                code.push(line);
+            }
+            out.push(Block::Code(code.join("\n")));
+        } else if let Some(quote) = line.strip_prefix(">>> ") {
+            let rest = std::iter::once(quote).chain(lines.by_ref()).collect::<Vec<_>>().join("\n");
+            out.push(Block::Quote(parse_blocks(&rest)));
+        } else if line.starts_with("> ") || line == ">" {
+            let mut quoted = vec![line.strip_prefix('>').unwrap_or_default().trim_start()];
+            let mut rest = lines.clone();
+            while let Some(next) = rest.next() {
+                match next.strip_prefix("> ").or(if next == ">" { Some("") } else { None }) {
+                    Some(q) => {
+                        quoted.push(q);
+                        lines = rest.clone();
+                    },
+                    None => break
+                }
+            }
+            out.push(Block::Quote(parse_blocks(&quoted.join("\n"))));
+        } else if let Some(heading) = line.strip_prefix("### ") {
+            out.push(Block::Heading(3, parse_inline(heading)));
+        } else if let Some(heading) = line.strip_prefix("## ") {
+            out.push(Block::Heading(2, parse_inline(heading)));
+        } else if let Some(heading) = line.strip_prefix("# ") {
+            out.push(Block::Heading(1, parse_inline(heading)));
+        } else if let Some(item) = line.strip_prefix("- ").or_else(|| line.strip_prefix("* ")) {
+            out.push(Block::ListItem(parse_inline(item)));
+        } else {
+            out.push(Block::Line(parse_inline(line)));
+        }
+    }
+    out
+}
+
+/// Names for everything mentioned in a piece of text
+pub struct Resolver {
+    guild_id: discord::Snowflake,
+    users: std::collections::HashMap<u64, String>,
+    channels: std::collections::HashMap<u64, String>,
+    roles: std::collections::HashMap<u64, String>,
+    time_zone: Option<chrono_tz::Tz>,
+    locale: crate::locale::Locale,
+}
+
+fn collect_mentions(inlines: &[Inline], users: &mut Vec<u64>, channels: &mut Vec<u64>, roles: &mut bool) {
+    for inline in inlines {
+        match inline {
+            Inline::Bold(i) | Inline::Italic(i) | Inline::Underline(i) | Inline::Strikethrough(i) | Inline::Spoiler(i) |
+            Inline::Link { text: i, .. } => collect_mentions(i, users, channels, roles),
+            Inline::User(id) => users.push(*id),
+            Inline::Channel(id) => channels.push(*id),
+            Inline::Role(_) => *roles = true,
+            _ => {}
+        }
+    }
+}
+
+fn collect_block_mentions(blocks: &[Block], users: &mut Vec<u64>, channels: &mut Vec<u64>, roles: &mut bool) {
+    for block in blocks {
+        match block {
+            Block::Line(i) | Block::Heading(_, i) | Block::ListItem(i) => collect_mentions(i, users, channels, roles),
+            Block::Quote(b) => collect_block_mentions(b, users, channels, roles),
+            Block::Code(_) => {}
+        }
+    }
+}
+
+impl Resolver {
+    /// Looks up the users, channels and roles mentioned in `text`, skipping any that can't be found
+    pub async fn fetch(
+        client: &reqwest::Client, bearer: Option<&str>, guild_id: &discord::Snowflake, text: &str,
+        time_zone: Option<chrono_tz::Tz>, locale: crate::locale::Locale
+    ) -> Resolver {
+        let (mut user_ids, mut channel_ids, mut has_roles) = (vec![], vec![], false);
+        collect_block_mentions(&parse_blocks(text), &mut user_ids, &mut channel_ids, &mut has_roles);
+        user_ids.sort_unstable();
+        user_ids.dedup();
+        channel_ids.sort_unstable();
+        channel_ids.dedup();
+
+        let mut resolver = Resolver {
+            guild_id: *guild_id,
+            users: std::collections::HashMap::new(),
+            channels: std::collections::HashMap::new(),
+            roles: std::collections::HashMap::new(),
+            time_zone,
+            locale,
+        };
+
+        for id in user_ids {
+            let member = crate::discord_get(client, bearer, format!("{}/guilds/{}/members/{}", API_BASE, guild_id, id))
+                .send().await.and_then(|r| r.error_for_status());
+            let member = match member {
+                Ok(r) => r.json::<discord::GuildMember>().await.ok(),
+                Err(_) => None
+            };
+            if let Some(name) = member.and_then(|m| m.nick.or_else(|| m.user.map(|u| u.display_name().to_string()))) {
+                resolver.users.insert(id, name);
+            }
+        }
+        for id in channel_ids {
+            let channel = crate::discord_get(client, bearer, format!("{}/channels/{}", API_BASE, id))
+                .send().await.and_then(|r| r.error_for_status());
+            let channel = match channel {
+                Ok(r) => r.json::<discord::Channel>().await.ok(),
+                Err(_) => None
+            };
+            if let Some(name) = channel.and_then(|c| c.name) {
+                resolver.channels.insert(id, name);
+            }
+        }
+        if has_roles {
+            let roles = crate::discord_get(client, bearer, format!("{}/guilds/{}/roles", API_BASE, guild_id))
+                .send().await.and_then(|r| r.error_for_status());
+            let roles = match roles {
+                Ok(r) => r.json::<Vec<discord::Role>>().await.unwrap_or_default(),
+                Err(_) => vec![]
+            };
+            resolver.roles.extend(roles.into_iter().map(|r| (r.id.0, r.name)));
+        }
+
+        resolver
+    }
+
+    fn user(&self, id: u64) -> String {
+        format!("@{}", self.users.get(&id).cloned().unwrap_or_else(|| self.locale.text("unknown-user", &[])))
+    }
+
+    fn channel(&self, id: u64) -> String {
+        format!("#{}", self.channels.get(&id).cloned().unwrap_or_else(|| self.locale.text("unknown-channel", &[])))
+    }
+
+    fn role(&self, id: u64) -> String {
+        format!("@{}", self.roles.get(&id).cloned().unwrap_or_else(|| self.locale.text("deleted-role", &[])))
+    }
+
+    /// Formats a timestamp like Discord's clients would, except relative times which would go stale in a calendar.
+    /// Times too far off to represent are left as they were written.
+    fn timestamp(&self, seconds: i64, style: Option<char>) -> String {
+        let format = match style {
+            Some('t') => "time",
+            Some('T') => "time-seconds",
+            Some('d') => "date-short",
+            Some('D') => "date",
+            Some('F') => "date-time-long",
+            _ => "date-time"
+        };
+        let date_time = match Utc.timestamp_opt(seconds, 0).single() {
+            Some(t) => t,
+            None => return match style {
+                Some(style) => format!("<t:{}:{}>", seconds, style),
+                None => format!("<t:{}>", seconds)
+            }
+        };
+        match &self.time_zone {
+            Some(tz) => self.locale.format(format, &date_time.with_timezone(tz)),
+            None => self.locale.format(format, &date_time)
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn inline_text(inlines: &[Inline], resolver: &Resolver, out: &mut String) {
+    for inline in inlines {
+        match inline {
+            Inline::Text(t) | Inline::Code(t) => out.push_str(t),
+            Inline::Bold(i) | Inline::Italic(i) | Inline::Underline(i) | Inline::Strikethrough(i) | Inline::Spoiler(i) =>
+                inline_text(i, resolver, out),
+            Inline::Link { text, url } => {
+                let mut t = String::new();
+                inline_text(text, resolver, &mut t);
+                if &t == url {
+                    out.push_str(url);
+                } else {
+                    out.push_str(&format!("{} ({})", t, url));
+                }
+            },
+            Inline::User(id) => out.push_str(&resolver.user(*id)),
+            Inline::Channel(id) => out.push_str(&resolver.channel(*id)),
+            Inline::Role(id) => out.push_str(&resolver.role(*id)),
+            Inline::Emoji { name, .. } => out.push_str(&format!(":{}:", name)),
+            Inline::Timestamp { seconds, style } => out.push_str(&resolver.timestamp(*seconds, *style)),
+        }
+    }
+}
+
+fn inline_html(inlines: &[Inline], resolver: &Resolver, out: &mut String) {
+    let wrap = |tag: &str, inner: &[Inline], out: &mut String| {
+        out.push_str(&format!("<{}>", tag));
+        inline_html(inner, resolver, out);
+        out.push_str(&format!("</{}>", tag.split(' ').next().unwrap_or(tag)));
+    };
+    for inline in inlines {
+        match inline {
+            Inline::Text(t) => out.push_str(&escape_html(t)),
+            Inline::Code(t) => out.push_str(&format!("<code>{}</code>", escape_html(t))),
+            Inline::Bold(i) => wrap("strong", i, out),
+            Inline::Italic(i) => wrap("em", i, out),
+            Inline::Underline(i) => wrap("u", i, out),
+            Inline::Strikethrough(i) => wrap("s", i, out),
+            Inline::Spoiler(i) => wrap("span class=\"spoiler\"", i, out),
+            Inline::Link { text, url } => {
+                out.push_str(&format!("<a href=\"{}\">", escape_html(url)));
+                inline_html(text, resolver, out);
+                out.push_str("</a>");
+            },
+            Inline::User(id) => out.push_str(&format!(
+                "<a href=\"https://discord.com/users/{}\">{}</a>", id, escape_html(&resolver.user(*id))
+            )),
+            Inline::Channel(id) => out.push_str(&format!(
+                "<a href=\"https://discord.com/channels/{}/{}\">{}</a>", resolver.guild_id, id, escape_html(&resolver.channel(*id))
+            )),
+            Inline::Role(id) => out.push_str(&format!("<strong>{}</strong>", escape_html(&resolver.role(*id)))),
+            Inline::Emoji { name, id, animated } => out.push_str(&format!(
+                "<img src=\"https://cdn.discordapp.com/emojis/{}.{}\" alt=\":{}:\" width=\"20\" height=\"20\">",
+                id, if *animated { "gif" } else { "png" }, escape_html(name)
+            )),
+            Inline::Timestamp { seconds, style } => out.push_str(&format!(
+                "<time>{}</time>", escape_html(&resolver.timestamp(*seconds, *style))
+            )),
+        }
+    }
+}
+
+fn blocks_text(blocks: &[Block], resolver: &Resolver) -> Vec<String> {
+    let mut out = vec![];
+    for block in blocks {
+        let mut line = String::new();
+        match block {
+            Block::Line(i) | Block::Heading(_, i) => inline_text(i, resolver, &mut line),
+            Block::ListItem(i) => {
+                line.push_str("- ");
+                inline_text(i, resolver, &mut line);
+            },
+            Block::Quote(b) => {
+                out.extend(blocks_text(b, resolver).into_iter().map(|l| format!("> {}", l)));
+                continue;
+            },
+            Block::Code(c) => line.push_str(c)
+        }
+        out.push(line);
+    }
+    out
+}
+
+fn blocks_html(blocks: &[Block], resolver: &Resolver, out: &mut String) {
+    let mut in_list = false;
+    let mut needs_break = false;
+    for block in blocks {
+        let is_item = matches!(block, Block::ListItem(_));
+        if in_list && !is_item {
+            out.push_str("</ul>");
+        } else if !in_list && is_item {
+            out.push_str("<ul>");
+        }
+        in_list = is_item;
+        match block {
+            Block::Line(i) => {
+                if needs_break {
+                    out.push_str("<br>");
+                }
+                inline_html(i, resolver, out);
+            },
+            Block::Heading(level, i) => {
+                out.push_str(&format!("<h{}>", level));
+                inline_html(i, resolver, out);
+                out.push_str(&format!("</h{}>", level));
+            },
+            Block::ListItem(i) => {
+                out.push_str("<li>");
+                inline_html(i, resolver, out);
+                out.push_str("</li>");
+            },
+            Block::Quote(b) => {
+                out.push_str("<blockquote>");
+                blocks_html(b, resolver, out);
+                out.push_str("</blockquote>");
+            },
+            Block::Code(c) => out.push_str(&format!("<pre><code>{}</code></pre>", escape_html(c)))
+        }
+        needs_break = matches!(block, Block::Line(_));
+    }
+    if in_list {
+        out.push_str("</ul>");
+    }
+}
+
+/// Renders Discord markup as plain text, with mentions and timestamps replaced by readable names and dates
+pub fn to_text(text: &str, resolver: &Resolver) -> String {
+    blocks_text(&parse_blocks(text), resolver).join("\n")
+}
+
+/// Renders Discord markup as an HTML document
+pub fn to_html(text: &str, resolver: &Resolver) -> String {
+    let mut out = match resolver.locale.language() {
+        Some(language) => format!("<!DOCTYPE html><html lang=\"{}\"><body>", language),
+        None => String::from("<!DOCTYPE html><html><body>")
+    };
+    blocks_html(&parse_blocks(text), resolver, &mut out);
+    out.push_str("</body></html>");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver(locale: crate::locale::Locale) -> Resolver {
+        Resolver {
+            guild_id: discord::Snowflake(10),
+            users: [(1, "Ann".to_string())].into_iter().collect(),
+            channels: [(2, "general".to_string())].into_iter().collect(),
+            roles: [(3, "Mods".to_string())].into_iter().collect(),
+            time_zone: Some(chrono_tz::Europe::London),
+            locale,
+        }
+    }
+
+    #[test]
+    fn renders_mentions() {
+        let resolver = resolver(Default::default());
+        let text = "Hi <@1> and <@!4>, see <#2> and <#5>, <@&3> <@&6>";
+        assert_eq!(to_text(text, &resolver), "Hi @Ann and @unknown-user, see #general and #unknown, @Mods @deleted-role");
+        assert!(to_html(text, &resolver).contains(
+            "Hi <a href=\"https://discord.com/users/1\">@Ann</a> and <a href=\"https://discord.com/users/4\">@unknown-user</a>, \
+            see <a href=\"https://discord.com/channels/10/2\">#general</a>"
+        ));
+
+        let (mut users, mut channels, mut roles) = (vec![], vec![], false);
+        collect_block_mentions(&parse_blocks("**<@1>**\n> <#2>\n`<@4>`"), &mut users, &mut channels, &mut roles);
+        assert_eq!((users, channels, roles), (vec![1], vec![2], false));
+    }
+
+    #[test]
+    fn renders_emoji() {
+        let resolver = resolver(Default::default());
+        let text = "<:party:123> <a:wave:456> <:broken:nope>";
+        assert_eq!(to_text(text, &resolver), ":party: :wave: <:broken:nope>");
+        let html = to_html(text, &resolver);
+        assert!(html.contains("<img src=\"https://cdn.discordapp.com/emojis/123.png\" alt=\":party:\""));
+        assert!(html.contains("<img src=\"https://cdn.discordapp.com/emojis/456.gif\" alt=\":wave:\""));
+        assert!(html.contains("&lt;:broken:nope&gt;"));
+    }
+
+    #[test]
+    fn renders_timestamps() {
+        let resolver = resolver(Default::default());
+        assert_eq!(to_text("<t:1877277600:t> <t:1877277600:D> <t:1877277600>", &resolver), "19:00 BST 27 June 2029 27 June 2029 19:00 BST");
+        assert_eq!(to_text("<t:1877277600:x>", &resolver), "<t:1877277600:x>");
+        // Out of range times are left alone rather than failing the whole calendar
+        assert_eq!(to_text("<t:99999999999999> <t:-99999999999999:R>", &resolver), "<t:99999999999999> <t:-99999999999999:R>");
+        assert!(to_html("<t:99999999999999>", &resolver).contains("<time>&lt;t:99999999999999&gt;</time>"));
+
+        let german = Resolver { time_zone: None, ..self::resolver(crate::locale::Locale::parse("de").unwrap()) };
+        assert_eq!(to_text("<t:1877277600:F>", &german), "Mittwoch, 27. Juni 2029 18:00 UTC");
+    }
+
+    #[test]
+    fn renders_formatting() {
+        let resolver = resolver(Default::default());
+        let text = "**bold *and italic* with __underline__** ~~gone~~ ||secret|| snake_case_name _em_";
+        assert_eq!(to_text(text, &resolver), "bold and italic with underline gone secret snake_case_name em");
+        assert!(to_html(text, &resolver).contains(
+            "<strong>bold <em>and italic</em> with <u>underline</u></strong> <s>gone</s> \
+            <span class=\"spoiler\">secret</span> snake_case_name <em>em</em>"
+        ));
+
+        let code = "Run `**not bold** <@1>` then\n```rust\nlet x = 1 < 2;\n```";
+        assert_eq!(to_text(code, &resolver), "Run **not bold** <@1> then\nlet x = 1 < 2;");
+        assert!(to_html(code, &resolver).contains(
+            "Run <code>**not bold** &lt;@1&gt;</code> then<pre><code>let x = 1 &lt; 2;</code></pre>"
+        ));
+        assert_eq!(to_text("\\*not italic\\*", &resolver), "*not italic*");
+    }
+
+    #[test]
+    fn renders_links() {
+        let resolver = resolver(Default::default());
+        let text = "[the **site**](https://example.com/a?b=1&c=2), <https://example.com/b> or https://example.com/c. \
+            [not a link](javascript:alert(1))";
+        assert_eq!(to_text(text, &resolver), "the site (https://example.com/a?b=1&c=2), https://example.com/b or \
+            https://example.com/c. [not a link](javascript:alert(1))");
+        let html = to_html(text, &resolver);
+        assert!(html.contains("<a href=\"https://example.com/a?b=1&amp;c=2\">the <strong>site</strong></a>"));
+        assert!(html.contains("<a href=\"https://example.com/b\">https://example.com/b</a>"));
+        assert!(html.contains("<a href=\"https://example.com/c\">https://example.com/c</a>."));
+        assert!(!html.contains("href=\"javascript"));
+    }
+
+    #[test]
+    fn escapes_html() {
+        let resolver = Resolver {
+            users: [(1, "<b>Ann</b>".to_string())].into_iter().collect(),
+            ..self::resolver(crate::locale::Locale::parse("fr").unwrap())
+        };
+        let html = to_html("# Tom & \"Jerry\"\n<script>alert(1)</script> <@1>\n- a < b\n> quoted > text", &resolver);
+        assert_eq!(html, "<!DOCTYPE html><html lang=\"fr\"><body><h1>Tom &amp; &quot;Jerry&quot;</h1>\
+            &lt;script&gt;alert(1)&lt;/script&gt; <a href=\"https://discord.com/users/1\">@&lt;b&gt;Ann&lt;/b&gt;</a>\
+            <ul><li>a &lt; b</li></ul><blockquote>quoted &gt; text</blockquote></body></html>");
+        assert_eq!(to_html("plain", &self::resolver(Default::default())), "<!DOCTYPE html><html><body>plain</body></html>");
+    }
+}
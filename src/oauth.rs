@@ -0,0 +1,340 @@
+use crate::{discord, ical, Config, API_BASE};
+use rand::Rng;
+
+const AUTHORIZE_URL: &str = "https://discord.com/oauth2/authorize";
+const SCOPES: &str = "identify guilds";
+const STATE_COOKIE: &str = "oauth_state";
+
+#[derive(Debug, Deserialize)]
+pub struct OAuthConfig {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+    refresh_token: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredUser {
+    user_id: u64,
+    refresh_token: String,
+}
+
+/// Users who have logged in, keyed by the secret token in their personal feed URL
+pub struct UserStore {
+    path: std::path::PathBuf,
+    users: tokio::sync::Mutex<std::collections::HashMap<String, StoredUser>>,
+    access_tokens: tokio::sync::Mutex<std::collections::HashMap<String, (String, chrono::DateTime<chrono::Utc>)>>,
+}
+
+impl UserStore {
+    pub fn load(path: std::path::PathBuf) -> std::io::Result<Self> {
+        let users = match std::fs::read(&path) {
+            Ok(d) => serde_json::from_slice(&d)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::collections::HashMap::new(),
+            Err(e) => return Err(e)
+        };
+        Ok(UserStore {
+            path,
+            users: tokio::sync::Mutex::new(users),
+            access_tokens: tokio::sync::Mutex::new(std::collections::HashMap::new()),
+        })
+    }
+
+    async fn save(&self, users: &std::collections::HashMap<String, StoredUser>) -> std::io::Result<()> {
+        let data = serde_json::to_vec(users)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let tmp_path = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, data).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await
+    }
+}
+
+fn random_token() -> String {
+    base64::encode_config(rand::thread_rng().gen::<[u8; 32]>(), base64::URL_SAFE_NO_PAD)
+}
+
+async fn request_token(client: &reqwest::Client, api_base: &str, oauth: &OAuthConfig, form: &[(&str, &str)])
+    -> reqwest::Result<TokenResponse> {
+    client.post(format!("{}/oauth2/token", api_base))
+        .basic_auth(&oauth.client_id, Some(&oauth.client_secret))
+        .form(form)
+        .send().await?
+        .error_for_status()?
+        .json().await
+}
+
+#[get("/login")]
+pub fn login(config: &rocket::State<Config>, cookies: &rocket::http::CookieJar<'_>)
+    -> Result<rocket::response::Redirect, rocket::http::Status> {
+    let oauth = config.oauth.as_ref().ok_or(rocket::http::Status::NotFound)?;
+
+    let state = random_token();
+    let url = reqwest::Url::parse_with_params(AUTHORIZE_URL, &[
+        ("response_type", "code"),
+        ("client_id", &oauth.client_id),
+        ("scope", SCOPES),
+        ("state", &state),
+        ("redirect_uri", &format!("{}{}", config.root_url, uri!(callback(_, _, _)))),
+        ("prompt", "consent"),
+    ]).map_err(|_| rocket::http::Status::InternalServerError)?;
+
+    cookies.add(rocket::http::Cookie::build(STATE_COOKIE, state)
+        .http_only(true)
+        .secure(config.root_url.starts_with("https://"))
+        .same_site(rocket::http::SameSite::Lax)
+        .finish());
+
+    Ok(rocket::response::Redirect::to(url.to_string()))
+}
+
+#[get("/callback?<code>&<state>&<error>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn callback(
+    client: &rocket::State<reqwest::Client>, config: &rocket::State<Config>, store: &rocket::State<UserStore>,
+    cookies: &rocket::http::CookieJar<'_>, accept_language: crate::locale::AcceptLanguage, code: Option<String>,
+    state: Option<String>, error: Option<String>
+) -> Result<rocket::response::content::RawHtml<String>, rocket::http::Status> {
+    let oauth = config.oauth.as_ref().ok_or(rocket::http::Status::NotFound)?;
+
+    let expected_state = cookies.get(STATE_COOKIE).map(|c| c.value().to_string());
+    cookies.remove(rocket::http::Cookie::named(STATE_COOKIE));
+    if error.is_some() || expected_state.is_none() || state != expected_state {
+        return Err(rocket::http::Status::BadRequest);
+    }
+    let code = code.ok_or(rocket::http::Status::BadRequest)?;
+
+    let redirect_uri = format!("{}{}", config.root_url, uri!(callback(_, _, _)));
+    let token = request_token(client, API_BASE, oauth, &[
+        ("grant_type", "authorization_code"),
+        ("code", &code),
+        ("redirect_uri", &redirect_uri),
+    ]).await.map_err(|e| {
+        println!("Unable to exchange OAuth code: {}", e);
+        rocket::http::Status::BadRequest
+    })?;
+
+    let user: discord::User = client.get(format!("{}/users/@me", API_BASE))
+        .bearer_auth(&token.access_token)
+        .send().await.map_err(|_| rocket::http::Status::InternalServerError)?
+        .error_for_status().map_err(|_| rocket::http::Status::InternalServerError)?
+        .json().await.map_err(|_| rocket::http::Status::InternalServerError)?;
+
+    let feed_token = random_token();
+    let mut users = store.users.lock().await;
+    users.retain(|_, u| u.user_id != user.id.0);
+    users.insert(feed_token.clone(), StoredUser {
+        user_id: user.id.0,
+        refresh_token: token.refresh_token,
+    });
+    store.save(&users).await.map_err(|e| {
+        println!("Unable to save user store: {}", e);
+        rocket::http::Status::InternalServerError
+    })?;
+    drop(users);
+    store.access_tokens.lock().await.insert(
+        feed_token.clone(), (token.access_token, chrono::Utc::now() + chrono::Duration::seconds(token.expires_in))
+    );
+
+    // The calendars linked to are in the language this page is in
+    let locale = accept_language.locale(None);
+    let link = |url: &str| format!("<a href=\"{url}\">{url}</a>", url = url);
+    let feed_url = format!("{}{}", config.root_url, uri!(feed(&feed_token, _, locale.language())));
+    let interested = match &config.feed_secret {
+        Some(secret) => {
+            let url = format!("{}{}", config.root_url, uri!(crate::subscribers::calendar(
+                user.id.0, crate::subscribers::feed_token(secret, user.id.0), _, locale.language()
+            )));
+            format!("<p>{}</p>", locale.text("interested-calendar", &[("link", &link(&url))]))
+        },
+        None => String::new()
+    };
+    let login = format!("<a href=\"{}\">{}</a>", uri!(login), locale.text("log-in-again", &[]));
+    Ok(rocket::response::content::RawHtml(format!(
+        "{}<head><title>Discord Events Export</title></head><body><p>{}</p>{}<p>{}</p></body></html>",
+        crate::html_start(locale), locale.text("personal-calendar", &[("link", &link(&feed_url))]), interested,
+        locale.text("keep-secret", &[("login", &login)])
+    )))
+}
+
+/// Returns a valid access token for a feed, refreshing it with the stored refresh token if needed.
+/// The user store isn't held locked while waiting on Discord.
+async fn access_token(client: &reqwest::Client, api_base: &str, oauth: &OAuthConfig, store: &UserStore, feed_token: &str)
+    -> Result<(u64, String), rocket::http::Status> {
+    let user = store.users.lock().await.get(feed_token).cloned().ok_or(rocket::http::Status::NotFound)?;
+    let cached_token = || async {
+        store.access_tokens.lock().await.get(feed_token)
+            .filter(|(_, expires)| *expires > chrono::Utc::now() + chrono::Duration::minutes(1))
+            .map(|(token, _)| token.clone())
+    };
+    if let Some(token) = cached_token().await {
+        return Ok((user.user_id, token));
+    }
+
+    let token = match request_token(client, api_base, oauth, &[
+        ("grant_type", "refresh_token"),
+        ("refresh_token", &user.refresh_token),
+    ]).await {
+        Ok(t) => t,
+        Err(e) if e.status() == Some(reqwest::StatusCode::BAD_REQUEST) || e.status() == Some(reqwest::StatusCode::UNAUTHORIZED) => {
+            let mut users = store.users.lock().await;
+            // Another request may have used the refresh token first, in which case its access token is used
+            if users.get(feed_token).is_some_and(|u| u.refresh_token != user.refresh_token) {
+                drop(users);
+                return cached_token().await.map(|t| (user.user_id, t)).ok_or(rocket::http::Status::ServiceUnavailable);
+            }
+            users.remove(feed_token);
+            if let Err(e) = store.save(&users).await {
+                println!("Unable to save user store: {}", e);
+            }
+            return Err(rocket::http::Status::Gone);
+        },
+        Err(e) => {
+            println!("Unable to refresh OAuth token: {}", e);
+            return Err(rocket::http::Status::InternalServerError);
+        }
+    };
+
+    let mut users = store.users.lock().await;
+    // The user may have logged out or in again while the token was being refreshed
+    if users.get(feed_token).is_some_and(|u| u.refresh_token == user.refresh_token) {
+        users.insert(feed_token.to_string(), StoredUser {
+            user_id: user.user_id,
+            refresh_token: token.refresh_token,
+        });
+        store.save(&users).await.map_err(|e| {
+            println!("Unable to save user store: {}", e);
+            rocket::http::Status::InternalServerError
+        })?;
+        store.access_tokens.lock().await.insert(
+            feed_token.to_string(), (token.access_token.clone(), chrono::Utc::now() + chrono::Duration::seconds(token.expires_in))
+        );
+    }
+
+    Ok((user.user_id, token.access_token))
+}
+
+#[get("/feeds/<feed_token>/calendar.ics?<tz>&<lang>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn feed(
+    client: &rocket::State<reqwest::Client>, config: &rocket::State<Config>, store: &rocket::State<UserStore>,
+    registry: &rocket::State<std::sync::Arc<crate::admin::Registry>>, accept_language: crate::locale::AcceptLanguage,
+    feed_token: String, tz: Option<String>, lang: Option<String>
+) -> Result<crate::CalendarResponse, rocket::http::Status> {
+    let oauth = config.oauth.as_ref().ok_or(rocket::http::Status::NotFound)?;
+    let time_zone = crate::parse_time_zone(tz.as_deref())?;
+    let locale = accept_language.locale(lang.as_deref());
+    let (user_id, access_token) = access_token(client, API_BASE, oauth, store, &feed_token).await?;
+
+    let mut events = vec![];
+    for guild in crate::current_user_guilds(client, Some(&access_token)).await? {
+        if registry.is_blocked(&guild.id).await {
+            continue;
+        }
+        // Guilds the user can't see events in are skipped rather than failing the whole feed
+        let guild_events = match crate::guild_events(client, Some(&access_token), &guild.id).await {
+            Ok(e) => e,
+            Err(_) => continue
+        };
+        for event in guild_events {
+            events.push(crate::event_to_ical(client, Some(&access_token), event, time_zone, locale, None).await);
+        }
+    }
+
+    let url = format!("{}{}", config.root_url, uri!(feed(&feed_token, _, lang.as_deref())));
+    let source = format!("{}{}", config.root_url, uri!(feed(&feed_token, tz.as_deref(), lang.as_deref())));
+    let calendar = ical::Calendar::new(format!("Discord Events Export {}", env!("CARGO_PKG_VERSION")))
+        .name(locale.text("personal-calendar-name", &[]))
+        .language(locale.language().map(ToString::to_string))
+        .uid(format!("{}@u.discord-events.magicalcodewit.ch", user_id))
+        .url(url)
+        .source(source)
+        .refresh_interval(config.cache_ttl())
+        .time_zone(time_zone)
+        .events(events);
+
+    Ok(crate::CalendarResponse::new(calendar, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Requests = std::sync::Arc<std::sync::Mutex<Vec<String>>>;
+
+    /// A token endpoint that swaps refresh token `rN` for access token `aN+1` and refresh token `rN+1`, and rejects
+    /// any other refresh token
+    async fn mock_token_endpoint() -> (String, Requests) {
+        let requests: Requests = Default::default();
+        let state = requests.clone();
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let requests = state.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+                    let requests = requests.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let mut url = reqwest::Url::parse("http://localhost/").unwrap();
+                        url.set_query(Some(std::str::from_utf8(&body).unwrap()));
+                        let form: std::collections::HashMap<String, String> = url.query_pairs().into_owned().collect();
+                        requests.lock().unwrap().push(form.get("refresh_token").cloned().unwrap_or_default());
+                        let next = form.get("refresh_token").and_then(|t| t.strip_prefix('r')).and_then(|n| n.parse::<u32>().ok());
+                        let res = hyper::Response::builder();
+                        Ok::<_, std::convert::Infallible>(match next {
+                            Some(n) if path == "/oauth2/token" && form.get("grant_type").map(String::as_str) == Some("refresh_token") => res
+                                .header("Content-Type", "application/json")
+                                .body(hyper::Body::from(serde_json::json!({
+                                    "access_token": format!("a{}", n + 1), "expires_in": 3600, "refresh_token": format!("r{}", n + 1)
+                                }).to_string())),
+                            _ => res.status(400).body(hyper::Body::from(r#"{"error": "invalid_grant"}"#))
+                        }.unwrap())
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let base = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (base, requests)
+    }
+
+    #[tokio::test]
+    async fn refreshes_tokens() {
+        let (base, requests) = mock_token_endpoint().await;
+        let client = crate::plain_client().unwrap();
+        let oauth = OAuthConfig { client_id: "id".to_string(), client_secret: "secret".to_string() };
+        let path = std::env::temp_dir().join(format!("users-{}.json", rand::random::<u64>()));
+        let store = UserStore::load(path.clone()).unwrap();
+        let feed_token = random_token();
+        store.users.lock().await.insert(feed_token.clone(), StoredUser { user_id: 1, refresh_token: "r1".to_string() });
+        store.users.lock().await.insert("revoked".to_string(), StoredUser { user_id: 2, refresh_token: "nope".to_string() });
+
+        // The refresh token is swapped for a new one, which is saved under the same feed token
+        assert_eq!(access_token(&client, &base, &oauth, &store, &feed_token).await, Ok((1, "a2".to_string())));
+        let reloaded = UserStore::load(path.clone()).unwrap();
+        assert_eq!(reloaded.users.lock().await[&feed_token].refresh_token, "r2");
+
+        // The access token is reused until it's about to expire
+        assert_eq!(access_token(&client, &base, &oauth, &store, &feed_token).await, Ok((1, "a2".to_string())));
+        assert_eq!(*requests.lock().unwrap(), vec!["r1".to_string()]);
+
+        // A store loaded from disk has no access tokens, so refreshes with the saved refresh token
+        assert_eq!(access_token(&client, &base, &oauth, &reloaded, &feed_token).await, Ok((1, "a3".to_string())));
+        assert_eq!(*requests.lock().unwrap(), vec!["r1".to_string(), "r2".to_string()]);
+
+        // Feeds whose refresh token has been revoked are forgotten
+        assert_eq!(access_token(&client, &base, &oauth, &reloaded, "revoked").await, Err(rocket::http::Status::Gone));
+        assert_eq!(access_token(&client, &base, &oauth, &reloaded, "unknown").await, Err(rocket::http::Status::NotFound));
+        let users = UserStore::load(path.clone()).unwrap().users.into_inner();
+        assert_eq!(users.keys().collect::<Vec<_>>(), vec![&feed_token]);
+        assert_eq!(users[&feed_token].refresh_token, "r3");
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
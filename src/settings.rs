@@ -0,0 +1,177 @@
+use crate::{discord, ical};
+
+const RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How long events Discord gives no end time are taken to last, in minutes, by where they're held
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Durations {
+    pub voice: Option<u32>,
+    pub stage: Option<u32>,
+}
+
+/// Templates for the text of each event, in place of its name, description and location
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Templates {
+    pub summary: Option<crate::template::Template>,
+    pub description: Option<crate::template::Template>,
+    pub location: Option<crate::template::Template>,
+}
+
+/// Overrides for one guild's calendars
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GuildSettings {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub tz: Option<chrono_tz::Tz>,
+    /// Minutes before each event to remind subscribers of it
+    pub alarms: Vec<u32>,
+    pub durations: Durations,
+    pub templates: Templates,
+    pub excluded_channels: Vec<discord::Snowflake>,
+    /// Only events with a name matching one of these are shown, if there are any
+    #[serde(deserialize_with = "regexes")]
+    pub include_titles: Vec<regex::Regex>,
+    #[serde(deserialize_with = "regexes")]
+    pub exclude_titles: Vec<regex::Regex>,
+    pub color: Option<String>,
+}
+
+fn regexes<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<regex::Regex>, D::Error> {
+    <Vec<String> as serde::Deserialize>::deserialize(deserializer)?.iter()
+        .map(|r| regex::Regex::new(r).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+impl GuildSettings {
+    /// Whether an event belongs in the guild's calendars
+    pub fn shows(&self, event: &discord::GuildEvent) -> bool {
+        !event.channel_id.is_some_and(|c| self.excluded_channels.contains(&c))
+            && (self.include_titles.is_empty() || self.include_titles.iter().any(|r| r.is_match(&event.name)))
+            && !self.exclude_titles.iter().any(|r| r.is_match(&event.name))
+    }
+
+    pub fn default_duration(&self, entity_type: discord::GuildEventEntityType) -> Option<chrono::Duration> {
+        match entity_type {
+            discord::GuildEventEntityType::Voice => self.durations.voice,
+            discord::GuildEventEntityType::Stage => self.durations.stage,
+            discord::GuildEventEntityType::External => None
+        }.map(|m| chrono::Duration::minutes(m.into()))
+    }
+
+    pub fn alarms(&self, description: &str) -> Vec<ical::Alarm> {
+        self.alarms.iter().map(|m| ical::Alarm {
+            before: std::time::Duration::from_secs(u64::from(*m) * 60),
+            description: description.to_string(),
+        }).collect()
+    }
+}
+
+type Guilds = std::collections::HashMap<discord::Snowflake, GuildSettings>;
+
+/// Settings of each guild, read from a TOML or YAML file and read again whenever it changes
+pub struct Settings {
+    path: Option<std::path::PathBuf>,
+    guilds: std::sync::RwLock<(Option<std::time::SystemTime>, std::sync::Arc<Guilds>)>,
+}
+
+fn parse(path: &std::path::Path, data: &str) -> Result<Guilds, String> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml" | "yml") => serde_yaml::from_str(data).map_err(|e| e.to_string()),
+        _ => toml::from_str(data).map_err(|e| e.to_string())
+    }
+}
+
+impl Settings {
+    /// Reads the settings file, if there is one
+    pub fn load(path: Option<std::path::PathBuf>) -> Result<Self, String> {
+        let settings = Settings { path, guilds: Default::default() };
+        settings.reload()?;
+        Ok(settings)
+    }
+
+    /// Reads the settings file again if it's been modified, keeping the current settings if it can't be parsed
+    fn reload(&self) -> Result<bool, String> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(false)
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified())
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        if self.guilds.read().unwrap().0 == Some(modified) {
+            return Ok(false);
+        }
+        let result = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))
+            .and_then(|d| parse(path, &d).map_err(|e| format!("Unable to parse {}: {}", path.display(), e)));
+        let mut guilds = self.guilds.write().unwrap();
+        // Either way, don't try again until the file changes
+        guilds.0 = Some(modified);
+        guilds.1 = std::sync::Arc::new(result?);
+        Ok(true)
+    }
+
+    pub fn guild(&self, guild_id: &discord::Snowflake) -> GuildSettings {
+        self.guilds.read().unwrap().1.get(guild_id).cloned().unwrap_or_default()
+    }
+}
+
+/// Checks the settings file for changes every few seconds, forever, dropping cached calendars when it changes
+pub async fn watch(settings: std::sync::Arc<Settings>, cache: std::sync::Arc<crate::CalendarCache>) {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        match settings.reload() {
+            // Calendars built with the old settings would otherwise be served until they expire
+            Ok(true) => {
+                cache.clear_all();
+                println!("Reloaded guild settings");
+            },
+            Ok(false) => {},
+            Err(e) => println!("{}, keeping the previous guild settings", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reloads_on_change() {
+        let path = std::env::temp_dir().join(format!("settings-{}.toml", rand::random::<u64>()));
+        std::fs::write(&path, "[\"10\"]\nname = \"Club events\"\nalarms = [15]\nexclude_titles = [\"(?i)staff\"]\n\
+            [\"10\".durations]\nvoice = 90\n").unwrap();
+        let settings = Settings::load(Some(path.clone())).unwrap();
+        let guild = settings.guild(&discord::Snowflake(10));
+        assert_eq!(guild.name.as_deref(), Some("Club events"));
+        assert_eq!(guild.default_duration(discord::GuildEventEntityType::Voice), Some(chrono::Duration::minutes(90)));
+        assert!(guild.shows(&crate::changes::test_event(1, "Game night", "2029-01-01T20:00:00Z", "Pub")));
+        assert!(!guild.shows(&crate::changes::test_event(2, "Staff meeting", "2029-01-01T20:00:00Z", "Pub")));
+        assert!(settings.guild(&discord::Snowflake(11)).name.is_none());
+        assert!(!settings.reload().unwrap());
+
+        // A broken file keeps the last settings that worked
+        let touch = |data: &str, age: u64| {
+            std::fs::write(&path, data).unwrap();
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(age)).unwrap();
+        };
+        touch("[\"10\"]\nname = ", 1);
+        assert!(settings.reload().is_err());
+        assert_eq!(settings.guild(&discord::Snowflake(10)).name.as_deref(), Some("Club events"));
+
+        touch("[\"10\"]\nname = \"Renamed\"\n", 2);
+        assert!(settings.reload().unwrap());
+        assert_eq!(settings.guild(&discord::Snowflake(10)).name.as_deref(), Some("Renamed"));
+        std::fs::remove_file(&path).unwrap();
+
+        let yaml = parse(std::path::Path::new("guilds.yaml"), "\"10\":\n  tz: Europe/London\n  color: slateblue\n").unwrap();
+        assert_eq!(yaml[&discord::Snowflake(10)].tz, Some(chrono_tz::Europe::London));
+        let templates = parse(std::path::Path::new("guilds.toml"), "[\"10\".templates]\nsummary = \"[{{entity_type}}] {{name}}\"\n");
+        assert!(templates.unwrap()[&discord::Snowflake(10)].templates.summary.is_some());
+        assert!(parse(std::path::Path::new("guilds.toml"), "[\"10\".templates]\nsummary = \"{{nope}}\"\n").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{discord, ical, Config, API_BASE};
+use hmac::Mac;
+
+const INDEX_TTL: std::time::Duration = std::time::Duration::from_secs(300);
+const PAGE_SIZE: usize = 100;
+
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+/// Which events each user has marked themselves as interested in, as (guild ID, event ID) pairs
+#[derive(Default)]
+struct Index {
+    subscriptions: std::collections::HashMap<u64, std::collections::HashSet<(u64, u64)>>,
+}
+
+/// Subscriber index across all the bot's guilds, rebuilt when it goes stale
+#[derive(Default)]
+pub struct SubscriberIndex {
+    cached: std::sync::Mutex<Option<(std::time::Instant, std::sync::Arc<Index>)>>,
+    /// Held while the index is being rebuilt, so only one rebuild happens at a time
+    building: tokio::sync::Mutex<()>,
+}
+
+/// Fetches users interested in an event, up to `max` if given, paging through the list by user ID
+pub async fn event_subscribers(
+    client: &reqwest::Client, guild_id: &discord::Snowflake, event_id: &discord::Snowflake, with_member: bool,
+    max: Option<usize>
+) -> reqwest::Result<Vec<discord::GuildEventUser>> {
+    let mut users: Vec<discord::GuildEventUser> = vec![];
+    loop {
+        let mut url = format!(
+            "{}/guilds/{guild_id}/scheduled-events/{event_id}/users?limit={limit}&with_member={with_member}",
+            API_BASE, guild_id = guild_id, event_id = event_id, limit = PAGE_SIZE, with_member = with_member
+        );
+        if let Some(last) = users.last() {
+            url.push_str(&format!("&after={}", last.user.id));
+        }
+        let page: Vec<discord::GuildEventUser> = client.get(url)
+            .send().await?
+            .error_for_status()?
+            .json().await?;
+        let done = page.len() < PAGE_SIZE;
+        users.extend(page);
+        if let Some(max) = max {
+            if users.len() >= max {
+                users.truncate(max);
+                return Ok(users);
+            }
+        }
+        if done {
+            return Ok(users);
+        }
+    }
+}
+
+/// Lists users interested in an event as attendees, named by their guild nickname where they have one
+pub async fn event_attendees(client: &reqwest::Client, guild_id: &discord::Snowflake, event_id: &discord::Snowflake, max: usize)
+    -> Vec<ical::Attendee> {
+    let users = match event_subscribers(client, guild_id, event_id, true, Some(max)).await {
+        Ok(u) => u,
+        Err(e) => {
+            println!("Unable to fetch subscribers for event {}: {}", event_id, e);
+            return vec![]
+        }
+    };
+    users.into_iter().map(|u| ical::Attendee {
+        address: format!("https://discord.com/users/{}", u.user.id),
+        common_name: Some(u.member.and_then(|m| m.nick)
+            .unwrap_or_else(|| format!("{}#{}", u.user.username, u.user.discriminator))),
+        role: Some(ical::Role::OptionalParticipant),
+        participation_status: Some(ical::ParticipationStatus::Tentative),
+        rsvp: Some(false),
+    }).collect()
+}
+
+async fn build_index(client: &reqwest::Client) -> Result<Index, rocket::http::Status> {
+    let mut index = Index::default();
+    for guild in crate::current_user_guilds(client, None).await? {
+        let events = match crate::guild_events(client, None, &guild.id).await {
+            Ok(e) => e,
+            Err(_) => continue
+        };
+        for event in events {
+            let users = match event_subscribers(client, &guild.id, &event.id, false, None).await {
+                Ok(u) => u,
+                Err(e) => {
+                    println!("Unable to fetch subscribers for event {}: {}", event.id, e);
+                    continue
+                }
+            };
+            for user in users {
+                index.subscriptions.entry(user.user.id.0).or_default().insert((guild.id.0, event.id.0));
+            }
+        }
+    }
+    Ok(index)
+}
+
+impl SubscriberIndex {
+    async fn get(&self, client: &reqwest::Client) -> Result<std::sync::Arc<Index>, rocket::http::Status> {
+        self.get_or_build(|| build_index(client)).await
+    }
+
+    /// The cached index, if there is one, and whether it's still fresh
+    fn cached(&self) -> Option<(bool, std::sync::Arc<Index>)> {
+        self.cached.lock().unwrap().as_ref().map(|(built, index)| (built.elapsed() < INDEX_TTL, index.clone()))
+    }
+
+    /// Returns the cached index, rebuilding it if it's stale. While another request is rebuilding it, a stale index
+    /// is returned if there is one, and otherwise the rebuild is waited for.
+    async fn get_or_build<F: std::future::Future<Output=Result<Index, rocket::http::Status>>>(&self, build: impl FnOnce() -> F)
+        -> Result<std::sync::Arc<Index>, rocket::http::Status> {
+        let stale = match self.cached() {
+            Some((true, index)) => return Ok(index),
+            Some((false, index)) => Some(index),
+            None => None
+        };
+        let _building = match (self.building.try_lock(), stale) {
+            (Ok(guard), _) => guard,
+            (Err(_), Some(index)) => return Ok(index),
+            (Err(_), None) => self.building.lock().await
+        };
+        if let Some((true, index)) = self.cached() {
+            return Ok(index);
+        }
+        let index = std::sync::Arc::new(build().await?);
+        *self.cached.lock().unwrap() = Some((std::time::Instant::now(), index.clone()));
+        Ok(index)
+    }
+}
+
+fn token_mac(secret: &str, user_id: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(user_id.to_string().as_bytes());
+    mac
+}
+
+/// Token authorising access to a user's interested events feed
+pub fn feed_token(secret: &str, user_id: u64) -> String {
+    base64::encode_config(token_mac(secret, user_id).finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+fn verify_feed_token(secret: &str, user_id: u64, token: &str) -> bool {
+    match base64::decode_config(token, base64::URL_SAFE_NO_PAD) {
+        Ok(t) => token_mac(secret, user_id).verify_slice(&t).is_ok(),
+        Err(_) => false
+    }
+}
+
+#[get("/users/<user_id>/calendar.ics?<token>&<tz>&<lang>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn calendar(
+    client: &rocket::State<reqwest::Client>, config: &rocket::State<Config>, index: &rocket::State<SubscriberIndex>,
+    registry: &rocket::State<std::sync::Arc<crate::admin::Registry>>, accept_language: crate::locale::AcceptLanguage, user_id: u64, token: String, tz: Option<String>, lang: Option<String>
+) -> Result<crate::CalendarResponse, rocket::http::Status> {
+    let secret = config.feed_secret.as_ref().ok_or(rocket::http::Status::NotFound)?;
+    if !verify_feed_token(secret, user_id, &token) {
+        return Err(rocket::http::Status::NotFound);
+    }
+    let time_zone = crate::parse_time_zone(tz.as_deref())?;
+    let locale = accept_language.locale(lang.as_deref());
+
+    let index = index.get(client).await?;
+    let subscriptions = index.subscriptions.get(&user_id).cloned().unwrap_or_default();
+    let mut guild_ids = subscriptions.iter().map(|(g, _)| *g).collect::<Vec<_>>();
+    guild_ids.sort_unstable();
+    guild_ids.dedup();
+
+    let mut events = vec![];
+    for guild_id in guild_ids {
+        if registry.is_blocked(&discord::Snowflake(guild_id)).await {
+            continue;
+        }
+        let guild_events = match crate::guild_events(client, None, &discord::Snowflake(guild_id)).await {
+            Ok(e) => e,
+            Err(_) => continue
+        };
+        for event in guild_events {
+            if subscriptions.contains(&(guild_id, event.id.0)) {
+                events.push(crate::event_to_ical(client, None, event, time_zone, locale, None).await);
+            }
+        }
+    }
+
+    let url = format!("{}{}", config.root_url, uri!(calendar(&user_id, &token, _, lang.as_deref())));
+    let source = format!("{}{}", config.root_url, uri!(calendar(&user_id, &token, tz.as_deref(), lang.as_deref())));
+    let calendar = ical::Calendar::new(format!("Discord Events Export {}", env!("CARGO_PKG_VERSION")))
+        .name(locale.text("interested-calendar-name", &[]))
+        .language(locale.language().map(ToString::to_string))
+        .uid(format!("{}@i.discord-events.magicalcodewit.ch", user_id))
+        .url(url)
+        .source(source)
+        .refresh_interval(config.cache_ttl())
+        .time_zone(time_zone)
+        .events(events);
+
+    Ok(crate::CalendarResponse::new(calendar, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(user_id: u64) -> Index {
+        let mut index = Index::default();
+        index.subscriptions.entry(user_id).or_default().insert((1, 2));
+        index
+    }
+
+    #[tokio::test]
+    async fn rebuilds_index_once() {
+        let subscribers = SubscriberIndex::default();
+        let builds = std::sync::atomic::AtomicU64::new(0);
+        let build = |user_id| {
+            let builds = &builds;
+            move || async move {
+                builds.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                Ok(index(user_id))
+            }
+        };
+
+        // Requests arriving while the first build is running wait for it rather than building their own
+        let (a, b) = tokio::join!(subscribers.get_or_build(build(1)), subscribers.get_or_build(build(2)));
+        assert!(a.unwrap().subscriptions.contains_key(&1) && b.unwrap().subscriptions.contains_key(&1));
+        assert_eq!(builds.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(subscribers.get_or_build(build(3)).await.unwrap().subscriptions.contains_key(&1));
+
+        // Once stale, it's rebuilt, with the stale index served to requests arriving meanwhile
+        let built = std::time::Instant::now().checked_sub(INDEX_TTL).unwrap();
+        subscribers.cached.lock().unwrap().as_mut().unwrap().0 = built;
+        let (a, b) = tokio::join!(subscribers.get_or_build(build(4)), subscribers.get_or_build(build(5)));
+        assert!(a.unwrap().subscriptions.contains_key(&4));
+        assert!(b.unwrap().subscriptions.contains_key(&1));
+        assert_eq!(builds.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert!(subscribers.get_or_build(build(6)).await.unwrap().subscriptions.contains_key(&4));
+
+        // A failed build leaves the index as it was
+        subscribers.cached.lock().unwrap().as_mut().unwrap().0 = built;
+        let failed = subscribers.get_or_build(|| async { Err(rocket::http::Status::BadGateway) }).await;
+        assert_eq!(failed.err(), Some(rocket::http::Status::BadGateway));
+        let (fresh, index) = subscribers.cached().unwrap();
+        assert!(!fresh && index.subscriptions.contains_key(&4));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{discord, locale};
+
+/// Fields templates can use, from the event, the channel it's in, its guild and whoever created it
+pub const FIELDS: &[&str] = &[
+    "id", "name", "description", "entity_type", "status", "start", "end", "location", "url", "interested", "image",
+    "channel.id", "channel.name", "channel.topic",
+    "guild.id", "guild.name", "guild.description",
+    "creator.id", "creator.name",
+];
+
+pub type Fields = std::collections::HashMap<&'static str, String>;
+
+#[derive(Clone, Debug)]
+enum Node {
+    Text(String),
+    Field(&'static str),
+    /// Shown when the field has a value, or when it doesn't if inverted
+    Section { field: &'static str, inverted: bool, body: Vec<Node> },
+}
+
+/// A template of text with `{{field}}` placeholders, and `{{#field}}...{{/field}}` sections only shown when the field
+/// has a value, or `{{^field}}...{{/field}}` when it doesn't
+#[derive(Clone, Debug)]
+pub struct Template(Vec<Node>);
+
+fn field(name: &str) -> Result<&'static str, String> {
+    FIELDS.iter().find(|f| **f == name).copied().ok_or_else(|| format!("unknown template field {}", name))
+}
+
+/// Parses nodes up to the end of the template, or the tag closing `section`, returning them and the rest of the text
+fn parse_nodes<'a>(mut s: &'a str, section: Option<&str>) -> Result<(Vec<Node>, &'a str), String> {
+    let mut nodes = vec![];
+    while let Some(start) = s.find("{{") {
+        if start > 0 {
+            nodes.push(Node::Text(s[..start].to_string()));
+        }
+        let (tag, rest) = s[start + 2..].split_once("}}").ok_or("unclosed {{ in template")?;
+        let tag = tag.trim();
+        s = rest;
+        if let Some(name) = tag.strip_prefix('/') {
+            return match section {
+                Some(open) if open == name.trim() => Ok((nodes, s)),
+                _ => Err(format!("unexpected {{{{/{}}}}} in template", name.trim()))
+            };
+        }
+        let inverted = tag.starts_with('^');
+        match tag.strip_prefix('#').or_else(|| tag.strip_prefix('^')) {
+            Some(name) => {
+                let field = field(name.trim())?;
+                let (body, rest) = parse_nodes(s, Some(field))?;
+                nodes.push(Node::Section { field, inverted, body });
+                s = rest;
+            },
+            None => nodes.push(Node::Field(field(tag)?))
+        }
+    }
+    if let Some(open) = section {
+        return Err(format!("unclosed {{{{#{}}}}} in template", open));
+    }
+    if !s.is_empty() {
+        nodes.push(Node::Text(s.to_string()));
+    }
+    Ok((nodes, ""))
+}
+
+fn render_nodes(nodes: &[Node], fields: &Fields, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(t) => out.push_str(t),
+            Node::Field(f) => out.push_str(fields.get(f).map_or("", String::as_str)),
+            Node::Section { field, inverted, body } => {
+                if fields.get(field).is_some_and(|v| !v.is_empty()) != *inverted {
+                    render_nodes(body, fields, out);
+                }
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        parse_nodes(s, None).map(|(nodes, _)| Template(nodes))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Template {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <String as serde::Deserialize>::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Template {
+    /// Fills in the template, giving `None` if that leaves nothing but whitespace
+    pub fn render(&self, fields: &Fields) -> Option<String> {
+        let mut out = String::new();
+        render_nodes(&self.0, fields, &mut out);
+        Some(out.trim().to_string()).filter(|o| !o.is_empty())
+    }
+}
+
+/// The values of every field for an event, with times in the calendar's time zone and language
+pub fn fields(
+    event: &discord::GuildEvent, channel: Option<&discord::Channel>, guild: &discord::Guild,
+    time_zone: Option<chrono_tz::Tz>, locale: locale::Locale
+) -> Fields {
+    let time = |t: chrono::DateTime<chrono::Utc>| match time_zone {
+        Some(tz) => locale.format("date-time", &t.with_timezone(&tz)),
+        None => locale.format("date-time", &t)
+    };
+    let values = [
+        ("id", Some(event.id.to_string())),
+        ("name", Some(event.name.clone())),
+        ("description", event.description.clone()),
+        ("entity_type", Some(locale.text(match event.entity_type {
+            discord::GuildEventEntityType::Stage => "entity-type-stage",
+            discord::GuildEventEntityType::Voice => "entity-type-voice",
+            discord::GuildEventEntityType::External => "entity-type-external"
+        }, &[]))),
+        ("status", Some(locale.text(match event.status {
+            discord::GuildEventStatus::Scheduled => "status-scheduled",
+            discord::GuildEventStatus::Active => "status-active",
+            discord::GuildEventStatus::Completed => "status-completed",
+            discord::GuildEventStatus::Cancelled => "status-cancelled"
+        }, &[]))),
+        ("start", Some(time(event.scheduled_start_time))),
+        ("end", event.scheduled_end_time.map(time)),
+        ("location", event.entity_metadata.as_ref().and_then(|m| m.location.clone())),
+        ("url", Some(format!("https://discord.com/events/{}/{}", event.guild_id, event.id))),
+        ("interested", event.user_count.map(|c| c.to_string())),
+        ("image", event.image.as_ref().map(|i| format!("https://cdn.discordapp.com/guild-events/{}/{}.png", event.id, i))),
+        ("channel.id", event.channel_id.map(|c| c.to_string())),
+        ("channel.name", channel.and_then(|c| c.name.clone())),
+        ("channel.topic", channel.and_then(|c| c.topic.clone())),
+        ("guild.id", Some(guild.id.to_string())),
+        ("guild.name", Some(guild.name.clone())),
+        ("guild.description", guild.description.clone()),
+        ("creator.id", event.creator_id.or(event.creator.as_ref().map(|c| c.id)).map(|c| c.to_string())),
+        ("creator.name", event.creator.as_ref().map(|c| c.display_name().to_string())),
+    ];
+    values.into_iter().filter_map(|(name, value)| Some((name, value?))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders() {
+        let guild: discord::Guild = serde_json::from_value(serde_json::json!({
+            "id": "10", "name": "Club", "icon": null, "splash": null, "discovery_splash": null, "owner_id": "1",
+            "description": null
+        })).unwrap();
+        let mut event = crate::changes::test_event(1, "Game night", "2029-01-01T20:00:00Z", "Pub");
+        event.user_count = Some(3);
+        let fields = fields(&event, None, &guild, Some(chrono_tz::Europe::Paris), locale::Locale::default());
+
+        let summary: Template = "[{{entity_type}}] {{ name }}".parse().unwrap();
+        assert_eq!(summary.render(&fields).as_deref(), Some("[External] Game night"));
+        let description: Template = "{{description}}{{^description}}No details yet{{/description}}\n\n\
+            {{#interested}}{{interested}} interested. {{/interested}}{{url}}".parse().unwrap();
+        assert_eq!(description.render(&fields).as_deref(),
+            Some("No details yet\n\n3 interested. https://discord.com/events/81384788765712384/1"));
+        let location: Template = "{{location}}, from {{start}}".parse().unwrap();
+        assert_eq!(location.render(&fields).as_deref(), Some("Pub, from 1 January 2029 21:00 CET"));
+        assert_eq!("{{channel.name}}".parse::<Template>().unwrap().render(&fields), None);
+
+        let french = super::fields(&event, None, &guild, None, locale::Locale::parse("fr").unwrap());
+        assert_eq!("{{entity_type}}, {{status}}".parse::<Template>().unwrap().render(&french).as_deref(), Some("Externe, Prévu"));
+
+        assert!("{{nope}}".parse::<Template>().is_err());
+        assert!("{{#name}}unclosed".parse::<Template>().is_err());
+        assert!("{{#name}}crossed{{/id}}".parse::<Template>().is_err());
+        assert!("{{name".parse::<Template>().is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use crate::{changes, discord};
+use chrono::prelude::*;
+use hmac::Mac;
+
+const DELIVERY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+const MAX_ATTEMPTS: u32 = 10;
+
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub secret: String,
+    /// Only send changes to events in these guilds, or in every guild if empty
+    #[serde(default)]
+    pub guilds: Vec<discord::Snowflake>,
+}
+
+impl WebhookConfig {
+    fn wants(&self, guild_id: &discord::Snowflake) -> bool {
+        self.guilds.is_empty() || self.guilds.contains(guild_id)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Delivery {
+    url: String,
+    body: String,
+    attempts: u32,
+    next_attempt: DateTime<Utc>,
+}
+
+/// Webhook deliveries still to be made, saved to disk so they survive restarts
+pub struct Queue {
+    path: std::path::PathBuf,
+    deliveries: tokio::sync::Mutex<Vec<Delivery>>,
+}
+
+/// Signs a webhook body, over its timestamp and the body joined by a full stop
+fn signature(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Waits twice as long after each failed attempt, starting from 30 seconds
+fn retry_delay(attempts: u32) -> chrono::Duration {
+    chrono::Duration::seconds(30 << attempts.saturating_sub(1).min(16))
+}
+
+/// Sends a body to a webhook, giving whether it's worth retrying if it fails
+async fn send(client: &reqwest::Client, webhook: &WebhookConfig, body: &str) -> Result<(), bool> {
+    let timestamp = Utc::now().timestamp().to_string();
+    let res = client.post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Timestamp", &timestamp)
+        .header("X-Webhook-Signature", signature(&webhook.secret, &timestamp, body))
+        .body(body.to_string())
+        .send().await;
+    match res {
+        Ok(r) if r.status().is_success() => Ok(()),
+        Ok(r) => {
+            println!("Webhook {} responded with {}", webhook.url, r.status());
+            Err(r.status().is_server_error() || r.status() == reqwest::StatusCode::REQUEST_TIMEOUT
+                || r.status() == reqwest::StatusCode::TOO_MANY_REQUESTS)
+        },
+        Err(e) => {
+            println!("Unable to send webhook to {}: {}", webhook.url, e);
+            Err(true)
+        }
+    }
+}
+
+impl Queue {
+    pub fn load(path: std::path::PathBuf) -> std::io::Result<Self> {
+        let deliveries = match std::fs::read(&path) {
+            Ok(d) => serde_json::from_slice(&d)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e)
+        };
+        Ok(Queue {
+            path,
+            deliveries: tokio::sync::Mutex::new(deliveries),
+        })
+    }
+
+    async fn save(&self, deliveries: &[Delivery]) {
+        let data = match serde_json::to_vec(deliveries) {
+            Ok(d) => d,
+            Err(e) => {
+                println!("Unable to serialize webhook queue: {}", e);
+                return
+            }
+        };
+        let tmp_path = self.path.with_extension("tmp");
+        if let Err(e) = async {
+            tokio::fs::write(&tmp_path, data).await?;
+            tokio::fs::rename(&tmp_path, &self.path).await
+        }.await {
+            println!("Unable to save webhook queue: {}", e);
+        }
+    }
+
+    /// Queues a delivery of the changes each webhook wants to hear about
+    pub async fn enqueue(&self, webhooks: &[WebhookConfig], changes: &[changes::Change]) {
+        let mut deliveries = self.deliveries.lock().await;
+        for webhook in webhooks {
+            let wanted = changes.iter().filter(|c| webhook.wants(&c.guild_id)).collect::<Vec<_>>();
+            if wanted.is_empty() {
+                continue;
+            }
+            deliveries.push(Delivery {
+                url: webhook.url.clone(),
+                body: serde_json::json!({ "changes": wanted }).to_string(),
+                attempts: 0,
+                next_attempt: Utc::now(),
+            });
+        }
+        self.save(&deliveries).await;
+    }
+
+    /// Attempts every delivery due by `now`, one webhook at a time so each receives its changes in order
+    pub async fn deliver_due(&self, client: &reqwest::Client, webhooks: &[WebhookConfig], now: DateTime<Utc>) {
+        let mut deliveries = self.deliveries.lock().await;
+        // Webhooks with an earlier delivery still waiting to be retried
+        let mut blocked = std::collections::HashSet::new();
+        let mut changed = false;
+        let mut i = 0;
+        while i < deliveries.len() {
+            let delivery = deliveries[i].clone();
+            if blocked.contains(&delivery.url) || delivery.next_attempt > now {
+                blocked.insert(delivery.url);
+                i += 1;
+                continue;
+            }
+            changed = true;
+            let webhook = match webhooks.iter().find(|w| w.url == delivery.url) {
+                Some(w) => w,
+                None => {
+                    println!("Dropping delivery to {}, which is no longer configured", delivery.url);
+                    deliveries.remove(i);
+                    continue;
+                }
+            };
+            match send(client, webhook, &delivery.body).await {
+                Ok(()) => {
+                    deliveries.remove(i);
+                },
+                Err(retry) if !retry || delivery.attempts + 1 >= MAX_ATTEMPTS => {
+                    println!("Giving up on delivery to {} after {} attempts", delivery.url, delivery.attempts + 1);
+                    deliveries.remove(i);
+                },
+                Err(_) => {
+                    deliveries[i].attempts += 1;
+                    deliveries[i].next_attempt = now + retry_delay(deliveries[i].attempts);
+                    blocked.insert(delivery.url);
+                    i += 1;
+                }
+            }
+        }
+        if changed {
+            self.save(&deliveries).await;
+        }
+    }
+}
+
+/// Fetches the latest events of each watched guild, giving the changes since the last poll. A guild's first snapshot
+/// only sets a baseline, so a restart doesn't report every event as new. Blocked guilds are skipped, and get a new
+/// baseline once unblocked.
+async fn poll(
+    client: &reqwest::Client, registry: &crate::admin::Registry, webhooks: &[WebhookConfig],
+    snapshots: &mut std::collections::HashMap<discord::Snowflake, Vec<discord::GuildEvent>>
+) -> Vec<changes::Change> {
+    let guilds = if webhooks.iter().any(|w| w.guilds.is_empty()) {
+        match crate::current_user_guilds(client, None).await {
+            Ok(g) => g.into_iter().map(|g| g.id).collect::<Vec<_>>(),
+            Err(e) => {
+                println!("Unable to list guilds for webhooks: {}", e);
+                return vec![];
+            }
+        }
+    } else {
+        let mut guilds = webhooks.iter().flat_map(|w| w.guilds.iter().copied()).collect::<Vec<_>>();
+        guilds.sort();
+        guilds.dedup();
+        guilds
+    };
+
+    let mut changes = vec![];
+    for guild_id in guilds {
+        if registry.is_blocked(&guild_id).await {
+            snapshots.remove(&guild_id);
+            continue;
+        }
+        let events = match crate::guild_events(client, None, &guild_id).await {
+            Ok(e) => e,
+            Err(e) => {
+                println!("Unable to fetch events of guild {} for webhooks: {}", guild_id, e);
+                continue;
+            }
+        };
+        if let Some(old) = snapshots.get(&guild_id) {
+            changes.extend(changes::diff(old, &events));
+        }
+        snapshots.insert(guild_id, events);
+    }
+    changes
+}
+
+/// Polls for event changes and delivers them to the configured webhooks, forever
+pub async fn run(
+    bot_client: reqwest::Client, client: reqwest::Client, queue: std::sync::Arc<Queue>,
+    registry: std::sync::Arc<crate::admin::Registry>, webhooks: Vec<WebhookConfig>, poll_interval: std::time::Duration
+) {
+    let mut snapshots = std::collections::HashMap::new();
+    let mut last_poll: Option<std::time::Instant> = None;
+    let mut interval = tokio::time::interval(DELIVERY_INTERVAL);
+    loop {
+        interval.tick().await;
+        if last_poll.is_none_or(|p| p.elapsed() >= poll_interval) {
+            last_poll = Some(std::time::Instant::now());
+            let changes = poll(&bot_client, &registry, &webhooks, &mut snapshots).await;
+            if !changes.is_empty() {
+                queue.enqueue(&webhooks, &changes).await;
+            }
+        }
+        queue.deliver_due(&client, &webhooks, Utc::now()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    /// Accepts HTTP requests, answering each with the next of the given statuses, and records their headers and bodies
+    async fn receiver(statuses: Vec<u16>)
+        -> (String, std::sync::Arc<std::sync::Mutex<Vec<(std::collections::HashMap<String, String>, String)>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let log = received.clone();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut headers = std::collections::HashMap::new();
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                loop {
+                    line.clear();
+                    stream.read_line(&mut line).await.unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((name, value)) => headers.insert(name.to_lowercase(), value.to_string()),
+                        None => break
+                    };
+                }
+                let mut body = vec![0; headers["content-length"].parse().unwrap()];
+                stream.read_exact(&mut body).await.unwrap();
+                log.lock().unwrap().push((headers, String::from_utf8(body).unwrap()));
+                stream.write_all(format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).as_bytes())
+                    .await.unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn delivers_to_local_receiver() {
+        let (url, received) = receiver(vec![500, 200]).await;
+        let webhooks = vec![WebhookConfig { url, secret: "secret".to_string(), guilds: vec![] }];
+        let path = std::env::temp_dir().join(format!("webhooks-{}.json", rand::random::<u64>()));
+        let changes = changes::diff(&[], &[changes::test_event(1, "Game night", "2029-01-01T20:00:00Z", "Pub")]);
+
+        let queue = Queue::load(path.clone()).unwrap();
+        queue.enqueue(&webhooks, &changes).await;
+        let now = Utc::now();
+        queue.deliver_due(&crate::plain_client().unwrap(), &webhooks, now).await;
+        queue.deliver_due(&crate::plain_client().unwrap(), &webhooks, now).await;
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        // The failed delivery is retried by a fresh queue, as if after a restart
+        let queue = Queue::load(path.clone()).unwrap();
+        assert_eq!(queue.deliveries.lock().await[0].attempts, 1);
+        queue.deliver_due(&crate::plain_client().unwrap(), &webhooks, now + chrono::Duration::hours(1)).await;
+        assert!(queue.deliveries.lock().await.is_empty());
+        std::fs::remove_file(path).unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        let (headers, body) = &received[1];
+        assert_eq!(headers["x-webhook-signature"], signature("secret", &headers["x-webhook-timestamp"], body));
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["changes"][0]["type"], "created");
+        assert_eq!(body["changes"][0]["event_id"], "1");
+    }
+}